@@ -7,6 +7,7 @@ pub struct Connection {
     node_id: NodeId,
     subdomain: Subdomain,
     port: u16,
+    forwarded: Option<Forwarded>,
 }
 
 impl Connection {
@@ -22,6 +23,7 @@ impl Connection {
             node_id,
             subdomain,
             port,
+            forwarded: None,
         }))
     }
 
@@ -30,6 +32,7 @@ impl Connection {
             node_id,
             subdomain: packet.subdomain,
             port: packet.port,
+            forwarded: packet.forwarded,
         }
     }
 
@@ -48,44 +51,228 @@ impl Connection {
     pub fn port(&self) -> u16 {
         self.port
     }
+
+    /// The node that opened the stream, which is not the peer when the stream
+    /// came along a multi-hop route.
+    pub fn origin(&self) -> NodeId {
+        self.forwarded
+            .map_or(self.node_id, |forwarded| forwarded.origin)
+    }
+
+    /// Nodes the stream was forwarded by before it reached this one.
+    pub fn hops(&self) -> u8 {
+        self.forwarded.map_or(0, |forwarded| forwarded.hops)
+    }
+}
+
+/// Carried by a stream a node forwards along a multi-hop route.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Forwarded {
+    /// The node that opened the stream at the first hop.
+    pub origin: NodeId,
+    /// Nodes that forwarded the stream so far, including the sender.
+    pub hops: u8,
+}
+
+impl Forwarded {
+    const LEN: usize = 1 + 32;
+}
+
+/// What the stream opened with a [`WavePacket`] asks the node for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketKind {
+    /// A TCP byte stream to the route `subdomain`.
+    Stream,
+    /// A UDP flow to the route `subdomain` instead of a TCP byte stream.
+    Udp,
+    /// Asks an exit node to dial `subdomain`, which holds a host name or IP
+    /// address, instead of one of its routes. With `resolve`, the node
+    /// resolves the host name itself and answers with a SOCKS5 reply code
+    /// before the stream, so a failure reaches the caller.
+    Exit { resolve: bool },
+    /// Asks the node which subnets it routes for the sender; it answers with
+    /// one CIDR per line and finishes the stream.
+    Subnets,
+    /// Asks the node which of its routes are public; it answers with one
+    /// subdomain per line and finishes the stream.
+    PublicRoutes,
+    /// The stream was opened by a public gateway for a client outside wave;
+    /// the node only serves it for public routes.
+    Gateway,
+}
+
+impl PacketKind {
+    const ALL: [Self; 7] = [
+        Self::Stream,
+        Self::Udp,
+        Self::Exit { resolve: false },
+        Self::Exit { resolve: true },
+        Self::Subnets,
+        Self::PublicRoutes,
+        Self::Gateway,
+    ];
+
+    fn flags(self) -> u32 {
+        match self {
+            Self::Stream => 0,
+            Self::Udp => WavePacket::UDP_FLAG,
+            Self::Exit { resolve: false } => WavePacket::EXIT_FLAG,
+            Self::Exit { resolve: true } => WavePacket::EXIT_FLAG | WavePacket::RESOLVE_FLAG,
+            Self::Subnets => WavePacket::SUBNETS_FLAG,
+            Self::PublicRoutes => WavePacket::PUBLIC_ROUTES_FLAG,
+            Self::Gateway => WavePacket::GATEWAY_FLAG,
+        }
+    }
+
+    fn from_flags(flags: u32) -> Option<Self> {
+        Self::ALL.into_iter().find(|kind| kind.flags() == flags)
+    }
 }
 
 pub struct WavePacket {
     pub port: u16,
     pub subdomain: Subdomain,
+    pub kind: PacketKind,
+    pub forwarded: Option<Forwarded>,
 }
 
 impl WavePacket {
+    const HEADER_LEN: usize = 2 + 4;
+
+    /// Set in the subdomain length field for UDP flows. Subdomains are at most
+    /// 255 bytes, so older peers reject the packet as an overflow.
+    const UDP_FLAG: u32 = 1 << 31;
+
+    const EXIT_FLAG: u32 = 1 << 30;
+
+    const SUBNETS_FLAG: u32 = 1 << 29;
+
+    const RESOLVE_FLAG: u32 = 1 << 28;
+
+    /// Set when a `Forwarded` header follows the subdomain.
+    const FORWARDED_FLAG: u32 = 1 << 27;
+
+    const PUBLIC_ROUTES_FLAG: u32 = 1 << 26;
+
+    const GATEWAY_FLAG: u32 = 1 << 25;
+
+    const FLAGS: u32 = Self::UDP_FLAG
+        | Self::EXIT_FLAG
+        | Self::SUBNETS_FLAG
+        | Self::RESOLVE_FLAG
+        | Self::FORWARDED_FLAG
+        | Self::PUBLIC_ROUTES_FLAG
+        | Self::GATEWAY_FLAG;
+
+    fn with_kind(kind: PacketKind, port: u16, subdomain: Subdomain) -> Self {
+        Self {
+            port,
+            subdomain,
+            kind,
+            forwarded: None,
+        }
+    }
+
     pub fn new(port: u16, subdomain: Subdomain) -> Self {
-        Self { port, subdomain }
+        Self::with_kind(PacketKind::Stream, port, subdomain)
+    }
+
+    pub fn udp(port: u16, subdomain: Subdomain) -> Self {
+        Self::with_kind(PacketKind::Udp, port, subdomain)
+    }
+
+    /// A request for an exit node to connect to `host:port` over TCP.
+    pub fn exit(port: u16, host: Subdomain) -> Self {
+        Self::with_kind(PacketKind::Exit { resolve: false }, port, host)
+    }
+
+    /// Like `exit`, for a host name only the node can resolve. The node
+    /// answers with a SOCKS5 reply code.
+    pub fn resolve(port: u16, host: Subdomain) -> Self {
+        Self::with_kind(PacketKind::Exit { resolve: true }, port, host)
+    }
+
+    /// A query for the subnets the node routes for the sender.
+    pub fn subnets() -> Self {
+        Self::with_kind(PacketKind::Subnets, 0, Subdomain(Arc::from("")))
+    }
+
+    /// A query for the routes the node serves through public gateways.
+    pub fn public_routes() -> Self {
+        Self::with_kind(PacketKind::PublicRoutes, 0, Subdomain(Arc::from("")))
+    }
+
+    /// A stream a public gateway opens to `subdomain` for an outside client.
+    pub fn gateway(port: u16, subdomain: Subdomain) -> Self {
+        Self::with_kind(PacketKind::Gateway, port, subdomain)
     }
 
     pub fn decode(data: &mut BytesMut) -> Result<Option<Self>, WavePacketDecodeError> {
-        if data.remaining() < 6 {
+        if data.remaining() < Self::HEADER_LEN {
             return Ok(None);
         }
 
-        let port = data.get_u16();
-        let subdomain_len = data.get_u32();
-
-        if data.remaining() < subdomain_len as usize {
-            return Ok(None);
-        } else if subdomain_len > Subdomain::MAX_LEN as u32 {
+        let len_field = u32::from_be_bytes(data[2..6].try_into().unwrap());
+        let kind = PacketKind::from_flags(len_field & Self::FLAGS & !Self::FORWARDED_FLAG)
+            .ok_or(WavePacketDecodeError::InvalidKind)?;
+        let forwarded_len = match len_field & Self::FORWARDED_FLAG != 0 {
+            true => Forwarded::LEN,
+            false => 0,
+        };
+        let subdomain_len = len_field & !Self::FLAGS;
+        if subdomain_len > Subdomain::MAX_LEN as u32 {
             return Err(WavePacketDecodeError::SubdomainOverflow);
+        } else if data.remaining() < Self::HEADER_LEN + subdomain_len as usize + forwarded_len {
+            return Ok(None);
         }
 
+        let port = data.get_u16();
+        data.advance(4);
         let subdomain = data.split_to(subdomain_len as usize);
         let subdomain = Arc::from(std::str::from_utf8(subdomain.as_ref())?);
         let subdomain = Subdomain::new(subdomain).unwrap();
+        let forwarded = if forwarded_len > 0 {
+            let hops = data.get_u8();
+            let origin = data.split_to(32);
+            let origin = iroh::PublicKey::try_from(origin.as_ref())
+                .map_err(|_| WavePacketDecodeError::InvalidOrigin)?;
+            Some(Forwarded {
+                origin: NodeId(origin),
+                hops,
+            })
+        } else {
+            None
+        };
 
-        Ok(Some(WavePacket { port, subdomain }))
+        Ok(Some(WavePacket {
+            port,
+            subdomain,
+            kind,
+            forwarded,
+        }))
     }
 
     pub fn encode(self) -> Bytes {
-        let mut buf = BytesMut::with_capacity(2 + 4 + self.subdomain.as_str().len());
+        self.encode_with_payload(&[])
+    }
+
+    /// Encodes the packet followed by the first bytes of the stream, so both
+    /// leave in a single write.
+    pub fn encode_with_payload(self, payload: &[u8]) -> Bytes {
+        let subdomain = self.subdomain.as_str();
+        let mut buf = BytesMut::with_capacity(Self::HEADER_LEN + subdomain.len() + payload.len());
         buf.put_u16(self.port);
-        buf.put_u32(self.subdomain.as_str().len() as u32);
-        buf.put(self.subdomain.as_str().as_bytes());
+        let mut flags = self.kind.flags();
+        if self.forwarded.is_some() {
+            flags |= Self::FORWARDED_FLAG;
+        }
+        buf.put_u32(subdomain.len() as u32 | flags);
+        buf.put(subdomain.as_bytes());
+        if let Some(forwarded) = self.forwarded {
+            buf.put_u8(forwarded.hops);
+            buf.put(&forwarded.origin.0.as_bytes()[..]);
+        }
+        buf.put(payload);
         buf.freeze()
     }
 }
@@ -95,6 +282,10 @@ pub enum WavePacketDecodeError {
     Utf8Error(std::str::Utf8Error),
     #[display("Subdomain overflow")]
     SubdomainOverflow,
+    #[display("Invalid origin")]
+    InvalidOrigin,
+    #[display("Invalid packet kind")]
+    InvalidKind,
 }
 
 #[cfg(test)]
@@ -108,4 +299,125 @@ mod tests {
         assert_eq!(conn.port, 8080);
         assert_eq!(conn.subdomain.as_str(), "baidu");
     }
+
+    #[test]
+    fn test_decode_partial_packet() {
+        let packet = WavePacket::new(80, "web".parse().unwrap()).encode_with_payload(b"GET /");
+
+        let mut buf = BytesMut::from(&packet[..7]);
+        assert!(WavePacket::decode(&mut buf).unwrap().is_none());
+        assert_eq!(buf.len(), 7);
+
+        buf.extend_from_slice(&packet[7..]);
+        let decoded = WavePacket::decode(&mut buf).unwrap().unwrap();
+        assert_eq!(decoded.port, 80);
+        assert_eq!(decoded.subdomain.as_str(), "web");
+        assert_eq!(&buf[..], b"GET /");
+        assert_eq!(decoded.kind, PacketKind::Stream);
+    }
+
+    #[test]
+    fn test_udp_packet() {
+        let packet = WavePacket::udp(53, "dns".parse().unwrap()).encode();
+        let decoded = WavePacket::decode(&mut BytesMut::from(&packet[..]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(decoded.kind, PacketKind::Udp);
+        assert_eq!(decoded.port, 53);
+        assert_eq!(decoded.subdomain.as_str(), "dns");
+    }
+
+    #[test]
+    fn test_exit_packet() {
+        let packet = WavePacket::exit(443, "::1".parse().unwrap()).encode();
+        let decoded = WavePacket::decode(&mut BytesMut::from(&packet[..]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(decoded.kind, PacketKind::Exit { resolve: false });
+        assert_eq!(decoded.port, 443);
+        assert_eq!(decoded.subdomain.as_str(), "::1");
+
+        let packet = WavePacket::resolve(5432, "db.corp.internal".parse().unwrap()).encode();
+        let decoded = WavePacket::decode(&mut BytesMut::from(&packet[..]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(decoded.kind, PacketKind::Exit { resolve: true });
+        assert_eq!(decoded.subdomain.as_str(), "db.corp.internal");
+    }
+
+    #[test]
+    fn test_forwarded_packet() {
+        let origin = NodeId(iroh::SecretKey::from_bytes(&[1; 32]).public());
+        let mut packet = WavePacket::new(80, "web".parse().unwrap());
+        packet.forwarded = Some(Forwarded { origin, hops: 2 });
+        let packet = packet.encode_with_payload(b"GET /");
+
+        let mut buf = BytesMut::from(&packet[..packet.len() - 6]);
+        assert!(WavePacket::decode(&mut buf).unwrap().is_none());
+
+        let mut buf = BytesMut::from(&packet[..]);
+        let decoded = WavePacket::decode(&mut buf).unwrap().unwrap();
+        assert_eq!(decoded.forwarded, Some(Forwarded { origin, hops: 2 }));
+        assert_eq!(decoded.subdomain.as_str(), "web");
+        assert_eq!(&buf[..], b"GET /");
+
+        let conn = Connection::accept(
+            NodeId(iroh::SecretKey::from_bytes(&[2; 32]).public()),
+            decoded,
+        );
+        assert_eq!(conn.origin(), origin);
+        assert_eq!(conn.hops(), 2);
+    }
+
+    #[test]
+    fn test_subnets_packet() {
+        let packet = WavePacket::subnets().encode();
+        let decoded = WavePacket::decode(&mut BytesMut::from(&packet[..]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(decoded.kind, PacketKind::Subnets);
+        assert!(decoded.subdomain.is_empty());
+    }
+
+    #[test]
+    fn test_gateway_packets() {
+        let packet = WavePacket::public_routes().encode();
+        let decoded = WavePacket::decode(&mut BytesMut::from(&packet[..]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(decoded.kind, PacketKind::PublicRoutes);
+
+        let packet = WavePacket::gateway(80, "web".parse().unwrap()).encode();
+        let decoded = WavePacket::decode(&mut BytesMut::from(&packet[..]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(decoded.kind, PacketKind::Gateway);
+        assert_eq!(decoded.subdomain.as_str(), "web");
+        assert_eq!(decoded.port, 80);
+    }
+    #[test]
+    fn test_invalid_kind() {
+        for flags in [
+            WavePacket::UDP_FLAG | WavePacket::EXIT_FLAG,
+            WavePacket::RESOLVE_FLAG,
+            WavePacket::SUBNETS_FLAG | WavePacket::PUBLIC_ROUTES_FLAG,
+        ] {
+            let mut buf = BytesMut::new();
+            buf.put_u16(80);
+            buf.put_u32(3 | flags);
+            buf.put(&b"web"[..]);
+            assert!(matches!(
+                WavePacket::decode(&mut buf),
+                Err(WavePacketDecodeError::InvalidKind)
+            ));
+        }
+
+        for kind in PacketKind::ALL {
+            let packet = WavePacket::with_kind(kind, 80, "web".parse().unwrap()).encode();
+            let decoded = WavePacket::decode(&mut BytesMut::from(&packet[..]))
+                .unwrap()
+                .unwrap();
+            assert_eq!(decoded.kind, kind);
+        }
+    }
 }
@@ -1,4 +1,4 @@
-pub use connection::{Connection, WavePacket};
+pub use connection::{Connection, PacketKind, WavePacket};
 use derive_more::{AsRef, Display, Error, From};
 pub use error::Error;
 use serde::{Deserialize, Serialize};
@@ -68,3 +68,17 @@ impl std::str::FromStr for NodeId {
         Ok(NodeId(public_key))
     }
 }
+
+impl Serialize for NodeId {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for NodeId {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
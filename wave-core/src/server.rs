@@ -8,6 +8,45 @@ use std::{collections::HashMap, net::IpAddr, str::FromStr, sync::Arc};
 pub enum Host {
     Ip(IpAddr),
     Domain(Arc<str>),
+    /// A route of another node, reached with a further hop.
+    Wave(WaveTarget),
+}
+
+/// A route on another node, written `sub.<node_id>` or `sub.<node_id>:port`.
+/// Without a port the stream keeps the port it arrived for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WaveTarget {
+    pub subdomain: Subdomain,
+    pub node_id: NodeId,
+    pub port: Option<u16>,
+}
+
+impl WaveTarget {
+    fn parse(s: &str) -> Option<Self> {
+        let (name, port) = match s.rsplit_once(':') {
+            Some((name, port)) => (name, Some(port.parse().ok()?)),
+            None => (s, None),
+        };
+        let (subdomain, node_id) = name.rsplit_once('.').unwrap_or(("", name));
+        Some(WaveTarget {
+            subdomain: Subdomain::new(Arc::from(subdomain)).ok()?,
+            node_id: node_id.parse().ok()?,
+            port,
+        })
+    }
+}
+
+impl std::fmt::Display for WaveTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if !self.subdomain.is_empty() {
+            write!(f, "{}.", self.subdomain)?;
+        }
+        write!(f, "{}", self.node_id)?;
+        if let Some(port) = self.port {
+            write!(f, ":{}", port)?;
+        }
+        Ok(())
+    }
 }
 
 impl Host {
@@ -23,6 +62,8 @@ impl FromStr for Host {
         }
         if let Ok(ip) = s.parse() {
             Ok(Host::Ip(ip))
+        } else if let Some(target) = WaveTarget::parse(s) {
+            Ok(Host::Wave(target))
         } else {
             Ok(Host::Domain(Arc::from(s)))
         }
@@ -39,7 +80,7 @@ impl Server {
         Self { router }
     }
 
-    pub fn iter(&self) -> std::collections::hash_map::Iter<Subdomain, Host> {
+    pub fn iter(&self) -> std::collections::hash_map::Iter<'_, Subdomain, Host> {
         self.router.iter()
     }
 
@@ -133,3 +174,29 @@ impl Default for Fallback {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wave_target() {
+        let node_id = NodeId(iroh::SecretKey::from_bytes(&[1; 32]).public());
+        let Host::Wave(target) = format!("db.{}:5432", node_id).parse().unwrap() else {
+            panic!("expected a wave target");
+        };
+        assert_eq!(target.subdomain.as_str(), "db");
+        assert_eq!(target.node_id, node_id);
+        assert_eq!(target.port, Some(5432));
+        assert_eq!(target.to_string(), format!("db.{}:5432", node_id));
+
+        let Host::Wave(target) = node_id.to_string().parse().unwrap() else {
+            panic!("expected a wave target");
+        };
+        assert!(target.subdomain.is_empty());
+        assert_eq!(target.port, None);
+
+        assert!(matches!("example.com".parse(), Ok(Host::Domain(_))));
+        assert!(matches!("::1".parse(), Ok(Host::Ip(_))));
+    }
+}
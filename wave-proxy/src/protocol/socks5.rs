@@ -21,7 +21,7 @@ impl NoAuthHandshake {
     }
 
     pub fn handshake(self, request: HandshakeRequest) -> (Transmit, Result<Connecting, Error>) {
-        let response = if !request.methods.iter().any(|x| *x == AuthMethod::None) {
+        let response = if !request.methods.contains(&AuthMethod::None) {
             HandshakeResponse(AuthMethod::NotAcceptable)
         } else {
             HandshakeResponse(AuthMethod::None)
@@ -139,6 +139,8 @@ pub enum Error {
     InvalidCommand { command: u8 },
     #[display("Invalid address type: {addr_type}")]
     InvalidAddrType { addr_type: u8 },
+    #[display("Invalid reply: {reply}")]
+    InvalidReply { reply: u8 },
     #[from]
     FromUtf8Error(std::string::FromUtf8Error),
     #[from]
@@ -67,3 +67,28 @@ fn test1() {
         data: Bytes::from_static(RESPONSE),
     });
 }
+
+#[test]
+fn test_udp_header() {
+    let header = UdpHeader {
+        frag: 0,
+        address: "[::1]:53".parse().unwrap(),
+    };
+    let mut buf = BytesMut::from(&header.clone().encode()[..]);
+    buf.extend_from_slice(b"query");
+    assert_eq!(UdpHeader::decode(&mut buf).unwrap(), Some(header));
+    assert_eq!(&buf[..], b"query");
+
+    let mut buf = BytesMut::from(&[0x00, 0x00, 0x00, 0x03, 0x05, b't', b'e'][..]);
+    assert_eq!(UdpHeader::decode(&mut buf).unwrap(), None);
+}
+
+#[test]
+fn test_decode_ipv4_request() {
+    let data = [0x05, 0x03, 0x00, 0x01, 0, 0, 0, 0, 0, 0];
+    let request = ConnectRequest::decode(&mut BytesMut::from(&data[..]))
+        .unwrap()
+        .unwrap();
+    assert_eq!(request.command, Command::UdpAssociate);
+    assert_eq!(request.target, "0.0.0.0:0".parse().unwrap());
+}
@@ -156,6 +156,40 @@ impl ConnectResponse {
     }
 }
 
+/// Prefixes every datagram relayed for a UDP ASSOCIATE.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UdpHeader {
+    pub frag: u8,
+    pub address: Address,
+}
+
+impl UdpHeader {
+    /// |RSV | FRAG | ATYP | DST.ADDR | DST.PORT | DATA |
+    /// |:--:|:----:|:----:|:--------:|:--------:|:----:|
+    /// | 2  |  1   |  1   | Variable |    2     | Variable |
+    ///
+    /// Leaves the payload in `buf`.
+    pub fn decode(buf: &mut BytesMut) -> Result<Option<Self>, Error> {
+        if buf.remaining() < 5 {
+            return Ok(None);
+        }
+        let _reserved = buf.get_u16();
+        let frag = buf.get_u8();
+        let Some((_addr_type, address)) = decode_address(&mut *buf)? else {
+            return Ok(None);
+        };
+        Ok(Some(UdpHeader { frag, address }))
+    }
+
+    pub fn encode(self) -> Bytes {
+        let mut buf = BytesMut::with_capacity(3 + 18);
+        buf.put_u16(0);
+        buf.put_u8(self.frag);
+        buf.put(encode_address(self.address));
+        buf.freeze()
+    }
+}
+
 #[derive(Debug, Clone, Copy, Display, PartialEq, Eq)]
 #[repr(u8)]
 pub enum ConnectedStatus {
@@ -170,6 +204,24 @@ pub enum ConnectedStatus {
     AddressTypeNotSupported = SOCKS5_REPLY_ADDRESS_TYPE_NOT_SUPPORTED,
 }
 
+impl TryFrom<u8> for ConnectedStatus {
+    type Error = Error;
+    fn try_from(value: u8) -> Result<Self, Error> {
+        match value {
+            SOCKS5_REPLY_SUCCEEDED => Ok(ConnectedStatus::Succeeded),
+            SOCKS5_REPLY_GENERAL_FAILURE => Ok(ConnectedStatus::GeneralServerFailure),
+            SOCKS5_REPLY_CONNECTION_NOT_ALLOWED => Ok(ConnectedStatus::ConnectionNotAllowed),
+            SOCKS5_REPLY_NETWORK_UNREACHABLE => Ok(ConnectedStatus::NetworkUnreachable),
+            SOCKS5_REPLY_HOST_UNREACHABLE => Ok(ConnectedStatus::HostUnreachable),
+            SOCKS5_REPLY_CONNECTION_REFUSED => Ok(ConnectedStatus::ConnectionRefused),
+            SOCKS5_REPLY_TTL_EXPIRED => Ok(ConnectedStatus::TtlExpired),
+            SOCKS5_REPLY_COMMAND_NOT_SUPPORTED => Ok(ConnectedStatus::CommandNotSupported),
+            SOCKS5_REPLY_ADDRESS_TYPE_NOT_SUPPORTED => Ok(ConnectedStatus::AddressTypeNotSupported),
+            _ => Err(Error::InvalidReply { reply: value }),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, Display, PartialEq, Eq)]
 #[repr(u8)]
 pub enum Command {
@@ -214,7 +266,7 @@ pub fn decode_address(mut buf: impl Buf) -> Result<Option<(AddrType, Address)>,
     let addr_type = buf.get_u8().try_into()?;
     let address = match addr_type {
         AddrType::V4 => {
-            if buf.remaining() < 8 {
+            if buf.remaining() < 6 {
                 return Ok(None);
             }
             Address::Ip(SocketAddr::V4(SocketAddrV4::new(
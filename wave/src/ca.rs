@@ -0,0 +1,310 @@
+use crate::endpoint::write_private;
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, DistinguishedName, DnType, GeneralSubtree,
+    IsCa, KeyPair, KeyUsagePurpose, NameConstraints,
+};
+use rustls::{
+    crypto::ring::{default_provider, sign::any_supported_type},
+    pki_types::PrivateKeyDer,
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    ServerConfig,
+};
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+use time::{Duration, OffsetDateTime};
+use tracing::{debug, info, warn};
+use wave_core::NodeId;
+
+const CA_CERT_FILE: &str = "ca.pem";
+
+const CA_KEY_FILE: &str = "ca_key.pem";
+
+const CA_NAME: &str = "wave local CA";
+
+/// Kept short since whoever holds the key of a trusted CA can sign for the
+/// names it covers.
+const CA_VALIDITY: Duration = Duration::days(365);
+
+/// Browsers refuse leaf certificates valid for longer than 398 days.
+const LEAF_VALIDITY: Duration = Duration::days(90);
+
+/// How long before it expires a leaf certificate is minted anew.
+const LEAF_RENEWAL: Duration = Duration::days(7);
+
+const LOCALHOST: &str = "localhost";
+
+/// A CA kept in the state directory that signs certificates for wave names, so
+/// the HTTP gateway can serve them over HTTPS once the CA is trusted. Name
+/// constraints limit it to names under `localhost`.
+pub struct LocalCa {
+    cert: Certificate,
+    key: KeyPair,
+    pem: String,
+    not_after: OffsetDateTime,
+    /// Wildcard certificates by the name they cover, e.g. `<node_id>.localhost`,
+    /// with when they expire.
+    leaves: Mutex<HashMap<String, (OffsetDateTime, Arc<CertifiedKey>)>>,
+}
+
+impl std::fmt::Debug for LocalCa {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LocalCa").finish_non_exhaustive()
+    }
+}
+
+impl LocalCa {
+    /// Loads the CA from `state_dir`, creating it on first use. Without a state
+    /// directory the CA lives as long as the process.
+    pub fn load_or_create(state_dir: Option<&Path>) -> anyhow::Result<Self> {
+        let Some(state_dir) = state_dir else {
+            warn!("No state directory, the local CA changes on every start");
+            return Self::generate();
+        };
+        let (cert_path, key_path) = (state_dir.join(CA_CERT_FILE), state_dir.join(CA_KEY_FILE));
+        match std::fs::read_to_string(&key_path) {
+            Ok(key) => {
+                let pem = std::fs::read_to_string(&cert_path)?;
+                if let Some(ca) = Self::from_pem(&pem, &key)? {
+                    return Ok(ca);
+                }
+                warn!(
+                    path = %cert_path.display(),
+                    "Replacing expiring or unconstrained local CA, trust the new one"
+                );
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        let ca = Self::generate()?;
+        std::fs::create_dir_all(state_dir)?;
+        write_private(&key_path, ca.key.serialize_pem().as_bytes())?;
+        std::fs::write(&cert_path, &ca.pem)?;
+        info!(path = %cert_path.display(), "Generated new local CA");
+        Ok(ca)
+    }
+
+    pub fn generate() -> anyhow::Result<Self> {
+        let key = KeyPair::generate()?;
+        let mut params = CertificateParams::default();
+        params.distinguished_name = DistinguishedName::new();
+        params.distinguished_name.push(DnType::CommonName, CA_NAME);
+        params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+        params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+        params.name_constraints = Some(NameConstraints {
+            permitted_subtrees: vec![GeneralSubtree::DnsName(LOCALHOST.to_string())],
+            excluded_subtrees: Vec::new(),
+        });
+        let now = OffsetDateTime::now_utc();
+        params.not_before = now - Duration::days(1);
+        params.not_after = now + CA_VALIDITY;
+        let not_after = params.not_after;
+        let cert = params.self_signed(&key)?;
+        let pem = cert.pem();
+        Ok(Self {
+            cert,
+            key,
+            pem,
+            not_after,
+            leaves: Mutex::default(),
+        })
+    }
+
+    /// The stored CA, or `None` when it expires before a leaf minted now would,
+    /// or was made without name constraints.
+    fn from_pem(pem: &str, key: &str) -> anyhow::Result<Option<Self>> {
+        let key = KeyPair::from_pem(key)?;
+        let params = CertificateParams::from_ca_cert_pem(pem)?;
+        let not_after = params.not_after;
+        if params.name_constraints.is_none()
+            || not_after < OffsetDateTime::now_utc() + LEAF_VALIDITY
+        {
+            return Ok(None);
+        }
+        // Signing only needs the subject and key of the stored certificate,
+        // which a re-signed copy shares.
+        let cert = params.self_signed(&key)?;
+        Ok(Some(Self {
+            cert,
+            key,
+            pem: pem.to_string(),
+            not_after,
+            leaves: Mutex::default(),
+        }))
+    }
+
+    /// The CA certificate to install as trusted, in PEM.
+    pub fn cert_pem(&self) -> &str {
+        &self.pem
+    }
+
+    /// TLS settings for a listener serving wave names with certificates from
+    /// this CA. Only HTTP/1.1 is offered since requests are relayed as is.
+    pub fn server_config(self: Arc<Self>) -> anyhow::Result<ServerConfig> {
+        let mut config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_cert_resolver(self);
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(config)
+    }
+
+    /// The certificate for `*.<base>` and `<base>`, minted on first use and
+    /// again when it is about to expire.
+    fn leaf(&self, base: &str) -> anyhow::Result<Arc<CertifiedKey>> {
+        let now = OffsetDateTime::now_utc();
+        if let Some((not_after, leaf)) = self.leaves.lock().unwrap().get(base) {
+            if *not_after - now > LEAF_RENEWAL {
+                return Ok(leaf.clone());
+            }
+        }
+        let key = KeyPair::generate()?;
+        let mut params = CertificateParams::new([format!("*.{base}"), base.to_string()])?;
+        params.distinguished_name = DistinguishedName::new();
+        params.distinguished_name.push(DnType::CommonName, base);
+        params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+        params.not_before = now - Duration::days(1);
+        params.not_after = (now + LEAF_VALIDITY).min(self.not_after);
+        let not_after = params.not_after;
+        let cert = params.signed_by(&key, &self.cert, &self.key)?;
+        let signing_key = any_supported_type(&PrivateKeyDer::Pkcs8(key.serialize_der().into()))?;
+        let leaf = Arc::new(CertifiedKey::new(vec![cert.der().clone()], signing_key));
+        debug!(%base, "Minted gateway certificate");
+        self.leaves
+            .lock()
+            .unwrap()
+            .insert(base.to_string(), (not_after, leaf.clone()));
+        Ok(leaf)
+    }
+}
+
+impl ResolvesServerCert for LocalCa {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let base = wildcard_base(client_hello.server_name()?)?;
+        self.leaf(&base)
+            .inspect_err(|e| tracing::error!("Mint certificate error: {}", e))
+            .ok()
+    }
+}
+
+/// The name a wildcard certificate for `server_name` covers,
+/// `<node_id>.localhost`. Bare wave names get none, the CA may only sign for
+/// names under `localhost`.
+fn wildcard_base(server_name: &str) -> Option<String> {
+    let name = server_name.trim_end_matches('.').to_ascii_lowercase();
+    let labels: Vec<&str> = name.split('.').collect();
+    match labels.as_slice() {
+        [.., id, LOCALHOST] if id.parse::<NodeId>().is_ok() => Some(format!("{id}.{LOCALHOST}")),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::{
+        pki_types::{pem::PemObject, CertificateDer, ServerName},
+        ClientConfig, ClientConnection, RootCertStore, ServerConnection,
+    };
+
+    /// Runs a TLS handshake for `name` in memory, the client trusting only
+    /// `root_pem`.
+    fn handshake(server: ServerConfig, root_pem: &str, name: &str) -> Result<(), rustls::Error> {
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(CertificateDer::from_pem_slice(root_pem.as_bytes()).unwrap())
+            .unwrap();
+        let client = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let name = ServerName::try_from(name.to_string()).unwrap();
+        let mut client = ClientConnection::new(Arc::new(client), name).unwrap();
+        let mut server = ServerConnection::new(Arc::new(server)).unwrap();
+        while client.is_handshaking() || server.is_handshaking() {
+            let mut buf = Vec::new();
+            client.write_tls(&mut buf).unwrap();
+            server.read_tls(&mut buf.as_slice()).unwrap();
+            server.process_new_packets()?;
+            buf.clear();
+            server.write_tls(&mut buf).unwrap();
+            client.read_tls(&mut buf.as_slice()).unwrap();
+            client.process_new_packets()?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_or_create() {
+        let state_dir = std::env::temp_dir().join(format!("wave-test-{}", rand::random::<u64>()));
+        let first = LocalCa::load_or_create(Some(&state_dir)).unwrap();
+        let second = LocalCa::load_or_create(Some(&state_dir)).unwrap();
+        assert_eq!(first.cert_pem(), second.cert_pem());
+        drop(first);
+
+        // Leaves of the reloaded CA verify against the certificate on disk.
+        let id = NodeId(iroh::SecretKey::from_bytes(&[1; 32]).public());
+        let on_disk = std::fs::read_to_string(state_dir.join(CA_CERT_FILE)).unwrap();
+        let server = Arc::new(second).server_config().unwrap();
+        handshake(server, &on_disk, &format!("web.{id}.localhost")).unwrap();
+        std::fs::remove_dir_all(state_dir).unwrap();
+    }
+
+    #[test]
+    fn test_name_constraints() {
+        let ca = LocalCa::generate().unwrap();
+        let key = KeyPair::generate().unwrap();
+        let cert = CertificateParams::new(["example.com".to_string()])
+            .unwrap()
+            .signed_by(&key, &ca.cert, &ca.key)
+            .unwrap();
+        let server = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![cert.der().clone()],
+                PrivateKeyDer::Pkcs8(key.serialize_der().into()),
+            )
+            .unwrap();
+        let e = handshake(server, ca.cert_pem(), "example.com").unwrap_err();
+        assert!(format!("{e:?}").contains("NameConstraintViolation"));
+    }
+
+    #[test]
+    fn test_leaf_renewal() {
+        let ca = LocalCa::generate().unwrap();
+        let first = ca.leaf("node.localhost").unwrap();
+        assert!(Arc::ptr_eq(&first, &ca.leaf("node.localhost").unwrap()));
+
+        let expiring = OffsetDateTime::now_utc() + Duration::days(1);
+        ca.leaves
+            .lock()
+            .unwrap()
+            .get_mut("node.localhost")
+            .unwrap()
+            .0 = expiring;
+        assert!(!Arc::ptr_eq(&first, &ca.leaf("node.localhost").unwrap()));
+    }
+
+    #[test]
+    fn test_wildcard_base() {
+        let id = NodeId(iroh::SecretKey::from_bytes(&[1; 32]).public());
+        let base = |name: String| wildcard_base(&name);
+        assert_eq!(
+            base(format!("web.{id}.localhost")),
+            Some(format!("{id}.localhost"))
+        );
+        assert_eq!(base(format!("a.b.{id}")), None);
+        assert_eq!(
+            base(format!("{id}.localhost.")),
+            Some(format!("{id}.localhost"))
+        );
+        assert_eq!(base(format!("{id}.example.com")), None);
+        assert_eq!(base("example.com".to_string()), None);
+    }
+}
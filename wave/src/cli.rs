@@ -1,32 +1,109 @@
-use crate::{client::Client, config, server::ServerService, ALPN};
-use clap::{Args, Parser};
+use crate::{
+    ca::LocalCa,
+    client::{gateway::DEFAULT_GATEWAY_PORT, Client},
+    config::{self, Config, PeerAddr},
+    dns::{DnsServer, FakeIpPool},
+    endpoint,
+    path::PathReporter,
+    public_gateway::{PublicGateway, PublicGatewayConfig},
+    relay::BufferPool,
+    relay_server::{SelfHostedRelay, DEFAULT_RELAY_HTTP_BIND, DEFAULT_RELAY_STUN_BIND},
+    remote_dns::RemoteDnsRules,
+    resolver::{DnsResolver, Resolve},
+    rules::{RuleTable, Rules},
+    server::{sni::SniRoutes, ServerService, DEFAULT_MAX_CONCURRENT_STREAMS},
+    subnet::{SubnetGrants, SubnetRoutes},
+    udp::DEFAULT_UDP_IDLE_TIMEOUT,
+};
+use clap::{Args, Parser, Subcommand};
 use iroh::Endpoint;
-use std::sync::Arc;
-use tracing::info;
-use wave_core::{NodeId, Server};
-
-const SERVER_ENDPOINT: &str = "127.0.0.1:8282";
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use tracing::{info, warn};
+use wave_core::{Connection, NodeId, Server};
+use wave_proxy::Address;
 
 const CLIENT_PROXY: &str = "127.0.0.1:8182";
 
 const DOWNSTREAM: &str = "127.0.0.1";
 
+/// How often the config file is checked for changed routing rules.
+const RULES_RELOAD_INTERVAL: Duration = Duration::from_secs(2);
+
+const DEFAULT_STATUS_INTERVAL: Duration = Duration::from_secs(60);
+
 #[derive(Parser)]
 pub enum Cli {
     Bind(BindArgs),
+    /// Run a relay server for wave nodes, see `relays` in the config
+    Relay(RelayArgs),
+    /// Serve the routes nodes made public to clients outside wave, see
+    /// `public_gateway` in the config
+    Gateway(GatewayArgs),
+    /// Manage the local CA the HTTP gateway signs certificates with
+    #[command(subcommand)]
+    Ca(CaCommand),
+    /// Inspect the routing rules in the config
+    #[command(subcommand)]
+    Route(RouteCommand),
 }
 
 #[derive(Args)]
 pub struct BindArgs {
     pub addr: Option<String>,
+    /// Run without relays or n0 discovery
+    #[arg(long)]
+    pub offline: bool,
+    /// Static peer address as `node_id@ip:port`, may be repeated
+    #[arg(long = "peer")]
+    pub peers: Vec<PeerAddr>,
+}
+
+#[derive(Args)]
+pub struct GatewayArgs {
+    /// Address to serve on, instead of the one in the config
+    #[arg(long)]
+    pub bind: Option<SocketAddr>,
+    /// Domain the node names go under, e.g. `gw.example.com`, instead of the
+    /// one in the config
+    #[arg(long)]
+    pub domain: Option<String>,
+}
+
+#[derive(Subcommand)]
+pub enum CaCommand {
+    /// Print the CA certificate in PEM, for installing it as trusted
+    Export,
+}
+
+#[derive(Subcommand)]
+pub enum RouteCommand {
+    /// Show how a connection to `host:port` would be routed, without
+    /// connecting
+    Test { addr: Address },
+}
+
+#[derive(Args)]
+pub struct RelayArgs {
+    /// Address the relay serves HTTP on
+    #[arg(long, default_value = DEFAULT_RELAY_HTTP_BIND)]
+    pub http_bind: SocketAddr,
+    /// Address the STUN server binds to
+    #[arg(long, default_value = DEFAULT_RELAY_STUN_BIND)]
+    pub stun_bind: SocketAddr,
+    /// Do not run the STUN server
+    #[arg(long)]
+    pub no_stun: bool,
 }
 
 pub async fn run_cli() -> anyhow::Result<()> {
     let cli = Cli::parse();
-    let config = config::init_config()?;
+    let mut config = config::init_config()?;
     match cli {
         Cli::Bind(args) => {
-            let mut server = Server::try_from_iter(config.router)?;
+            config.offline |= args.offline;
+            config.peers.extend(args.peers);
+
+            let mut server = Server::try_from_iter(config.router.clone())?;
             if let Some(addr) = args.addr {
                 server.add("".parse()?, addr.parse()?);
             } else {
@@ -34,42 +111,274 @@ pub async fn run_cli() -> anyhow::Result<()> {
             }
 
             server.iter().for_each(|(k, v)| info!("{}: {}", k, v));
+            check_route_proxies(&server, &config)?;
+            let sni_routes = SniRoutes::new(config.sni_routes.clone())?;
 
-            let ep = Endpoint::builder()
-                .alpns(vec![ALPN.into()])
-                .discovery_local_network()
-                .discovery_n0()
-                // .discovery_dht()
-                .bind_addr_v4(SERVER_ENDPOINT.parse().unwrap())
-                .bind()
-                .await
-                .unwrap();
+            let ep = endpoint::bind(&config).await?;
 
             let server = Arc::new(server);
-            spawn_client(ep.clone(), server.clone());
-            spawn_server(ep, server).await;
+            let buffers = config
+                .relay_memory_budget
+                .map(BufferPool::with_budget)
+                .unwrap_or_default();
+            let mut proxies = config.proxy_bind.clone();
+            if proxies.is_empty() {
+                proxies.push(CLIENT_PROXY.parse()?);
+            }
+            let mut client = Client::new(proxies[0], ep.clone(), server.clone()).await?;
+            for proxy in &proxies[1..] {
+                client = client.listen(*proxy).await?;
+            }
+            let mut client = client
+                .with_buffer_pool(buffers.clone())
+                .with_path_policy(config.client_paths.clone())
+                .with_udp_idle_timeout(udp_idle_timeout(&config));
+            let resolver: Arc<dyn Resolve> = Arc::new(DnsResolver::new(&config.resolver));
+            let rules = RuleTable::new(Rules::new(config.rules.clone()));
+            client = client
+                .with_rules(rules.clone())
+                .with_resolver(resolver.clone());
+            tokio::spawn(
+                rules.reload_on_change(config::CONFIG_NAME.to_string(), RULES_RELOAD_INTERVAL),
+            );
+            if let Some(exit) = config.exit_node {
+                client = client.with_exit_node(exit);
+            }
+            if !config.subnet_routes.is_empty() {
+                client = client.with_subnet_routes(SubnetRoutes::new(config.subnet_routes.clone()));
+                check_subnet_routes(&client, &config);
+            }
+            if !config.remote_dns.is_empty() {
+                client = client.with_remote_dns(RemoteDnsRules::new(config.remote_dns.clone()));
+            }
+            if let Some(dns) = &config.dns {
+                let pool = Arc::new(FakeIpPool::new(dns.fake_ipv4, dns.fake_ipv6)?);
+                let server = DnsServer::bind(dns.bind, dns.upstream, pool.clone()).await?;
+                tokio::spawn(async move {
+                    server
+                        .run()
+                        .await
+                        .inspect_err(|e| tracing::error!("DNS server error: {}", e))
+                        .ok();
+                });
+                client = client.with_fake_ips(pool);
+            }
+            for forward in &config.udp_forwards {
+                let forward = client
+                    .udp_forward(forward.bind, forward.target.parse()?)
+                    .await?;
+                tokio::spawn(async move {
+                    forward
+                        .run()
+                        .await
+                        .inspect_err(|e| tracing::error!("UDP forward error: {}", e))
+                        .ok();
+                });
+            }
+            if let Some(gateway) = &config.http_gateway {
+                let ca = gateway
+                    .tls
+                    .then(|| LocalCa::load_or_create(config.state_dir.as_deref()))
+                    .transpose()?
+                    .map(Arc::new);
+                client = client.with_http_gateway(gateway, ca).await?;
+            }
+            if let Some(bind) = config.pac_bind {
+                let pac = client.pac_server(bind).await?;
+                tokio::spawn(async move {
+                    pac.run()
+                        .await
+                        .inspect_err(|e| tracing::error!("PAC server error: {}", e))
+                        .ok();
+                });
+            }
+            let path_reporter = client.path_reporter().clone();
+            if let Some(interval) = status_interval(&config) {
+                tokio::spawn(buffers.clone().log_usage(interval));
+                tokio::spawn(path_reporter.clone().log_streams(interval));
+            }
+            spawn_client(client);
+            spawn_server(
+                ep,
+                server,
+                sni_routes,
+                resolver,
+                buffers,
+                path_reporter,
+                &config,
+            )
+            .await;
+        }
+        Cli::Relay(args) => {
+            let stun_bind = (!args.no_stun).then_some(args.stun_bind);
+            let relay = SelfHostedRelay::spawn(args.http_bind, stun_bind).await?;
+            println!("relay: http://{}", relay.http_addr());
+            relay.run().await?;
+        }
+        Cli::Gateway(args) => {
+            let gateway = match (config.public_gateway.clone(), args.bind, args.domain) {
+                (Some(mut gateway), bind, domain) => {
+                    gateway.bind = bind.unwrap_or(gateway.bind);
+                    gateway.domain = domain.unwrap_or(gateway.domain);
+                    gateway
+                }
+                (None, Some(bind), Some(domain)) => PublicGatewayConfig {
+                    bind,
+                    domain,
+                    custom_domains: Default::default(),
+                    port: DEFAULT_GATEWAY_PORT,
+                    tls: None,
+                    rate_limit: Default::default(),
+                    endpoint_bind: None,
+                },
+                (None, _, _) => {
+                    anyhow::bail!("no public_gateway in the config, pass --bind and --domain")
+                }
+            };
+            let ep = endpoint::bind_gateway(&config, &gateway).await?;
+            println!("node_id: {}", NodeId(ep.node_id()));
+            PublicGateway::bind(&gateway, ep).await?.run().await?;
+        }
+        Cli::Ca(CaCommand::Export) => {
+            let Some(state_dir) = &config.state_dir else {
+                anyhow::bail!("no state_dir in the config, the CA would not outlive this command")
+            };
+            let ca = LocalCa::load_or_create(Some(state_dir))?;
+            print!("{}", ca.cert_pem());
+        }
+        Cli::Route(RouteCommand::Test { addr }) => {
+            let rules = Rules::new(config.rules.clone());
+            match rules.decide(&addr) {
+                Some((index, rule)) => println!("rule {}: {}", index + 1, rule),
+                None => println!("no rule: {}", default_route(&config, &addr)),
+            }
         }
     }
 
     Ok(())
 }
 
-fn spawn_client(ep: Endpoint, server: Arc<Server>) {
+fn spawn_client(client: Client) {
     tokio::spawn(async move {
         info!("start client");
-        let client = Client::new(CLIENT_PROXY, ep, server).await.unwrap();
-
         client.run().await.unwrap();
     });
 }
 
-async fn spawn_server(ep: Endpoint, server: Arc<Server>) {
+async fn spawn_server(
+    ep: Endpoint,
+    server: Arc<Server>,
+    sni_routes: SniRoutes,
+    resolver: Arc<dyn Resolve>,
+    buffers: BufferPool,
+    path_reporter: PathReporter,
+    config: &Config,
+) {
     info!("start server");
     let node_id = NodeId(ep.node_id());
 
     println!("node_id: {}", node_id);
 
-    let server = ServerService::new(server, ep);
+    let mut server = ServerService::new(server, ep)
+        .with_max_concurrent_streams(
+            config
+                .max_concurrent_streams
+                .unwrap_or(DEFAULT_MAX_CONCURRENT_STREAMS),
+        )
+        .with_buffer_pool(buffers)
+        .with_route_paths(config.route_paths.clone())
+        .with_path_reporter(path_reporter)
+        .with_udp_routes(config.udp_routes.iter().cloned().collect())
+        .with_udp_idle_timeout(udp_idle_timeout(config))
+        .with_sni_routes(sni_routes)
+        .with_resolver(resolver)
+        .with_route_proxies(config.route_proxies.clone())
+        .with_forwarders(config.forwarders.clone())
+        .with_public_routes(config.public_routes.clone());
+    for (route, proxy) in &config.route_proxies {
+        info!(%route, %proxy, "Route dials through proxy");
+    }
+    if let Some(exit) = &config.exit {
+        info!(peers = exit.peers.len(), "Serving as exit node");
+        server = server.with_exit_policy(exit.clone());
+    }
+    if !config.subnets.is_empty() {
+        for grant in &config.subnets {
+            info!(net = %grant.net, peers = grant.peers.len(), "Routing subnet");
+        }
+        server = server.with_subnets(SubnetGrants::new(config.subnets.clone()));
+    }
+    for route in &config.public_routes {
+        info!(%route, "Public route");
+    }
 
     server.run().await.unwrap();
 }
+
+/// Refuses proxies for routes the router does not have, which no stream would
+/// ever dial through.
+fn check_route_proxies(server: &Server, config: &Config) -> anyhow::Result<()> {
+    for route in config.route_proxies.keys() {
+        anyhow::ensure!(
+            server
+                .iter()
+                .any(|(subdomain, _)| subdomain.as_str() == route),
+            "route_proxies names route {:?}, which is not in router",
+            route
+        );
+    }
+    Ok(())
+}
+
+/// Warns about subnet routes their node does not advertise to this one, which
+/// it would refuse.
+fn check_subnet_routes(client: &Client, config: &Config) {
+    for route in config.subnet_routes.clone() {
+        let query = client.advertised_subnets(route.via);
+        tokio::spawn(async move {
+            match query.await {
+                Ok(nets) if nets.iter().any(|net| net.contains(&route.net)) => {
+                    info!(net = %route.net, via = %route.via, "Subnet route advertised")
+                }
+                Ok(_) => warn!(net = %route.net, via = %route.via, "Subnet route not advertised"),
+                Err(e) => warn!(net = %route.net, via = %route.via, "Query subnets error: {}", e),
+            }
+        });
+    }
+}
+
+/// How the client reaches `addr` when no rule matches it. Fake IPs are not
+/// known outside the running client.
+fn default_route(config: &Config, addr: &Address) -> String {
+    let exit = |what: &str| match config.exit_node {
+        Some(exit) => format!("exit node {exit}"),
+        None => what.to_string(),
+    };
+    match addr {
+        Address::Ip(ip) => match SubnetRoutes::new(config.subnet_routes.clone()).lookup(ip.ip()) {
+            Some(via) => format!("subnet route via {via}"),
+            None => exit("direct"),
+        },
+        Address::Domain(domain, port) => match Connection::connect(domain, *port) {
+            Ok((_, conn)) => format!("wave node {}", conn.node_id()),
+            Err(_) => match RemoteDnsRules::new(config.remote_dns.clone()).lookup(domain) {
+                Some(via) => format!("resolved and dialed by {via}"),
+                None => exit("direct"),
+            },
+        },
+    }
+}
+
+fn status_interval(config: &Config) -> Option<Duration> {
+    match config.status_interval {
+        Some(0) => None,
+        Some(secs) => Some(Duration::from_secs(secs)),
+        None => Some(DEFAULT_STATUS_INTERVAL),
+    }
+}
+
+fn udp_idle_timeout(config: &Config) -> Duration {
+    config
+        .udp_idle_timeout
+        .map_or(DEFAULT_UDP_IDLE_TIMEOUT, Duration::from_secs)
+}
@@ -0,0 +1,103 @@
+use bytes::{Bytes, BytesMut};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio_rustls::TlsAcceptor;
+
+/// Port the gateway connects to on the route's target unless configured.
+pub const DEFAULT_GATEWAY_PORT: u16 = 80;
+
+/// Upper bound on the head of an HTTP request.
+pub(crate) const MAX_REQUEST_HEAD: usize = 16 * 1024;
+
+const LOCALHOST: &str = "localhost";
+
+/// An HTTP listener for browsers without proxy settings: a request for
+/// `http://web.<node_id>.localhost:8080/` goes to the route `web` of that
+/// node, on `port`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct GatewayConfig {
+    pub bind: SocketAddr,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    /// Serves HTTPS with certificates from the local CA, see `wave ca export`.
+    #[serde(default)]
+    pub tls: bool,
+}
+
+fn default_port() -> u16 {
+    DEFAULT_GATEWAY_PORT
+}
+
+/// How a gateway connection is served.
+#[derive(Clone)]
+pub(crate) struct GatewayMode {
+    pub(crate) port: u16,
+    pub(crate) tls: Option<TlsAcceptor>,
+}
+
+/// Reads until `buf` holds a complete request head and returns its length, or
+/// `None` when the peer closes first or the head grows too large.
+pub(crate) async fn read_head<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    buf: &mut BytesMut,
+) -> std::io::Result<Option<usize>> {
+    loop {
+        if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            return Ok(Some(end + 4));
+        }
+        if buf.len() >= MAX_REQUEST_HEAD || reader.read_buf(buf).await? == 0 {
+            return Ok(None);
+        }
+    }
+}
+
+/// The value of the `Host` header of a request head.
+pub(crate) fn request_host(head: &[u8]) -> Option<&str> {
+    std::str::from_utf8(head)
+        .ok()?
+        .split("\r\n")
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("host"))
+        .map(|(_, value)| value.trim())
+}
+
+/// The wave name a `Host` value stands for: without port and trailing dot, and
+/// with a `.localhost` suffix taken off.
+pub(crate) fn wave_name(host: &str) -> String {
+    let host = match host.rsplit_once(':') {
+        Some((name, port)) if port.bytes().all(|b| b.is_ascii_digit()) => name,
+        _ => host,
+    };
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    match host.strip_suffix(LOCALHOST) {
+        Some(name) if name.ends_with('.') => name.trim_end_matches('.').to_string(),
+        _ => host,
+    }
+}
+
+/// A plain-text response that ends the connection.
+pub(crate) fn error_response(status: &str) -> Bytes {
+    Bytes::from(format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{status}\n",
+        status.len() + 1
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_host() {
+        let head = b"GET / HTTP/1.1\r\nUser-Agent: test\r\nhost:  Web.Node.localhost:8080 \r\n\r\n";
+        let host = request_host(head).unwrap();
+        assert_eq!(host, "Web.Node.localhost:8080");
+        assert_eq!(wave_name(host), "web.node");
+        assert_eq!(wave_name("web.node.localhost."), "web.node");
+        assert_eq!(wave_name("web.node:80"), "web.node");
+        assert_eq!(wave_name("localhost:8080"), "localhost");
+        assert_eq!(request_host(b"GET / HTTP/1.0\r\n\r\n"), None);
+    }
+}
@@ -1,33 +1,75 @@
 // #![allow(unused)]
-use crate::{Stream, ALPN};
+use crate::{
+    ca::LocalCa,
+    dns::FakeIpPool,
+    path::{self, ClientPathPolicy, PathPolicy, PathReporter, PathTracker, DIRECT_PATH_TIMEOUT},
+    relay::{relay, BufferPool},
+    remote_dns::RemoteDnsRules,
+    resolver::{self, DnsResolver, Resolve, ResolverConfig},
+    rules::{Action, RuleTable, Rules},
+    subnet::SubnetRoutes,
+    udp::DEFAULT_UDP_IDLE_TIMEOUT,
+    Stream,
+};
 use bytes::BytesMut;
-use futures_lite::FutureExt;
-use iroh::Endpoint;
-use std::{net::SocketAddr, sync::Arc};
+use gateway::{error_response, read_head, request_host, wave_name, GatewayConfig, GatewayMode};
+use ipnet::IpNet;
+use iroh::{
+    endpoint::{RecvStream, SendStream},
+    Endpoint,
+};
+use pac::{PacRules, PacServer};
+use pool::{ConnectionPool, Lease, DEFAULT_IDLE_TIMEOUT};
+use std::{future::Future, net::SocketAddr, sync::Arc, task::Poll, time::Duration};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpStream, ToSocketAddrs},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
 };
+use tokio_rustls::TlsAcceptor;
 use tracing::{debug, info};
+use udp::{Association, FlowOpener, UdpForward};
 use wave_core::{
     server::{Fallback, Host},
-    Connection, Server,
+    Connection, NodeId, Server, Subdomain, WavePacket,
 };
 use wave_proxy::{
     protocol::socks5::{
-        types::{ConnectRequest, ConnectedStatus, HandshakeRequest},
-        NoAuthHandshake, Transmit,
+        types::{Command, ConnectRequest, ConnectResponse, ConnectedStatus, HandshakeRequest},
+        NoAuthHandshake, Relay, Transmit,
     },
     Address,
 };
 
+pub mod gateway;
+pub mod pac;
+pub mod pool;
 #[cfg(test)]
 mod tests;
+pub mod udp;
+
+/// How long to wait for the application's first bytes after the SOCKS reply so
+/// they can travel together with the `WavePacket`.
+const EARLY_DATA_WINDOW: Duration = Duration::from_millis(10);
+
+/// Upper bound on the answer to a subnets query.
+const MAX_SUBNETS_REPLY: usize = 64 * 1024;
 
 pub struct Client {
-    listener: tokio::net::TcpListener,
-    endpoint: Endpoint,
+    listeners: Vec<TcpListener>,
+    pool: ConnectionPool,
     server: Arc<Server>,
+    buffers: BufferPool,
+    paths: Arc<ClientPathPolicy>,
+    direct_path_timeout: Duration,
+    path_reporter: PathReporter,
+    udp_idle_timeout: Duration,
+    exit_node: Option<NodeId>,
+    subnet_routes: Arc<SubnetRoutes>,
+    remote_dns: Arc<RemoteDnsRules>,
+    fake_ips: Option<Arc<FakeIpPool>>,
+    gateway: Option<(TcpListener, GatewayMode)>,
+    rules: RuleTable,
+    resolver: Arc<dyn Resolve>,
 }
 
 impl Client {
@@ -36,35 +78,263 @@ impl Client {
         endpoint: Endpoint,
         server: Arc<Server>,
     ) -> Result<Self, std::io::Error> {
-        let listener = tokio::net::TcpListener::bind(bind).await?;
+        let listener = TcpListener::bind(bind).await?;
         Ok(Self {
-            listener,
-            endpoint,
+            listeners: vec![listener],
+            pool: ConnectionPool::new(endpoint, DEFAULT_IDLE_TIMEOUT),
             server,
+            buffers: BufferPool::default(),
+            paths: Arc::default(),
+            direct_path_timeout: DIRECT_PATH_TIMEOUT,
+            path_reporter: PathReporter::default(),
+            udp_idle_timeout: DEFAULT_UDP_IDLE_TIMEOUT,
+            exit_node: None,
+            subnet_routes: Arc::default(),
+            remote_dns: Arc::default(),
+            fake_ips: None,
+            gateway: None,
+            rules: RuleTable::default(),
+            resolver: Arc::new(DnsResolver::new(&ResolverConfig::default())),
         })
     }
 
+    /// Also accepts SOCKS connections on `bind`, e.g. the IPv6 counterpart of
+    /// the first address.
+    pub async fn listen<A: ToSocketAddrs>(mut self, bind: A) -> Result<Self, std::io::Error> {
+        self.listeners.push(TcpListener::bind(bind).await?);
+        Ok(self)
+    }
+
+    /// The address of the first listener.
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.listeners[0].local_addr()
+    }
+
+    pub fn local_addrs(&self) -> std::io::Result<Vec<SocketAddr>> {
+        self.listeners.iter().map(TcpListener::local_addr).collect()
+    }
+
+    /// Shares relay buffers with other services, e.g. the `ServerService` on the
+    /// same endpoint.
+    pub fn with_buffer_pool(mut self, buffers: BufferPool) -> Self {
+        self.buffers = buffers;
+        self
+    }
+
+    /// Sets which destinations must be reached over a direct path.
+    pub fn with_path_policy(mut self, paths: ClientPathPolicy) -> Self {
+        self.paths = Arc::new(paths);
+        self
+    }
+
+    /// How long a direct-only stream waits for a direct path before it is refused.
+    pub fn with_direct_path_timeout(mut self, timeout: Duration) -> Self {
+        self.direct_path_timeout = timeout;
+        self
+    }
+
+    /// Records the path of every stream to a wave peer in `reporter`.
+    pub fn with_path_reporter(mut self, reporter: PathReporter) -> Self {
+        self.path_reporter = reporter;
+        self
+    }
+
+    pub fn path_reporter(&self) -> &PathReporter {
+        &self.path_reporter
+    }
+
+    /// How long a UDP flow may go without a datagram in either direction.
+    pub fn with_udp_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.udp_idle_timeout = timeout;
+        self
+    }
+
+    /// Sends TCP connections to destinations that are not wave routes through
+    /// `exit`, which must list this node in its exit policy.
+    pub fn with_exit_node(mut self, exit: NodeId) -> Self {
+        self.exit_node = Some(exit);
+        self
+    }
+
+    /// Sends TCP connections to IP addresses in a routed subnet through the
+    /// node that routes it, ahead of the exit node.
+    pub fn with_subnet_routes(mut self, routes: SubnetRoutes) -> Self {
+        self.subnet_routes = Arc::new(routes);
+        self
+    }
+
+    /// Has a node resolve the names under its suffixes and connect to them.
+    /// The SOCKS reply waits for its answer, so resolution errors reach the
+    /// application.
+    pub fn with_remote_dns(mut self, rules: RemoteDnsRules) -> Self {
+        self.remote_dns = Arc::new(rules);
+        self
+    }
+
+    /// Routes TCP connections by the first matching rule ahead of everything
+    /// else. Each connection uses the rules in `rules` when it arrives.
+    pub fn with_rules(mut self, rules: RuleTable) -> Self {
+        self.rules = rules;
+        self
+    }
+
+    /// Resolves the names of destinations dialed from this host.
+    pub fn with_resolver(mut self, resolver: Arc<dyn Resolve>) -> Self {
+        self.resolver = resolver;
+        self
+    }
+
+    /// Maps the fake addresses `pool` handed out back to their wave names.
+    pub fn with_fake_ips(mut self, pool: Arc<FakeIpPool>) -> Self {
+        self.fake_ips = Some(pool);
+        self
+    }
+
+    /// Also serves HTTP on `config.bind`, routing each connection by the `Host`
+    /// of its first request. Browsers keep a connection per host, so later
+    /// keep-alive requests and upgrades such as WebSocket are relayed as is.
+    /// With `ca` TLS is terminated here, before the end-to-end encrypted
+    /// stream.
+    pub async fn with_http_gateway(
+        mut self,
+        config: &GatewayConfig,
+        ca: Option<Arc<LocalCa>>,
+    ) -> anyhow::Result<Self> {
+        let tls = match ca {
+            Some(ca) => Some(TlsAcceptor::from(Arc::new(ca.server_config()?))),
+            None => None,
+        };
+        let mode = GatewayMode {
+            port: config.port,
+            tls,
+        };
+        self.gateway = Some((TcpListener::bind(config.bind).await?, mode));
+        Ok(self)
+    }
+
+    pub fn gateway_addr(&self) -> Option<std::io::Result<SocketAddr>> {
+        self.gateway
+            .as_ref()
+            .map(|(listener, _)| listener.local_addr())
+    }
+
+    /// Asks `node_id` which subnets it routes for this node. The returned
+    /// future does not borrow the client.
+    pub fn advertised_subnets(
+        &self,
+        node_id: NodeId,
+    ) -> impl Future<Output = anyhow::Result<Vec<IpNet>>> + Send + 'static {
+        let pool = self.pool.clone();
+        async move {
+            let (mut send, mut recv, _lease) = pool.open_bi(node_id).await?;
+            send.write_all_buf(&mut WavePacket::subnets().encode())
+                .await?;
+            send.finish()?;
+            let reply = recv.read_to_end(MAX_SUBNETS_REPLY).await?;
+            let nets = std::str::from_utf8(&reply)?
+                .lines()
+                .map(str::parse)
+                .collect::<Result<_, _>>()?;
+            Ok(nets)
+        }
+    }
+
+    /// Serves a PAC file on `bind` that sends wave names, remote DNS suffixes
+    /// and routed subnets through the first SOCKS listener and everything else
+    /// direct, or through the proxy too when an exit node is set.
+    pub async fn pac_server(&self, bind: SocketAddr) -> std::io::Result<PacServer> {
+        let routes = self.subnet_routes.routes();
+        let rules = PacRules {
+            proxy: self.local_addr()?,
+            suffixes: self
+                .remote_dns
+                .rules()
+                .map(|(suffix, _)| suffix.to_string())
+                .collect(),
+            nets: routes.iter().map(|route| route.net).collect(),
+            exit: self.exit_node.is_some(),
+        };
+        PacServer::bind(bind, rules).await
+    }
+
+    /// Forwards the local UDP port `bind` to `target`, a UDP route written as
+    /// `subdomain.node_id:port`. Shares connections with the SOCKS proxy.
+    pub async fn udp_forward(
+        &self,
+        bind: SocketAddr,
+        target: Address,
+    ) -> anyhow::Result<UdpForward> {
+        UdpForward::bind(bind, target, self.flow_opener()).await
+    }
+
+    fn flow_opener(&self) -> FlowOpener {
+        FlowOpener {
+            pool: self.pool.clone(),
+            paths: self.paths.clone(),
+            direct_path_timeout: self.direct_path_timeout,
+            path_reporter: self.path_reporter.clone(),
+            idle_timeout: self.udp_idle_timeout,
+        }
+    }
+
     pub async fn run(self) -> anyhow::Result<()> {
         loop {
-            let (stream, local) = self.listener.accept().await?;
-            let endpoint = self.endpoint.clone();
+            let (stream, local, gateway) = self.accept().await?;
+            let pool = self.pool.clone();
             let server = self.server.clone();
+            let buffers = self.buffers.clone();
+            let paths = self.paths.clone();
+            let direct_path_timeout = self.direct_path_timeout;
+            let path_reporter = self.path_reporter.clone();
+            let udp = self.flow_opener();
+            let exit_node = self.exit_node;
+            let subnet_routes = self.subnet_routes.clone();
+            let remote_dns = self.remote_dns.clone();
+            let fake_ips = self.fake_ips.clone();
+            let rules = self.rules.load();
+            let resolver = self.resolver.clone();
             tokio::spawn(async move {
                 let upstream_address = stream
                     .peer_addr()
                     .inspect_err(|e| tracing::error!("Get peer address failed: {}", e))
                     .expect("Get peer address failed");
+                let upstream = match gateway.as_ref().and_then(|mode| mode.tls.as_ref()) {
+                    Some(acceptor) => match acceptor.accept(stream).await {
+                        Ok(stream) => Stream::Tls(Box::new(stream)),
+                        Err(e) => {
+                            debug!(%upstream_address, "Gateway TLS handshake error: {}", e);
+                            return;
+                        }
+                    },
+                    None => Stream::Tcp(stream),
+                };
                 let handler = Handler {
                     server,
                     local,
-                    endpoint,
+                    pool,
                     upstream_address,
-                    upstream: Stream::Tcp(stream),
+                    upstream,
                     downstream: None,
+                    lease: None,
+                    buffers,
+                    paths,
+                    direct_path_timeout,
+                    direct_only: None,
+                    path_reporter,
+                    path: None,
+                    udp,
+                    exit_node,
+                    subnet_routes,
+                    remote_dns,
+                    fake_ips,
+                    rules,
+                    resolver,
                 };
-                handler
-                    .handle()
-                    .await
+                let handled = match gateway {
+                    Some(mode) => handler.handle_http(mode.port).await,
+                    None => handler.handle().await,
+                };
+                handled
                     .inspect_err(|e| {
                         tracing::error!("handle incomming error: {}", e);
                     })
@@ -74,13 +344,50 @@ impl Client {
     }
 }
 
+impl Client {
+    /// Accepts on any listener; the mode is set for HTTP gateway connections.
+    async fn accept(&self) -> std::io::Result<(TcpStream, SocketAddr, Option<GatewayMode>)> {
+        std::future::poll_fn(|cx| {
+            for listener in &self.listeners {
+                if let Poll::Ready(res) = listener.poll_accept(cx) {
+                    return Poll::Ready(res.map(|(stream, local)| (stream, local, None)));
+                }
+            }
+            if let Some((listener, mode)) = &self.gateway {
+                if let Poll::Ready(res) = listener.poll_accept(cx) {
+                    return Poll::Ready(
+                        res.map(|(stream, local)| (stream, local, Some(mode.clone()))),
+                    );
+                }
+            }
+            Poll::Pending
+        })
+        .await
+    }
+}
+
 struct Handler {
     server: Arc<Server>,
     local: SocketAddr,
     upstream_address: SocketAddr,
-    endpoint: Endpoint,
+    pool: ConnectionPool,
     upstream: Stream,
     downstream: Option<(Address, Stream)>,
+    lease: Option<Lease>,
+    buffers: BufferPool,
+    paths: Arc<ClientPathPolicy>,
+    direct_path_timeout: Duration,
+    /// Set when the downstream must stay on a direct path.
+    direct_only: Option<NodeId>,
+    path_reporter: PathReporter,
+    path: Option<PathTracker>,
+    udp: FlowOpener,
+    exit_node: Option<NodeId>,
+    subnet_routes: Arc<SubnetRoutes>,
+    remote_dns: Arc<RemoteDnsRules>,
+    fake_ips: Option<Arc<FakeIpPool>>,
+    rules: Arc<Rules>,
+    resolver: Arc<dyn Resolve>,
 }
 
 impl Handler {
@@ -99,44 +406,301 @@ impl Handler {
 
         let req = ConnectRequest::decode(&mut buf)?.unwrap();
 
+        if req.command == Command::UdpAssociate {
+            return self.associate(req.target).await;
+        }
+
         info!(target = %req.target, "Try to connect " );
-        let (transmit, socks5) = socks5?.connect(req.clone(), ConnectedStatus::Succeeded);
+        let action = self.rule_action(&req.target);
+        let remote = match action {
+            Some(_) => None,
+            None => self.remote_resolver(&req.target),
+        };
+        let status = match (&action, remote) {
+            (Some(Action::Block), _) => {
+                info!(target = %req.target, "Blocked by rule");
+                ConnectedStatus::ConnectionNotAllowed
+            }
+            (_, Some(node_id)) => match self.connect_resolving(node_id, &req.target).await {
+                Ok(stream) => {
+                    self.downstream = Some((req.target.clone(), stream));
+                    ConnectedStatus::Succeeded
+                }
+                Err(status) => status,
+            },
+            _ => ConnectedStatus::Succeeded,
+        };
+        let (transmit, socks5) = socks5?.connect(req.clone(), status);
         self.send_transmit(transmit).await?;
+        if status != ConnectedStatus::Succeeded {
+            return Ok(());
+        }
+
+        // The SOCKS state machine is only consulted for the handshake; the relay
+        // itself runs in `relay::relay` without per-chunk bookkeeping.
+        let _socks5: Relay = if remote.is_some() {
+            socks5?
+        } else {
+            match self
+                .connect_to_downstream(req.target.clone(), action, &mut buf)
+                .await
+            {
+                Ok(mut stream) => {
+                    if !buf.is_empty() {
+                        stream.write_all_buf(&mut buf).await?;
+                    }
+                    self.downstream = Some((req.target.clone(), stream));
+                    Ok(socks5?)
+                }
+                Err(e) => {
+                    let fallback = Fallback::default();
+                    self.upstream.write_all_buf(&mut fallback.bytes()).await?;
+                    Err(e)
+                }
+            }?
+        };
+
+        self.relay_downstream().await
+    }
+
+    /// Serves a connection to the HTTP gateway: the `Host` of the first request
+    /// names the wave route, which is dialed on `port` with the request as
+    /// early data.
+    async fn handle_http(mut self, port: u16) -> anyhow::Result<()> {
+        info!("HTTP gateway connect from {}", self.upstream_address);
+
+        let mut buf = BytesMut::with_capacity(1024);
+        if read_head(&mut self.upstream, &mut buf).await?.is_none() {
+            if !buf.is_empty() {
+                self.upstream
+                    .write_all_buf(&mut error_response("400 Bad Request"))
+                    .await?;
+            }
+            return Ok(());
+        }
+        let Some(name) = request_host(&buf).map(wave_name) else {
+            self.upstream
+                .write_all_buf(&mut error_response("400 Bad Request"))
+                .await?;
+            return Ok(());
+        };
+        if Connection::connect(&name, port).is_err() {
+            info!(%name, "HTTP gateway host is not a wave name");
+            self.upstream
+                .write_all_buf(&mut error_response("404 Not Found"))
+                .await?;
+            return Ok(());
+        }
 
-        let mut socks5 = match self.connect_to_downstream(req.target.clone()).await {
-            Ok(stream) => {
-                self.downstream = Some((req.target.clone(), stream));
-                Ok(socks5?)
+        let target = Address::Domain(Arc::from(name), port);
+        let action = self.rule_action(&target);
+        match self
+            .connect_to_downstream(target.clone(), action, &mut buf)
+            .await
+        {
+            Ok(mut stream) => {
+                if !buf.is_empty() {
+                    stream.write_all_buf(&mut buf).await?;
+                }
+                self.downstream = Some((target, stream));
             }
             Err(e) => {
-                let fallback = Fallback::default();
-                self.upstream.write_all_buf(&mut fallback.bytes()).await?;
-                Err(e)
+                info!(%target, "HTTP gateway connect error: {}", e);
+                self.upstream
+                    .write_all_buf(&mut error_response("502 Bad Gateway"))
+                    .await?;
+                return Ok(());
             }
-        }?;
+        }
+        self.relay_downstream().await
+    }
 
-        buf.clear();
-        buf.reserve(8 * 1024);
-        let mut buf2 = BytesMut::with_capacity(8 * 1024);
-        loop {
-            if let Some((addr, stream)) = self.downstream.as_mut() {
-                let (addr, data) = async {
-                    stream.read_buf(&mut buf).await?;
-                    anyhow::Ok((addr.clone(), buf.split().freeze()))
-                }
-                .race(async {
-                    self.upstream.read_buf(&mut buf2).await?;
-                    Ok((self.upstream_address.into(), buf2.split().freeze()))
-                })
-                .await?;
-                let transmit = socks5.relay(addr, data);
-                self.send_transmit(transmit).await?;
+    async fn relay_downstream(&mut self) -> anyhow::Result<()> {
+        let Some((target, downstream)) = self.downstream.as_mut() else {
+            return Ok(());
+        };
+        let account = self.buffers.account(target.to_string());
+        let relaying = relay(&mut self.upstream, downstream, &account);
+        let (sent, received) = match self.direct_only {
+            Some(node_id) => tokio::select! {
+                res = relaying => res?,
+                e = path::direct_lost(self.pool.endpoint(), node_id) => return Err(e),
+            },
+            None => relaying.await?,
+        };
+        debug!(%target, sent, received, "Relay finished");
+
+        Ok(())
+    }
+
+    /// Serves a UDP ASSOCIATE; `requested` is where the client will send from,
+    /// when it knows.
+    async fn associate(mut self, requested: Address) -> anyhow::Result<()> {
+        let Stream::Tcp(control) = &self.upstream else {
+            unreachable!("SOCKS clients connect over TCP")
+        };
+        let local_ip = control.local_addr()?.ip();
+        let client = match requested {
+            Address::Ip(addr) if addr.port() != 0 && !addr.ip().is_unspecified() => Some(addr),
+            _ => None,
+        };
+        let association = Association::bind(
+            local_ip,
+            self.upstream_address.ip(),
+            client,
+            self.udp.clone(),
+            self.server.clone(),
+            self.resolver.clone(),
+            self.rules.clone(),
+        )
+        .await?;
+        let bind_address = association.local_addr()?;
+        let mut reply = ConnectResponse {
+            status: ConnectedStatus::Succeeded,
+            bind_address: bind_address.into(),
+        }
+        .encode();
+        self.upstream.write_all_buf(&mut reply).await?;
+        info!(%bind_address, "UDP associate");
+
+        association.run(&mut self.upstream).await
+    }
+
+    /// The action of the first rule matching `target`, or the wave name behind
+    /// it when it is a fake IP.
+    fn rule_action(&self, target: &Address) -> Option<Action> {
+        let target = self.unfake(target.clone()).ok()?;
+        let (index, rule) = self.rules.decide(&target)?;
+        debug!(%target, rule = index, action = %rule.action, "Matched routing rule");
+        Some(rule.action.clone())
+    }
+
+    /// The wave name `addr` stands for when it is a fake IP, or `addr` itself.
+    fn unfake(&self, addr: Address) -> anyhow::Result<Address> {
+        match (&addr, &self.fake_ips) {
+            (Address::Ip(ip), Some(pool)) if pool.contains(ip.ip()) => match pool.name(ip.ip()) {
+                Some(name) => Ok(Address::Domain(name, ip.port())),
+                None => anyhow::bail!("fake IP {} is not in use", ip.ip()),
+            },
+            _ => Ok(addr),
+        }
+    }
+
+    /// The node that resolves `target`, when it is a name under a remote DNS
+    /// suffix rather than a wave route.
+    fn remote_resolver(&self, target: &Address) -> Option<NodeId> {
+        match target {
+            Address::Domain(domain, port) if Connection::connect(domain, *port).is_err() => {
+                self.remote_dns.lookup(domain)
+            }
+            _ => None,
+        }
+    }
+
+    /// Asks `node_id` to resolve and connect to `target`, and returns the
+    /// SOCKS reply for a failure.
+    async fn connect_resolving(
+        &mut self,
+        node_id: NodeId,
+        target: &Address,
+    ) -> Result<Stream, ConnectedStatus> {
+        match self.open_resolving(node_id, target).await {
+            Ok((send, recv, ConnectedStatus::Succeeded)) => {
+                info!(%node_id, %target, "Connected to remote endpoint resolved by node");
+                Ok(Stream::Iroh(send, recv))
+            }
+            Ok((_, _, status)) => {
+                info!(%node_id, %target, %status, "Remote resolve refused");
+                Err(status)
+            }
+            Err(e) => {
+                tracing::error!("Remote resolve error: {}", e);
+                Err(ConnectedStatus::GeneralServerFailure)
             }
         }
     }
 
-    async fn connect_to_downstream(&self, addr: Address) -> anyhow::Result<Stream> {
+    async fn open_resolving(
+        &mut self,
+        node_id: NodeId,
+        target: &Address,
+    ) -> anyhow::Result<(SendStream, RecvStream, ConnectedStatus)> {
+        let Address::Domain(domain, port) = target else {
+            unreachable!("only names are resolved remotely")
+        };
+        let (mut send, mut recv, lease) = self.pool.open_bi(node_id).await?;
+        self.path = Some(self.path_reporter.track(
+            self.pool.endpoint(),
+            lease.connection(),
+            node_id,
+            target.to_string(),
+        ));
+        self.lease = Some(lease);
+        self.require_direct_path(node_id, None).await?;
+
+        let packet = WavePacket::resolve(*port, Subdomain::new(domain.clone())?);
+        send.write_all_buf(&mut packet.encode()).await?;
+        let status = ConnectedStatus::try_from(recv.read_u8().await?)?;
+        Ok((send, recv, status))
+    }
+
+    /// Connects to `addr` as the rule `action` says, or as without rules. For
+    /// wave targets the bytes already in `early_data`, or the first ones the
+    /// application sends, go out in the same write as the `WavePacket` and are
+    /// taken out of the buffer.
+    async fn connect_to_downstream(
+        &mut self,
+        addr: Address,
+        action: Option<Action>,
+        early_data: &mut BytesMut,
+    ) -> anyhow::Result<Stream> {
+        let addr = self.unfake(addr)?;
+        let Some(action) = action else {
+            return self.connect_default(addr, early_data).await;
+        };
+        match action {
+            Action::Direct => {
+                let stream = match &addr {
+                    Address::Ip(ip) => TcpStream::connect(ip).await?,
+                    Address::Domain(domain, port) => {
+                        resolver::connect(&*self.resolver, domain, *port).await?
+                    }
+                };
+                info!(%addr, "Connected to remote endpoint via tcp");
+                Ok(Stream::Tcp(stream))
+            }
+            Action::Block => anyhow::bail!("{} is blocked by a rule", addr),
+            Action::Proxy(proxy) => {
+                let stream = proxy.connect(&addr).await?;
+                info!(%addr, %proxy, "Connected to remote endpoint via proxy");
+                Ok(Stream::Tcp(stream))
+            }
+            Action::Exit(exit) => self.connect_via(exit, &addr, early_data).await,
+            Action::Wave(route) => {
+                let port = match &addr {
+                    Address::Ip(ip) => ip.port(),
+                    Address::Domain(_, port) => *port,
+                };
+                self.connect_default(route.address(port), early_data).await
+            }
+        }
+    }
+
+    /// Connects to `addr` the way it is reached without rules.
+    async fn connect_default(
+        &mut self,
+        addr: Address,
+        early_data: &mut BytesMut,
+    ) -> anyhow::Result<Stream> {
+        if let Address::Ip(ip) = &addr {
+            if let Some(router) = self.subnet_routes.lookup(ip.ip()) {
+                return self.connect_via(router, &addr, early_data).await;
+            }
+        }
         let stream = match &addr {
+            Address::Ip(_) if self.exit_node.is_some() => {
+                return self.connect_via_exit(&addr, early_data).await;
+            }
             Address::Ip(ip) => {
                 let stream = TcpStream::connect(ip).await?;
 
@@ -151,7 +715,7 @@ impl Handler {
             Address::Domain(domain, port) => match Connection::connect(domain, *port) {
                 Ok((_, conn)) => {
                     let node_id = conn.node_id();
-                    if node_id.0 == self.endpoint.node_id() {
+                    if node_id.0 == self.pool.endpoint().node_id() {
                         info!(?node_id, "Connected to self");
 
                         let target = self.server.get_target(&conn.subdomain());
@@ -165,9 +729,24 @@ impl Handler {
                             Some(Host::Domain(domain)) => {
                                 info!(%domain, %port, "Self connected, route to target via tcp");
 
-                                let stream = TcpStream::connect((domain.as_ref(), *port)).await?;
+                                let stream =
+                                    resolver::connect(&*self.resolver, &domain, *port).await?;
                                 Ok(Stream::Tcp(stream))
                             }
+                            // Own multi-hop routes lead straight to the next node.
+                            Some(Host::Wave(target)) if target.node_id != node_id => {
+                                info!(%target, "Self connected, route to target via iroh");
+
+                                let next = Address::Domain(
+                                    Arc::from(format!("{}.{}", target.subdomain, target.node_id)),
+                                    target.port.unwrap_or(*port),
+                                );
+                                Box::pin(self.connect_default(next, early_data)).await
+                            }
+                            Some(Host::Wave(target)) => Err(anyhow::anyhow!(
+                                "route to {} loops back to this node",
+                                target
+                            )),
                             None => Err(anyhow::anyhow!(
                                 "no target for subdomain {}",
                                 conn.subdomain()
@@ -176,20 +755,44 @@ impl Handler {
                         return res;
                     }
 
-                    let conn = self.endpoint.connect(node_id.0, ALPN).await?;
-
-                    let mut stream = conn.open_bi().await?;
-
-                    let (mut data, _) = wave_core::Connection::connect(domain, *port)?;
-
-                    stream.0.write_all_buf(&mut data).await?;
+                    let upstream = &mut self.upstream;
+                    let (opened, _) =
+                        futures_lite::future::zip(self.pool.open_bi(node_id), async {
+                            if early_data.is_empty() {
+                                tokio::time::timeout(
+                                    EARLY_DATA_WINDOW,
+                                    upstream.read_buf(early_data),
+                                )
+                                .await
+                                .ok();
+                            }
+                        })
+                        .await;
+                    let (mut send, recv, lease) = opened?;
+                    self.path = Some(self.path_reporter.track(
+                        self.pool.endpoint(),
+                        lease.connection(),
+                        node_id,
+                        addr.to_string(),
+                    ));
+                    self.lease = Some(lease);
+                    self.require_direct_path(node_id, Some(conn.subdomain().as_str()))
+                        .await?;
+
+                    let mut data = WavePacket::new(*port, conn.subdomain())
+                        .encode_with_payload(&early_data.split());
+
+                    send.write_all_buf(&mut data).await?;
 
                     info!(%node_id, "Connected to remote endpoint via iroh");
 
-                    Stream::Iroh(stream.0, stream.1)
+                    Stream::Iroh(send, recv)
+                }
+                Err(_) if self.exit_node.is_some() => {
+                    return self.connect_via_exit(&addr, early_data).await;
                 }
                 Err(_e) => {
-                    let stream = TcpStream::connect((domain.as_ref(), *port)).await?;
+                    let stream = resolver::connect(&*self.resolver, domain, *port).await?;
 
                     info!(%domain, %port, "Connected to remote endpoint via tcp");
 
@@ -200,6 +803,66 @@ impl Handler {
         Ok(stream)
     }
 
+    /// Asks the exit node to connect to `addr`, an ordinary destination.
+    async fn connect_via_exit(
+        &mut self,
+        addr: &Address,
+        early_data: &mut BytesMut,
+    ) -> anyhow::Result<Stream> {
+        let exit = self.exit_node.expect("checked by the caller");
+        self.connect_via(exit, addr, early_data).await
+    }
+
+    /// Asks `exit`, an exit node or the router of `addr`'s subnet, to connect
+    /// to `addr`.
+    async fn connect_via(
+        &mut self,
+        exit: NodeId,
+        addr: &Address,
+        early_data: &mut BytesMut,
+    ) -> anyhow::Result<Stream> {
+        let (host, port) = match addr {
+            Address::Ip(ip) => (ip.ip().to_string(), ip.port()),
+            Address::Domain(domain, port) => (domain.to_string(), *port),
+        };
+        let (mut send, recv, lease) = self.pool.open_bi(exit).await?;
+        self.path = Some(self.path_reporter.track(
+            self.pool.endpoint(),
+            lease.connection(),
+            exit,
+            addr.to_string(),
+        ));
+        self.lease = Some(lease);
+        self.require_direct_path(exit, None).await?;
+
+        let mut data = WavePacket::exit(port, Subdomain::new(Arc::from(host))?)
+            .encode_with_payload(&early_data.split());
+        send.write_all_buf(&mut data).await?;
+
+        info!(%exit, %addr, "Connected to remote endpoint via node");
+
+        Ok(Stream::Iroh(send, recv))
+    }
+
+    /// Waits for a direct path to `node_id` when the path policy keeps streams
+    /// to it, or to its route `subdomain`, off relays, and watches that this
+    /// stream stays direct.
+    async fn require_direct_path(
+        &mut self,
+        node_id: NodeId,
+        subdomain: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let policy = match subdomain {
+            Some(subdomain) => self.paths.get(&node_id, subdomain),
+            None => self.paths.peer(&node_id),
+        };
+        if policy == PathPolicy::DirectOnly {
+            path::wait_direct(self.pool.endpoint(), node_id, self.direct_path_timeout).await?;
+            self.direct_only = Some(node_id);
+        }
+        Ok(())
+    }
+
     async fn get_stream(&mut self, address: &Address) -> anyhow::Result<&mut Stream> {
         if Address::Ip(self.upstream_address) == *address {
             return Ok(&mut self.upstream);
@@ -0,0 +1,138 @@
+use super::gateway::read_head;
+use bytes::BytesMut;
+use ipnet::IpNet;
+use std::{
+    fmt::Write,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+};
+use tracing::{debug, info};
+
+pub const PAC_PATH: &str = "/proxy.pac";
+
+/// What the PAC file sends through the proxy: wave names of any node, whether
+/// or not this client has met it, and the names and nets the config routes.
+#[derive(Clone)]
+pub(crate) struct PacRules {
+    pub(crate) proxy: SocketAddr,
+    pub(crate) suffixes: Vec<String>,
+    pub(crate) nets: Vec<IpNet>,
+    /// With an exit node every destination goes through the proxy.
+    pub(crate) exit: bool,
+}
+
+impl PacRules {
+    pub(crate) fn script(&self) -> String {
+        let proxy = match self.proxy.ip() {
+            IpAddr::V4(ip) if ip.is_unspecified() => {
+                SocketAddr::from((Ipv4Addr::LOCALHOST, self.proxy.port()))
+            }
+            IpAddr::V6(ip) if ip.is_unspecified() => {
+                SocketAddr::from((Ipv6Addr::LOCALHOST, self.proxy.port()))
+            }
+            _ => self.proxy,
+        };
+        let mut script = String::new();
+        writeln!(script, "function FindProxyForURL(url, host) {{").unwrap();
+        writeln!(script, "  var proxy = \"SOCKS5 {proxy}; SOCKS {proxy}\";").unwrap();
+        writeln!(script, "  host = host.toLowerCase();").unwrap();
+        // A node id, in BASE32_DNSSEC, as the last label.
+        writeln!(
+            script,
+            "  if (/(^|\\.)[0-9a-v]{{52}}$/.test(host)) return proxy;"
+        )
+        .unwrap();
+        for domain in &self.suffixes {
+            writeln!(
+                script,
+                "  if (host == \"{domain}\" || dnsDomainIs(host, \".{domain}\")) return proxy;"
+            )
+            .unwrap();
+        }
+        // Only address literals are matched, isInNet would resolve names.
+        for net in &self.nets {
+            match net {
+                IpNet::V4(net) => writeln!(
+                    script,
+                    "  if (/^[0-9.]+$/.test(host) && isInNet(host, \"{}\", \"{}\")) return proxy;",
+                    net.network(),
+                    net.netmask()
+                ),
+                IpNet::V6(net) => writeln!(
+                    script,
+                    "  if (typeof isInNetEx == \"function\" && host.indexOf(\":\") >= 0 && isInNetEx(host, \"{net}\")) return proxy;"
+                ),
+            }
+            .unwrap();
+        }
+        let fallback = if self.exit { "proxy" } else { "\"DIRECT\"" };
+        writeln!(script, "  return {fallback};").unwrap();
+        writeln!(script, "}}").unwrap();
+        script
+    }
+}
+
+/// Serves a proxy auto-config file at [`PAC_PATH`], so browsers send only
+/// wave names and routed subnets through the SOCKS proxy.
+pub struct PacServer {
+    listener: TcpListener,
+    rules: PacRules,
+}
+
+impl PacServer {
+    pub(crate) async fn bind(bind: SocketAddr, rules: PacRules) -> std::io::Result<Self> {
+        Ok(Self {
+            listener: TcpListener::bind(bind).await?,
+            rules,
+        })
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub async fn run(self) -> anyhow::Result<()> {
+        info!(url = %format!("http://{}{}", self.local_addr()?, PAC_PATH), "Serve PAC file");
+        loop {
+            let (stream, from) = self.listener.accept().await?;
+            let rules = self.rules.clone();
+            tokio::spawn(async move {
+                serve(stream, &rules)
+                    .await
+                    .inspect_err(|e| debug!(%from, "Serve PAC error: {}", e))
+                    .ok();
+            });
+        }
+    }
+}
+
+async fn serve(mut stream: TcpStream, rules: &PacRules) -> std::io::Result<()> {
+    let mut head = BytesMut::new();
+    if read_head(&mut stream, &mut head).await?.is_none() {
+        return Ok(());
+    }
+    let request_line = head[..].split(|b| *b == b'\n').next().unwrap_or_default();
+    let mut parts = std::str::from_utf8(request_line)
+        .unwrap_or_default()
+        .split(' ');
+    let (method, path) = (parts.next(), parts.next());
+    let path = path.map(|path| path.split('?').next().unwrap_or(path));
+
+    let (status, content_type, body) = match (method, path) {
+        (Some("GET"), Some(PAC_PATH)) => (
+            "200 OK",
+            "application/x-ns-proxy-autoconfig",
+            rules.script(),
+        ),
+        _ => ("404 Not Found", "text/plain", "not found\n".to_string()),
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
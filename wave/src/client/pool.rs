@@ -0,0 +1,313 @@
+use crate::{udp::DatagramRouter, ALPN};
+use iroh::{
+    endpoint::{Connection, RecvStream, SendStream},
+    Endpoint,
+};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::{Duration, Instant},
+};
+use tracing::{debug, info};
+use wave_core::NodeId;
+
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+type Slot = Arc<tokio::sync::Mutex<Option<Pooled>>>;
+
+/// Keeps one iroh connection per peer and opens a new bi-stream on it for
+/// every request.
+#[derive(Clone)]
+pub struct ConnectionPool {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    endpoint: Endpoint,
+    idle_timeout: Duration,
+    slots: Mutex<HashMap<NodeId, Slot>>,
+}
+
+#[derive(Clone)]
+struct Pooled {
+    conn: Connection,
+    datagrams: DatagramRouter,
+    usage: Arc<Usage>,
+}
+
+struct Usage {
+    active: AtomicUsize,
+    last_used: Mutex<Instant>,
+}
+
+/// Marks a pooled connection as in use; the connection is not considered idle
+/// until every lease handed out for it has been dropped.
+pub struct Lease {
+    conn: Connection,
+    datagrams: DatagramRouter,
+    usage: Arc<Usage>,
+}
+
+impl Lease {
+    pub fn connection(&self) -> &Connection {
+        &self.conn
+    }
+
+    /// Routes the datagrams of UDP flows on this connection.
+    pub fn datagrams(&self) -> &DatagramRouter {
+        &self.datagrams
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.usage.active.fetch_sub(1, Ordering::AcqRel);
+        *self.usage.last_used.lock().unwrap() = Instant::now();
+    }
+}
+
+impl Pooled {
+    fn new(conn: Connection) -> Self {
+        Self {
+            datagrams: DatagramRouter::new(conn.clone()),
+            conn,
+            usage: Arc::new(Usage {
+                active: AtomicUsize::new(0),
+                last_used: Mutex::new(Instant::now()),
+            }),
+        }
+    }
+
+    fn lease(&self) -> Lease {
+        self.usage.active.fetch_add(1, Ordering::AcqRel);
+        Lease {
+            conn: self.conn.clone(),
+            datagrams: self.datagrams.clone(),
+            usage: self.usage.clone(),
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.conn.close_reason().is_some()
+    }
+
+    fn is_idle(&self, idle_timeout: Duration) -> bool {
+        self.usage.active.load(Ordering::Acquire) == 0
+            && self.usage.last_used.lock().unwrap().elapsed() >= idle_timeout
+    }
+}
+
+impl ConnectionPool {
+    pub fn new(endpoint: Endpoint, idle_timeout: Duration) -> Self {
+        let inner = Arc::new(Inner {
+            endpoint,
+            idle_timeout,
+            slots: Mutex::new(HashMap::new()),
+        });
+        tokio::spawn(reap(Arc::downgrade(&inner), idle_timeout));
+        Self { inner }
+    }
+
+    pub fn endpoint(&self) -> &Endpoint {
+        &self.inner.endpoint
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.slots.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Opens a bi-stream to `node_id`, reusing the cached connection when it is
+    /// still alive and dialing a fresh one otherwise.
+    pub async fn open_bi(
+        &self,
+        node_id: NodeId,
+    ) -> anyhow::Result<(SendStream, RecvStream, Lease)> {
+        let lease = self.connection(node_id, false).await?;
+        match lease.conn.open_bi().await {
+            Ok((send, recv)) => Ok((send, recv, lease)),
+            Err(e) => {
+                debug!(%node_id, "Pooled connection unusable, reconnecting: {}", e);
+                drop(lease);
+                let lease = self.connection(node_id, true).await?;
+                let (send, recv) = lease.conn.open_bi().await?;
+                Ok((send, recv, lease))
+            }
+        }
+    }
+
+    /// A lease on the connection to `node_id`, taken while the slot is locked
+    /// so the connection cannot be reaped as idle before a stream is opened.
+    async fn connection(&self, node_id: NodeId, reconnect: bool) -> anyhow::Result<Lease> {
+        let slot = self
+            .inner
+            .slots
+            .lock()
+            .unwrap()
+            .entry(node_id)
+            .or_default()
+            .clone();
+        let mut slot = slot.lock().await;
+
+        if let Some(pooled) = slot.as_ref() {
+            if !reconnect && !pooled.is_closed() {
+                return Ok(pooled.lease());
+            }
+        }
+
+        let conn = self.inner.endpoint.connect(node_id.0, ALPN).await?;
+        info!(%node_id, "Open new iroh connection");
+        let pooled = Pooled::new(conn);
+        let lease = pooled.lease();
+        *slot = Some(pooled);
+        Ok(lease)
+    }
+
+    /// Closes and forgets connections that have been unused for longer than the
+    /// idle timeout, as well as connections that were closed by the peer.
+    pub fn evict_idle(&self) {
+        self.inner.evict_idle();
+    }
+}
+
+impl Inner {
+    fn evict_idle(&self) {
+        self.slots.lock().unwrap().retain(|node_id, slot| {
+            let Ok(mut slot) = slot.try_lock() else {
+                return true;
+            };
+            match slot.as_ref() {
+                Some(pooled) if pooled.is_closed() => {
+                    debug!(%node_id, "Drop closed iroh connection");
+                    false
+                }
+                Some(pooled) if pooled.is_idle(self.idle_timeout) => {
+                    info!(%node_id, "Close idle iroh connection");
+                    pooled.conn.close(0u32.into(), b"idle");
+                    slot.take();
+                    false
+                }
+                Some(_) => true,
+                None => false,
+            }
+        });
+    }
+}
+
+async fn reap(inner: Weak<Inner>, idle_timeout: Duration) {
+    let period = (idle_timeout / 2).max(Duration::from_millis(100));
+    loop {
+        tokio::time::sleep(period).await;
+        let Some(inner) = inner.upgrade() else {
+            break;
+        };
+        inner.evict_idle();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iroh::RelayMode;
+
+    async fn echo_server(close_after_stream: bool) -> (Endpoint, Arc<AtomicUsize>) {
+        let ep = Endpoint::builder()
+            .alpns(vec![ALPN.into()])
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await
+            .unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let (server, counter) = (ep.clone(), accepted.clone());
+        tokio::spawn(async move {
+            while let Some(incoming) = server.accept().await {
+                counter.fetch_add(1, Ordering::AcqRel);
+                let conn = incoming.await.unwrap();
+                tokio::spawn(async move {
+                    while let Ok((mut send, mut recv)) = conn.accept_bi().await {
+                        tokio::io::copy(&mut recv, &mut send).await.unwrap();
+                        send.finish().unwrap();
+                        if close_after_stream {
+                            send.stopped().await.ok();
+                            conn.close(0u32.into(), b"bye");
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        (ep, accepted)
+    }
+
+    async fn pool(server: &Endpoint, idle_timeout: Duration) -> ConnectionPool {
+        let ep = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await
+            .unwrap();
+        ep.add_node_addr(server.node_addr().await.unwrap()).unwrap();
+        ConnectionPool::new(ep, idle_timeout)
+    }
+
+    async fn ping(pool: &ConnectionPool, node_id: NodeId) {
+        let (mut send, mut recv, _lease) = pool.open_bi(node_id).await.unwrap();
+        send.write_all(b"ping").await.unwrap();
+        send.finish().unwrap();
+        assert_eq!(recv.read_to_end(64).await.unwrap(), b"ping");
+    }
+
+    #[tokio::test]
+    async fn test_reuse_and_evict_idle() {
+        let (server, accepted) = echo_server(false).await;
+        let node_id = NodeId(server.node_id());
+        let pool = pool(&server, Duration::from_millis(200)).await;
+
+        for _ in 0..3 {
+            ping(&pool, node_id).await;
+        }
+        assert_eq!(accepted.load(Ordering::Acquire), 1);
+        assert_eq!(pool.len(), 1);
+
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        assert!(pool.is_empty());
+
+        ping(&pool, node_id).await;
+        assert_eq!(accepted.load(Ordering::Acquire), 2);
+    }
+
+    #[tokio::test]
+    async fn test_lease_before_open() {
+        let (server, accepted) = echo_server(false).await;
+        let node_id = NodeId(server.node_id());
+        let pool = pool(&server, Duration::from_millis(100)).await;
+
+        // Held across the wait, as while a stream is being opened.
+        let lease = pool.connection(node_id, false).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        pool.evict_idle();
+        assert_eq!(pool.len(), 1);
+        assert!(lease.conn.close_reason().is_none());
+        drop(lease);
+
+        ping(&pool, node_id).await;
+        assert_eq!(accepted.load(Ordering::Acquire), 1);
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_after_close() {
+        let (server, accepted) = echo_server(true).await;
+        let node_id = NodeId(server.node_id());
+        let pool = pool(&server, DEFAULT_IDLE_TIMEOUT).await;
+
+        ping(&pool, node_id).await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        ping(&pool, node_id).await;
+        assert_eq!(accepted.load(Ordering::Acquire), 2);
+    }
+}
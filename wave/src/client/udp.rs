@@ -0,0 +1,390 @@
+use super::pool::{ConnectionPool, Lease};
+use crate::{
+    path::{self, ClientPathPolicy, PathPolicy, PathReporter, PathTracker},
+    resolver::Resolve,
+    rules::{Action, Rules},
+    udp::{self, Flow, FLOW_QUEUE, MAX_PAYLOAD},
+    Stream,
+};
+use bytes::{Bytes, BytesMut};
+use std::{
+    collections::HashMap,
+    future::Future,
+    hash::Hash,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex, Weak},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UdpSocket,
+    sync::mpsc::{self, error::TrySendError},
+};
+use tracing::{debug, info};
+use wave_core::{server::Host, Connection, NodeId, Server, WavePacket};
+use wave_proxy::{protocol::socks5::types::UdpHeader, Address};
+
+/// What a UDP flow to a wave peer needs from the `Client`.
+#[derive(Clone)]
+pub(crate) struct FlowOpener {
+    pub(crate) pool: ConnectionPool,
+    pub(crate) paths: Arc<ClientPathPolicy>,
+    pub(crate) direct_path_timeout: Duration,
+    pub(crate) path_reporter: PathReporter,
+    pub(crate) idle_timeout: Duration,
+}
+
+/// A flow to a UDP route, with what keeps its connection leased and its path
+/// reported.
+struct WaveFlow {
+    flow: Flow,
+    direct_only: Option<NodeId>,
+    _lease: Lease,
+    _path: PathTracker,
+}
+
+impl FlowOpener {
+    async fn open(&self, conn: &Connection, target: &Address) -> anyhow::Result<WaveFlow> {
+        let node_id = conn.node_id();
+        let (mut send, recv, lease) = self.pool.open_bi(node_id).await?;
+        let path = self.path_reporter.track(
+            self.pool.endpoint(),
+            lease.connection(),
+            node_id,
+            target.to_string(),
+        );
+        let mut direct_only = None;
+        if self.paths.get(&node_id, conn.subdomain().as_str()) == PathPolicy::DirectOnly {
+            path::wait_direct(self.pool.endpoint(), node_id, self.direct_path_timeout).await?;
+            direct_only = Some(node_id);
+        }
+
+        send.write_all_buf(&mut WavePacket::udp(conn.port(), conn.subdomain()).encode())
+            .await?;
+        info!(%node_id, %target, "Opened UDP flow via iroh");
+
+        Ok(WaveFlow {
+            flow: lease.datagrams().open(send, recv),
+            direct_only,
+            _lease: lease,
+            _path: path,
+        })
+    }
+
+    /// Relays the payloads from `incoming` to the UDP route `conn` names, and
+    /// its replies to `reply`, until the flow ends.
+    async fn run(
+        self,
+        conn: Connection,
+        target: Address,
+        mut incoming: mpsc::Receiver<Bytes>,
+        reply: impl FnMut(Bytes),
+    ) -> anyhow::Result<()> {
+        let mut wave = self.open(&conn, &target).await?;
+        let relaying = udp::relay_channel(&mut wave.flow, &mut incoming, reply, self.idle_timeout);
+        match wave.direct_only {
+            Some(node_id) => tokio::select! {
+                res = relaying => res?,
+                e = path::direct_lost(self.pool.endpoint(), node_id) => return Err(e),
+            },
+            None => relaying.await?,
+        }
+        debug!(%target, "UDP flow finished");
+        Ok(())
+    }
+}
+
+/// The running flows of one local socket, by what tells their traffic apart.
+struct FlowTable<K> {
+    flows: Arc<Mutex<HashMap<K, mpsc::Sender<Bytes>>>>,
+}
+
+impl<K> Default for FlowTable<K> {
+    fn default() -> Self {
+        Self {
+            flows: Arc::default(),
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone + Send + 'static> FlowTable<K> {
+    /// Hands `payload` to the flow for `key`, starting one with `start` when it
+    /// has none. The payload is dropped while the flow is behind.
+    fn send<F>(&self, key: K, payload: Bytes, start: impl FnOnce(mpsc::Receiver<Bytes>) -> F)
+    where
+        F: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        let mut flows = self.flows.lock().unwrap();
+        let payload = match flows.get(&key) {
+            Some(flow) => match flow.try_send(payload) {
+                Ok(()) | Err(TrySendError::Full(_)) => return,
+                Err(TrySendError::Closed(payload)) => payload,
+            },
+            None => payload,
+        };
+        let (tx, rx) = mpsc::channel(FLOW_QUEUE);
+        tx.try_send(payload).ok();
+        let flow = tx.downgrade();
+        flows.insert(key.clone(), tx);
+
+        let table = Arc::downgrade(&self.flows);
+        let running = start(rx);
+        tokio::spawn(async move {
+            running
+                .await
+                .inspect_err(|e| tracing::error!("UDP flow error: {}", e))
+                .ok();
+            remove_flow(table, &key, flow);
+        });
+    }
+}
+
+fn remove_flow<K: Hash + Eq>(
+    table: Weak<Mutex<HashMap<K, mpsc::Sender<Bytes>>>>,
+    key: &K,
+    flow: mpsc::WeakSender<Bytes>,
+) {
+    let Some(table) = table.upgrade() else {
+        return;
+    };
+    let mut flows = table.lock().unwrap();
+    let current = flows.get(key).zip(flow.upgrade());
+    if current.is_some_and(|(current, flow)| current.same_channel(&flow)) {
+        flows.remove(key);
+    }
+}
+
+/// The wave route `target` names, unless it is an ordinary destination.
+fn wave_route(target: &Address) -> Option<Connection> {
+    match target {
+        Address::Domain(domain, port) => Connection::connect(domain, *port)
+            .ok()
+            .map(|(_, conn)| conn),
+        Address::Ip(_) => None,
+    }
+}
+
+/// The socket and state behind one SOCKS5 UDP ASSOCIATE.
+pub(crate) struct Association {
+    socket: Arc<UdpSocket>,
+    client_ip: IpAddr,
+    client: Option<SocketAddr>,
+    opener: FlowOpener,
+    server: Arc<Server>,
+    resolver: Arc<dyn Resolve>,
+    /// Only `block` rules apply to datagrams.
+    rules: Arc<Rules>,
+    flows: FlowTable<Address>,
+    /// Resolved ordinary destinations, both ways, so replies carry the address
+    /// the client sent to.
+    resolved: HashMap<Address, SocketAddr>,
+    direct: HashMap<SocketAddr, Address>,
+}
+
+impl Association {
+    /// Binds the relay socket on `local_ip`. Datagrams are only taken from
+    /// `client`, or when it is unknown from the first sender at `client_ip`.
+    pub(crate) async fn bind(
+        local_ip: IpAddr,
+        client_ip: IpAddr,
+        client: Option<SocketAddr>,
+        opener: FlowOpener,
+        server: Arc<Server>,
+        resolver: Arc<dyn Resolve>,
+        rules: Arc<Rules>,
+    ) -> std::io::Result<Self> {
+        Ok(Self {
+            socket: Arc::new(UdpSocket::bind((local_ip, 0)).await?),
+            client_ip,
+            client,
+            opener,
+            server,
+            resolver,
+            rules,
+            flows: FlowTable::default(),
+            resolved: HashMap::new(),
+            direct: HashMap::new(),
+        })
+    }
+
+    pub(crate) fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Relays datagrams until the client closes `control`, the TCP connection
+    /// the association was requested on.
+    pub(crate) async fn run(mut self, control: &mut Stream) -> anyhow::Result<()> {
+        let mut buf = vec![0u8; MAX_PAYLOAD];
+        let mut control_buf = [0u8; 64];
+        loop {
+            tokio::select! {
+                n = control.read(&mut control_buf) => {
+                    if n.unwrap_or(0) == 0 {
+                        debug!(client = ?self.client, "UDP associate closed");
+                        return Ok(());
+                    }
+                }
+                res = self.socket.recv_from(&mut buf) => {
+                    let (n, from) = res?;
+                    self.receive(from, &buf[..n]).await;
+                }
+            }
+        }
+    }
+
+    async fn receive(&mut self, from: SocketAddr, datagram: &[u8]) {
+        let from_client = match self.client {
+            Some(client) => from == client,
+            None => from.ip() == self.client_ip,
+        };
+        if from_client {
+            self.client = Some(from);
+            let mut buf = BytesMut::from(datagram);
+            match UdpHeader::decode(&mut buf) {
+                // Fragments are not supported and dropped, as RFC 1928 allows.
+                Ok(Some(header)) if header.frag == 0 => {
+                    self.forward(header.address, buf.freeze()).await
+                }
+                Ok(_) => debug!("Drop fragmented or truncated UDP request"),
+                Err(e) => debug!("Drop invalid UDP request: {}", e),
+            }
+        } else if let Some((client, target)) = self.client.zip(self.direct.get(&from)) {
+            let header = UdpHeader {
+                frag: 0,
+                address: target.clone(),
+            };
+            let reply = [&header.encode()[..], datagram].concat();
+            self.socket.send_to(&reply, client).await.ok();
+        }
+    }
+
+    async fn forward(&mut self, target: Address, payload: Bytes) {
+        let Some(client) = self.client else {
+            return;
+        };
+        if let Some((index, rule)) = self.rules.decide(&target) {
+            if rule.action == Action::Block {
+                debug!(%target, rule = index, "Drop UDP request blocked by rule");
+                return;
+            }
+        }
+        let own_node = self.opener.pool.endpoint().node_id();
+        let dest = match wave_route(&target) {
+            Some(conn) if conn.node_id().0 != own_node => {
+                let (socket, opener) = (self.socket.clone(), self.opener.clone());
+                let header = UdpHeader {
+                    frag: 0,
+                    address: target.clone(),
+                }
+                .encode();
+                let reply = move |payload: Bytes| {
+                    socket
+                        .try_send_to(&[&header[..], &payload].concat(), client)
+                        .ok();
+                };
+                self.flows.send(target.clone(), payload, |incoming| {
+                    opener.run(conn, target, incoming, reply)
+                });
+                return;
+            }
+            // Routes of this node are reached like any other destination.
+            Some(conn) => match self.server.get_target(&conn.subdomain()) {
+                Some(Host::Ip(ip)) => Address::Ip(SocketAddr::new(ip, conn.port())),
+                Some(Host::Domain(domain)) => Address::Domain(domain, conn.port()),
+                Some(Host::Wave(target)) => {
+                    debug!(%target, "Drop UDP request for multi-hop route");
+                    return;
+                }
+                None => {
+                    debug!(subdomain = %conn.subdomain(), "Drop UDP request without route");
+                    return;
+                }
+            },
+            None => target.clone(),
+        };
+
+        let addr = match self.resolve(&dest).await {
+            Ok(addr) => addr,
+            Err(e) => {
+                debug!(%dest, "Drop UDP request: {}", e);
+                return;
+            }
+        };
+        self.direct.insert(addr, target);
+        self.socket.send_to(&payload, addr).await.ok();
+    }
+
+    async fn resolve(&mut self, dest: &Address) -> anyhow::Result<SocketAddr> {
+        if let Some(addr) = self.resolved.get(dest) {
+            return Ok(*addr);
+        }
+        let addr = match dest {
+            Address::Ip(addr) => *addr,
+            Address::Domain(domain, port) => {
+                // Prefer the family of the relay socket, which sends from it.
+                let ips = self.resolver.resolve(domain).await?;
+                let local_v6 = self.socket.local_addr()?.is_ipv6();
+                let ip = ips
+                    .iter()
+                    .find(|ip| ip.is_ipv6() == local_v6)
+                    .or(ips.first())
+                    .ok_or_else(|| anyhow::anyhow!("{} did not resolve", domain))?;
+                SocketAddr::new(*ip, *port)
+            }
+        };
+        self.resolved.insert(dest.clone(), addr);
+        Ok(addr)
+    }
+}
+
+/// Forwards a local UDP port to a UDP route of a wave peer, with a flow per
+/// local sender.
+pub struct UdpForward {
+    socket: Arc<UdpSocket>,
+    target: Address,
+    opener: FlowOpener,
+}
+
+impl UdpForward {
+    pub(crate) async fn bind(
+        bind: SocketAddr,
+        target: Address,
+        opener: FlowOpener,
+    ) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            wave_route(&target).is_some(),
+            "{} is not a wave route, expected subdomain.node_id:port",
+            target
+        );
+        Ok(Self {
+            socket: Arc::new(UdpSocket::bind(bind).await?),
+            target,
+            opener,
+        })
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub async fn run(self) -> anyhow::Result<()> {
+        info!(bind = %self.local_addr()?, target = %self.target, "Forward UDP");
+        let flows = FlowTable::default();
+        let mut buf = vec![0u8; MAX_PAYLOAD];
+        loop {
+            let (n, from) = self.socket.recv_from(&mut buf).await?;
+            let (socket, opener, target) = (
+                self.socket.clone(),
+                self.opener.clone(),
+                self.target.clone(),
+            );
+            let reply = move |payload: Bytes| {
+                socket.try_send_to(&payload, from).ok();
+            };
+            flows.send(from, Bytes::copy_from_slice(&buf[..n]), |incoming| {
+                let conn = wave_route(&target).expect("checked on bind");
+                opener.run(conn, target, incoming, reply)
+            });
+        }
+    }
+}
@@ -0,0 +1,265 @@
+use hickory_proto::{
+    op::{Message, MessageType},
+    rr::{
+        rdata::{A, AAAA},
+        RData, Record, RecordType,
+    },
+};
+use ipnet::{Ipv4Net, Ipv6Net};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::net::UdpSocket;
+use tracing::{debug, info};
+use wave_core::Connection;
+
+pub const DEFAULT_FAKE_IPV4: &str = "198.18.0.0/15";
+
+pub const DEFAULT_FAKE_IPV6: &str = "fdfe:dcba:9876::/48";
+
+/// TTL of fake answers. Mappings outlive it until the pool wraps around.
+const FAKE_TTL: u32 = 60;
+
+pub(crate) const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(5);
+
+const MAX_MESSAGE: usize = u16::MAX as usize;
+
+/// A local DNS server for applications that resolve names themselves: wave
+/// names get fake addresses the SOCKS client maps back, everything else is
+/// forwarded to `upstream`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DnsConfig {
+    pub bind: SocketAddr,
+    pub upstream: SocketAddr,
+    #[serde(default = "default_fake_ipv4")]
+    pub fake_ipv4: Ipv4Net,
+    #[serde(default = "default_fake_ipv6")]
+    pub fake_ipv6: Ipv6Net,
+}
+
+fn default_fake_ipv4() -> Ipv4Net {
+    DEFAULT_FAKE_IPV4.parse().unwrap()
+}
+
+fn default_fake_ipv6() -> Ipv6Net {
+    DEFAULT_FAKE_IPV6.parse().unwrap()
+}
+
+/// Hands out addresses from private ranges for wave names and remembers which
+/// name each stands for. The n-th name gets the n-th address of both ranges;
+/// once they run out the oldest names lose theirs.
+pub struct FakeIpPool {
+    v4: Ipv4Net,
+    v6: Ipv6Net,
+    capacity: u64,
+    state: Mutex<PoolState>,
+}
+
+#[derive(Default)]
+struct PoolState {
+    by_name: HashMap<Arc<str>, u64>,
+    by_index: HashMap<u64, Arc<str>>,
+    next: u64,
+}
+
+impl FakeIpPool {
+    pub fn new(v4: Ipv4Net, v6: Ipv6Net) -> anyhow::Result<Self> {
+        // The network and broadcast addresses are left out.
+        let v4_hosts = (1u64 << (32 - v4.prefix_len())).saturating_sub(2);
+        let v6_hosts = 1u64
+            .checked_shl(128 - u32::from(v6.prefix_len()))
+            .map_or(u64::MAX, |size| size - 1);
+        let capacity = v4_hosts.min(v6_hosts);
+        anyhow::ensure!(
+            capacity > 0,
+            "fake IP ranges {} and {} are too small",
+            v4,
+            v6
+        );
+        Ok(Self {
+            v4,
+            v6,
+            capacity,
+            state: Mutex::default(),
+        })
+    }
+
+    /// The fake addresses of `name`, assigned on first use.
+    pub fn assign(&self, name: &str) -> (Ipv4Addr, Ipv6Addr) {
+        let name = normalize(name);
+        let mut state = self.state.lock().unwrap();
+        let index = match state.by_name.get(name.as_str()) {
+            Some(index) => *index,
+            None => {
+                let index = state.next;
+                state.next = (index + 1) % self.capacity;
+                if let Some(evicted) = state.by_index.remove(&index) {
+                    state.by_name.remove(&evicted);
+                }
+                let name: Arc<str> = Arc::from(name);
+                state.by_name.insert(name.clone(), index);
+                state.by_index.insert(index, name);
+                index
+            }
+        };
+        self.addrs(index)
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match ip.to_canonical() {
+            IpAddr::V4(ip) => self.v4.contains(&ip),
+            IpAddr::V6(ip) => self.v6.contains(&ip),
+        }
+    }
+
+    /// The name `ip` was handed out for, unless it is not in use.
+    pub fn name(&self, ip: IpAddr) -> Option<Arc<str>> {
+        let offset = match ip.to_canonical() {
+            IpAddr::V4(ip) if self.v4.contains(&ip) => {
+                u64::from(u32::from(ip) - u32::from(self.v4.network()))
+            }
+            IpAddr::V6(ip) if self.v6.contains(&ip) => {
+                u64::try_from(u128::from(ip) - u128::from(self.v6.network())).ok()?
+            }
+            _ => return None,
+        };
+        let index = offset.checked_sub(1)?;
+        self.state.lock().unwrap().by_index.get(&index).cloned()
+    }
+
+    fn addrs(&self, index: u64) -> (Ipv4Addr, Ipv6Addr) {
+        let v4 = u32::from(self.v4.network()) + index as u32 + 1;
+        let v6 = u128::from(self.v6.network()) + u128::from(index) + 1;
+        (Ipv4Addr::from(v4), Ipv6Addr::from(v6))
+    }
+}
+
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Answers queries for wave names from a [`FakeIpPool`] and forwards all
+/// others to an upstream server.
+pub struct DnsServer {
+    socket: Arc<UdpSocket>,
+    upstream: SocketAddr,
+    pool: Arc<FakeIpPool>,
+}
+
+impl DnsServer {
+    pub async fn bind(
+        bind: SocketAddr,
+        upstream: SocketAddr,
+        pool: Arc<FakeIpPool>,
+    ) -> std::io::Result<Self> {
+        Ok(Self {
+            socket: Arc::new(UdpSocket::bind(bind).await?),
+            upstream,
+            pool,
+        })
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub async fn run(self) -> anyhow::Result<()> {
+        info!(bind = %self.local_addr()?, upstream = %self.upstream, "Serve DNS");
+        let mut buf = vec![0u8; MAX_MESSAGE];
+        loop {
+            let (n, from) = self.socket.recv_from(&mut buf).await?;
+            let query = buf[..n].to_vec();
+            let (socket, upstream, pool) = (self.socket.clone(), self.upstream, self.pool.clone());
+            tokio::spawn(async move {
+                let reply = match answer(&pool, &query) {
+                    Some(reply) => Ok(reply),
+                    None => forward(upstream, &query).await,
+                };
+                match reply {
+                    Ok(reply) => {
+                        socket.send_to(&reply, from).await.ok();
+                    }
+                    Err(e) => debug!(%upstream, "Forward DNS query error: {}", e),
+                }
+            });
+        }
+    }
+}
+
+/// The reply to a query for a wave name. Queries for other types than A and
+/// AAAA get an empty answer, so resolvers do not give up on the name.
+fn answer(pool: &FakeIpPool, query: &[u8]) -> Option<Vec<u8>> {
+    let request = Message::from_vec(query).ok()?;
+    let [question] = request.queries() else {
+        return None;
+    };
+    let name = normalize(&question.name().to_utf8());
+    Connection::connect(&name, 0).ok()?;
+
+    let mut reply = Message::new();
+    reply
+        .set_id(request.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(request.op_code())
+        .set_authoritative(true)
+        .set_recursion_desired(request.recursion_desired())
+        .set_recursion_available(true)
+        .add_query(question.clone());
+    let (v4, v6) = pool.assign(&name);
+    let rdata = match question.query_type() {
+        RecordType::A => Some(RData::A(A(v4))),
+        RecordType::AAAA => Some(RData::AAAA(AAAA(v6))),
+        _ => None,
+    };
+    if let Some(rdata) = rdata {
+        reply.add_answer(Record::from_rdata(question.name().clone(), FAKE_TTL, rdata));
+    }
+    debug!(%name, %v4, %v6, "Answer wave name");
+    reply.to_vec().ok()
+}
+
+pub(crate) async fn forward(upstream: SocketAddr, query: &[u8]) -> std::io::Result<Vec<u8>> {
+    let bind = match upstream {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.connect(upstream).await?;
+    socket.send(query).await?;
+    let mut buf = vec![0u8; MAX_MESSAGE];
+    let n = tokio::time::timeout(UPSTREAM_TIMEOUT, socket.recv(&mut buf)).await??;
+    buf.truncate(n);
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fake_ip_pool() {
+        let pool = FakeIpPool::new("10.0.0.0/30".parse().unwrap(), default_fake_ipv6()).unwrap();
+        let (a4, a6) = pool.assign("a.node");
+        assert_eq!(a4, Ipv4Addr::new(10, 0, 0, 1));
+        assert_eq!(a6, "fdfe:dcba:9876::1".parse::<Ipv6Addr>().unwrap());
+        assert_eq!(pool.assign("A.Node."), (a4, a6));
+        assert_eq!(pool.name(a4.into()).as_deref(), Some("a.node"));
+        assert_eq!(pool.name(a6.into()).as_deref(), Some("a.node"));
+        assert!(pool.contains("10.0.0.3".parse().unwrap()));
+        assert_eq!(pool.name("10.0.0.0".parse().unwrap()), None);
+
+        // Two addresses fit; the third name takes the first one over.
+        let (b4, _) = pool.assign("b.node");
+        assert_eq!(b4, Ipv4Addr::new(10, 0, 0, 2));
+        assert_eq!(pool.assign("c.node").0, a4);
+        assert_eq!(pool.name(a4.into()).as_deref(), Some("c.node"));
+        assert_eq!(pool.name(b4.into()).as_deref(), Some("b.node"));
+        assert_ne!(pool.assign("a.node").0, a4);
+
+        assert!(FakeIpPool::new("10.0.0.0/31".parse().unwrap(), default_fake_ipv6()).is_err());
+    }
+}
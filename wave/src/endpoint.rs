@@ -0,0 +1,194 @@
+use crate::{
+    config::Config,
+    peer_cache::{PeerCache, DEFAULT_SAVE_INTERVAL},
+    public_gateway::PublicGatewayConfig,
+    ALPN,
+};
+use iroh::{Endpoint, NodeAddr, RelayMap, RelayMode, RelayNode, SecretKey};
+use std::{
+    collections::BTreeMap,
+    net::{SocketAddrV4, SocketAddrV6},
+    path::{Path, PathBuf},
+};
+use tracing::{info, warn};
+
+pub const DEFAULT_ENDPOINT_BIND: SocketAddrV4 =
+    SocketAddrV4::new(std::net::Ipv4Addr::LOCALHOST, 8282);
+
+pub const DEFAULT_GATEWAY_ENDPOINT_BIND: SocketAddrV4 =
+    SocketAddrV4::new(std::net::Ipv4Addr::LOCALHOST, 8283);
+
+/// Directory of the state dir the public gateway keeps its own state in.
+const GATEWAY_STATE_DIR: &str = "gateway";
+
+const SECRET_KEY_FILE: &str = "secret_key";
+
+/// Binds the iroh endpoint described by `config`.
+pub async fn bind(config: &Config) -> anyhow::Result<Endpoint> {
+    let bind_v4 = config.endpoint_bind.unwrap_or(DEFAULT_ENDPOINT_BIND);
+    bind_with(
+        config,
+        bind_v4,
+        config.endpoint_bind_v6,
+        config.state_dir.clone(),
+    )
+    .await
+}
+
+/// Binds the endpoint of `wave gateway`. It may run beside `wave bind` on the
+/// same machine, so it listens on its own address and keeps its key and peer
+/// cache in a directory of its own under the state dir.
+pub async fn bind_gateway(
+    config: &Config,
+    gateway: &PublicGatewayConfig,
+) -> anyhow::Result<Endpoint> {
+    let bind_v4 = gateway
+        .endpoint_bind
+        .unwrap_or(DEFAULT_GATEWAY_ENDPOINT_BIND);
+    let state_dir = config
+        .state_dir
+        .as_ref()
+        .map(|dir| dir.join(GATEWAY_STATE_DIR));
+    bind_with(config, bind_v4, None, state_dir).await
+}
+
+async fn bind_with(
+    config: &Config,
+    bind_v4: SocketAddrV4,
+    bind_v6: Option<SocketAddrV6>,
+    state_dir: Option<PathBuf>,
+) -> anyhow::Result<Endpoint> {
+    let mut builder = Endpoint::builder()
+        .alpns(vec![ALPN.into()])
+        .discovery_local_network()
+        .bind_addr_v4(bind_v4);
+    if let Some(addr) = bind_v6 {
+        builder = builder.bind_addr_v6(addr);
+    }
+
+    if config.offline {
+        info!("Offline mode, relays and n0 discovery are disabled");
+        builder = builder.relay_mode(RelayMode::Disabled);
+    } else {
+        builder = builder.discovery_n0();
+        if !config.relays.is_empty() {
+            builder = builder.relay_mode(RelayMode::Custom(relay_map(config)?));
+        }
+    }
+
+    let mut peers = static_peers(config);
+    let cache = state_dir.as_deref().map(PeerCache::new);
+    if let Some(cache) = &cache {
+        match cache.load() {
+            Ok(cached) => peers.extend(cached),
+            Err(e) => warn!("Load peer cache failed: {}", e),
+        }
+    }
+    if !peers.is_empty() {
+        builder = builder.known_nodes(peers);
+    }
+
+    if let Some(state_dir) = &state_dir {
+        builder = builder.secret_key(load_or_create_secret_key(state_dir)?);
+    }
+
+    let endpoint = builder.bind().await?;
+    if let Some(cache) = cache {
+        tokio::spawn(cache.run(endpoint.clone(), DEFAULT_SAVE_INTERVAL));
+    }
+    Ok(endpoint)
+}
+
+fn relay_map(config: &Config) -> anyhow::Result<RelayMap> {
+    RelayMap::from_nodes(config.relays.iter().map(|relay| RelayNode {
+        url: relay.url.clone(),
+        stun_only: false,
+        stun_port: relay.stun_port,
+        // `wave relay` serves no QUIC address discovery.
+        quic: None,
+    }))
+}
+
+/// Groups the configured `node_id@ip:port` entries into one `NodeAddr` per node.
+fn static_peers(config: &Config) -> Vec<NodeAddr> {
+    let mut peers = BTreeMap::<_, Vec<_>>::new();
+    for peer in &config.peers {
+        peers.entry(peer.node_id.0).or_default().push(peer.addr);
+    }
+    peers
+        .into_iter()
+        .map(|(node_id, addrs)| NodeAddr::new(node_id).with_direct_addresses(addrs))
+        .collect()
+}
+
+fn load_or_create_secret_key(state_dir: &Path) -> anyhow::Result<SecretKey> {
+    let path = state_dir.join(SECRET_KEY_FILE);
+    match std::fs::read(&path) {
+        Ok(bytes) => {
+            let bytes: [u8; 32] = bytes
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("invalid secret key in {}", path.display()))?;
+            Ok(SecretKey::from_bytes(&bytes))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let mut bytes = [0u8; 32];
+            rand::fill(&mut bytes);
+            std::fs::create_dir_all(state_dir)?;
+            write_private(&path, &bytes)?;
+            info!(path = %path.display(), "Generated new secret key");
+            Ok(SecretKey::from_bytes(&bytes))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(unix)]
+pub(crate) fn write_private(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    use std::{io::Write, os::unix::fs::OpenOptionsExt};
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(data)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn write_private(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    std::fs::write(path, data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_secret_key_is_persisted() {
+        let state_dir = std::env::temp_dir().join(format!("wave-test-{}", rand::random::<u64>()));
+        let config = Config {
+            offline: true,
+            endpoint_bind: Some("127.0.0.1:0".parse().unwrap()),
+            state_dir: Some(state_dir.clone()),
+            ..Default::default()
+        };
+
+        let first = bind(&config).await.unwrap();
+        let second = bind(&config).await.unwrap();
+        assert_eq!(first.node_id(), second.node_id());
+
+        let gateway = PublicGatewayConfig {
+            endpoint_bind: Some("127.0.0.1:0".parse().unwrap()),
+            ..toml::from_str("bind = '127.0.0.1:0'\ndomain = 'gw.test'").unwrap()
+        };
+        let gateway_ep = bind_gateway(&config, &gateway).await.unwrap();
+        assert_ne!(gateway_ep.node_id(), first.node_id());
+        let again = bind_gateway(&config, &gateway).await.unwrap();
+        assert_eq!(again.node_id(), gateway_ep.node_id());
+        assert!(state_dir
+            .join(GATEWAY_STATE_DIR)
+            .join(SECRET_KEY_FILE)
+            .exists());
+
+        std::fs::remove_dir_all(state_dir).unwrap();
+    }
+}
@@ -0,0 +1,286 @@
+use crate::resolver::Resolve;
+use derive_more::{Display, Error, From};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    net::{IpAddr, SocketAddr},
+    num::ParseIntError,
+    str::FromStr,
+};
+use wave_core::NodeId;
+use wave_proxy::protocol::socks5::types::ConnectedStatus;
+
+/// Ranges an exit node does not dial unless an `allow_ips` entry inside them
+/// names the address: loopback, private, link-local, shared and other
+/// non-global addresses, where the node's own services and cloud metadata live.
+const INTERNAL_RANGES: &[&str] = &[
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.0.0.0/24",
+    "192.168.0.0/16",
+    "198.18.0.0/15",
+    "224.0.0.0/4",
+    "240.0.0.0/4",
+    "::/128",
+    "::1/128",
+    "64:ff9b::/96",
+    "fc00::/7",
+    "fe80::/10",
+    "ff00::/8",
+];
+
+/// Lets the listed peers dial destinations on this node's network, within the
+/// address and port rules.
+///
+/// A destination is refused when it matches `deny_ips` or `deny_ports`, when
+/// `allow_ips` or `allow_ports` are set and do not match it, or when SSRF
+/// protection is on and it is internal without a matching `allow_ips` entry
+/// that lies inside an internal range, so `0.0.0.0/0` does not open loopback.
+/// Domains are resolved by the node and only a permitted address is dialed.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ExitPolicy {
+    /// Peers allowed to use this node as an exit; nobody when empty.
+    #[serde(default)]
+    pub peers: HashSet<NodeId>,
+    #[serde(default)]
+    pub allow_ips: Vec<IpNet>,
+    #[serde(default)]
+    pub deny_ips: Vec<IpNet>,
+    #[serde(default)]
+    pub allow_ports: Vec<PortRange>,
+    #[serde(default)]
+    pub deny_ports: Vec<PortRange>,
+    #[serde(default = "default_ssrf_protection")]
+    pub ssrf_protection: bool,
+}
+
+fn default_ssrf_protection() -> bool {
+    true
+}
+
+impl Default for ExitPolicy {
+    fn default() -> Self {
+        Self {
+            peers: HashSet::new(),
+            allow_ips: Vec::new(),
+            deny_ips: Vec::new(),
+            allow_ports: Vec::new(),
+            deny_ports: Vec::new(),
+            ssrf_protection: default_ssrf_protection(),
+        }
+    }
+}
+
+impl ExitPolicy {
+    pub fn permits_peer(&self, node_id: &NodeId) -> bool {
+        self.peers.contains(node_id)
+    }
+
+    pub fn permits(&self, addr: SocketAddr) -> bool {
+        let ip = addr.ip().to_canonical();
+        let port = addr.port();
+        if self.deny_ips.iter().any(|net| net.contains(&ip))
+            || self.deny_ports.iter().any(|range| range.contains(port))
+        {
+            return false;
+        }
+        if !self.allow_ports.is_empty() && !self.allow_ports.iter().any(|r| r.contains(port)) {
+            return false;
+        }
+        let mut allowed_by = self.allow_ips.iter().filter(|net| net.contains(&ip));
+        if !self.allow_ips.is_empty() && allowed_by.clone().next().is_none() {
+            return false;
+        }
+        if self.ssrf_protection && internal_ranges().any(|range| range.contains(&ip)) {
+            return allowed_by.any(|net| internal_ranges().any(|range| range.contains(net)));
+        }
+        true
+    }
+}
+
+/// Why a node does not dial the destination a peer asked for.
+#[derive(Debug, Display, From, Error)]
+pub enum ExitError {
+    Resolve(std::io::Error),
+    #[display("destination not permitted")]
+    #[from(ignore)]
+    NotPermitted,
+}
+
+impl ExitError {
+    /// The SOCKS5 reply that tells the application what went wrong.
+    pub fn status(&self) -> ConnectedStatus {
+        match self {
+            ExitError::Resolve(_) => ConnectedStatus::HostUnreachable,
+            ExitError::NotPermitted => ConnectedStatus::ConnectionNotAllowed,
+        }
+    }
+}
+
+/// The addresses of `host`, which may also be an IP address.
+pub async fn lookup(
+    resolver: &dyn Resolve,
+    host: &str,
+    port: u16,
+) -> Result<Vec<SocketAddr>, ExitError> {
+    let ips = match host.parse::<IpAddr>() {
+        Ok(ip) => vec![ip],
+        Err(_) => resolver.resolve(host).await?,
+    };
+    Ok(ips
+        .into_iter()
+        .map(|ip| SocketAddr::new(ip, port))
+        .collect())
+}
+
+fn internal_ranges() -> impl Iterator<Item = IpNet> {
+    INTERNAL_RANGES.iter().map(|net| net.parse().unwrap())
+}
+
+/// A port or an inclusive range of ports, written as `443` or `8000-8999`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+#[display("{start}-{end}")]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl PortRange {
+    pub fn contains(&self, port: u16) -> bool {
+        (self.start..=self.end).contains(&port)
+    }
+}
+
+#[derive(Debug, Display, From, Error)]
+pub enum PortRangeParseError {
+    Port(ParseIntError),
+    #[display("port range start is after its end")]
+    Reversed,
+}
+
+impl FromStr for PortRange {
+    type Err = PortRangeParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s.split_once('-').unwrap_or((s, s));
+        let range = PortRange {
+            start: start.trim().parse()?,
+            end: end.trim().parse()?,
+        };
+        if range.start > range.end {
+            return Err(PortRangeParseError::Reversed);
+        }
+        Ok(range)
+    }
+}
+
+impl TryFrom<String> for PortRange {
+    type Error = PortRangeParseError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<PortRange> for String {
+    fn from(value: PortRange) -> Self {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resolver::{DnsResolver, ResolverConfig};
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_ssrf_protection() {
+        let policy = ExitPolicy::default();
+        assert!(policy.permits(addr("93.184.216.34:443")));
+        assert!(policy.permits(addr("[2606:2800:220:1::]:443")));
+        for internal in [
+            "127.0.0.1:80",
+            "10.1.2.3:80",
+            "169.254.169.254:80",
+            "[::1]:80",
+            "[::ffff:127.0.0.1]:80",
+            "[fd00::1]:80",
+            "0.0.0.0:80",
+        ] {
+            assert!(!policy.permits(addr(internal)), "{internal}");
+        }
+
+        let policy = ExitPolicy {
+            ssrf_protection: false,
+            ..Default::default()
+        };
+        assert!(policy.permits(addr("127.0.0.1:80")));
+    }
+
+    #[test]
+    fn test_ip_and_port_rules() {
+        let policy = ExitPolicy {
+            allow_ips: vec!["10.1.0.0/16".parse().unwrap(), "0.0.0.0/0".parse().unwrap()],
+            deny_ips: vec!["10.1.9.0/24".parse().unwrap()],
+            deny_ports: vec!["25".parse().unwrap()],
+            ..Default::default()
+        };
+        // An allow entry inside an internal range lifts the SSRF block for it;
+        // the catch-all does not.
+        assert!(policy.permits(addr("10.1.2.3:80")));
+        assert!(!policy.permits(addr("10.2.0.1:80")));
+        assert!(!policy.permits(addr("127.0.0.1:80")));
+        assert!(!policy.permits(addr("10.1.9.1:80")));
+        assert!(!policy.permits(addr("10.1.2.3:25")));
+        assert!(policy.permits(addr("93.184.216.34:80")));
+        assert!(!policy.permits(addr("[2606:2800:220:1::]:80")));
+
+        let policy = ExitPolicy {
+            allow_ports: vec!["443".parse().unwrap(), "8000-8999".parse().unwrap()],
+            ..Default::default()
+        };
+        assert!(policy.permits(addr("93.184.216.34:443")));
+        assert!(policy.permits(addr("93.184.216.34:8080")));
+        assert!(!policy.permits(addr("93.184.216.34:80")));
+    }
+
+    #[test]
+    fn test_port_range() {
+        assert_eq!(
+            "80".parse::<PortRange>().unwrap(),
+            PortRange { start: 80, end: 80 }
+        );
+        assert_eq!(
+            "8000-8999".parse::<PortRange>().unwrap(),
+            PortRange {
+                start: 8000,
+                end: 8999
+            }
+        );
+        assert!("9000-8000".parse::<PortRange>().is_err());
+        assert!("http".parse::<PortRange>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_lookup() {
+        let resolver = DnsResolver::new(&ResolverConfig::default());
+        let addrs = lookup(&resolver, "localhost", 80).await.unwrap();
+        assert!(addrs.iter().all(|addr| addr.ip().is_loopback()));
+        assert_eq!(
+            lookup(&resolver, "::1", 80).await.unwrap(),
+            [addr("[::1]:80")]
+        );
+        let err = lookup(&resolver, "name.invalid", 80).await.unwrap_err();
+        assert_eq!(err.status(), ConnectedStatus::HostUnreachable);
+    }
+}
@@ -4,19 +4,37 @@ use tokio::{
     io::{AsyncRead, AsyncWrite},
     net::TcpStream,
 };
+use tokio_rustls::server::TlsStream;
 
+pub mod ca;
 pub mod cli;
 pub mod client;
 pub mod config;
+pub mod dns;
+pub mod endpoint;
+pub mod exit;
+pub mod path;
+pub mod peer_cache;
+pub mod public_gateway;
+pub mod relay;
+pub mod relay_server;
+pub mod remote_dns;
+pub mod resolver;
+pub mod rules;
 pub mod server;
+pub mod subnet;
 #[cfg(test)]
 mod tests;
+pub mod udp;
+pub mod upstream;
 
 pub const ALPN: &[u8] = b"wave";
 
 pub enum Stream {
     Iroh(SendStream, RecvStream),
     Tcp(TcpStream),
+    /// A gateway connection with TLS terminated locally.
+    Tls(Box<TlsStream<TcpStream>>),
 }
 
 impl AsyncRead for Stream {
@@ -28,6 +46,7 @@ impl AsyncRead for Stream {
         match self.get_mut() {
             Stream::Iroh(_, recv_stream) => pin!(recv_stream).poll_read(cx, buf),
             Stream::Tcp(stream) => pin!(stream).poll_read(cx, buf),
+            Stream::Tls(stream) => pin!(stream).poll_read(cx, buf),
         }
     }
 }
@@ -41,6 +60,7 @@ impl AsyncWrite for Stream {
         match self.get_mut() {
             Stream::Iroh(send_stream, _) => pin!(send_stream).poll_write(cx, buf),
             Stream::Tcp(stream) => pin!(stream).poll_write(cx, buf),
+            Stream::Tls(stream) => pin!(stream).poll_write(cx, buf),
         }
     }
 
@@ -51,6 +71,7 @@ impl AsyncWrite for Stream {
         match self.get_mut() {
             Stream::Iroh(send_stream, _) => pin!(send_stream).poll_flush(cx),
             Stream::Tcp(stream) => pin!(stream).poll_flush(cx),
+            Stream::Tls(stream) => pin!(stream).poll_flush(cx),
         }
     }
 
@@ -61,6 +82,7 @@ impl AsyncWrite for Stream {
         match self.get_mut() {
             Stream::Iroh(send_stream, _) => pin!(send_stream).poll_shutdown(cx),
             Stream::Tcp(stream) => pin!(stream).poll_shutdown(cx),
+            Stream::Tls(stream) => pin!(stream).poll_shutdown(cx),
         }
     }
 }
@@ -0,0 +1,91 @@
+use iroh::{endpoint::ConnectionType, Endpoint};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, time::Duration};
+use wave_core::NodeId;
+
+pub use report::{PathChange, PathKind, PathReporter, PathState, PathTracker, StreamPath};
+
+mod report;
+
+/// How long a stream that needs a direct path waits for one before it is
+/// refused.
+pub const DIRECT_PATH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Whether traffic may be carried over a relay.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PathPolicy {
+    #[default]
+    RelayAllowed,
+    /// Only a direct UDP path may carry stream data. A mixed path still sends
+    /// over the relay, so it does not count as direct. This covers what this
+    /// node sends; the peer needs the same policy for the other direction.
+    DirectOnly,
+}
+
+/// Client side policy by destination; the strictest matching entry applies.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ClientPathPolicy {
+    /// Also covers streams to the peer as exit node, subnet router or remote
+    /// resolver.
+    #[serde(default)]
+    pub peers: HashMap<NodeId, PathPolicy>,
+    #[serde(default)]
+    pub subdomains: HashMap<String, PathPolicy>,
+}
+
+impl ClientPathPolicy {
+    pub fn get(&self, node_id: &NodeId, subdomain: &str) -> PathPolicy {
+        let subdomain = self.subdomains.get(subdomain).copied().unwrap_or_default();
+        self.peer(node_id).max(subdomain)
+    }
+
+    /// The policy for streams to `node_id` that are not for one of its routes,
+    /// e.g. to it as an exit node.
+    pub fn peer(&self, node_id: &NodeId) -> PathPolicy {
+        self.peers.get(node_id).copied().unwrap_or_default()
+    }
+}
+
+fn is_direct(conn_type: &ConnectionType) -> bool {
+    matches!(conn_type, ConnectionType::Direct(_))
+}
+
+/// Waits until the path to `node_id` is direct, for at most `timeout`.
+pub async fn wait_direct(
+    endpoint: &Endpoint,
+    node_id: NodeId,
+    timeout: Duration,
+) -> anyhow::Result<()> {
+    let mut watcher = endpoint.conn_type(node_id.0)?;
+    let wait = async {
+        let mut conn_type = watcher.get()?;
+        while !is_direct(&conn_type) {
+            conn_type = watcher.updated().await?;
+        }
+        anyhow::Ok(())
+    };
+    tokio::time::timeout(timeout, wait)
+        .await
+        .map_err(|_| anyhow::anyhow!("no direct path to {} within {:?}", node_id, timeout))?
+}
+
+/// Resolves once the path to `node_id` is no longer direct.
+pub async fn direct_lost(endpoint: &Endpoint, node_id: NodeId) -> anyhow::Error {
+    let watch = async {
+        let mut watcher = endpoint.conn_type(node_id.0)?;
+        let mut conn_type = watcher.get()?;
+        while is_direct(&conn_type) {
+            conn_type = watcher.updated().await?;
+        }
+        Err(anyhow::anyhow!(
+            "path to {} is no longer direct: {}",
+            node_id,
+            conn_type
+        ))
+    };
+    match watch.await {
+        Ok(()) => unreachable!(),
+        Err(e) => e,
+    }
+}
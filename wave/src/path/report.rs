@@ -0,0 +1,293 @@
+use iroh::{
+    endpoint::{Connection, ConnectionType},
+    watchable::Watcher,
+    Endpoint, RelayUrl,
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::task::JoinHandle;
+use tracing::{debug, info};
+use wave_core::NodeId;
+
+pub const RTT_SAMPLE_INTERVAL: Duration = Duration::from_secs(5);
+
+const MAX_CHANGES: usize = 64;
+
+const MAX_RTT_SAMPLES: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathKind {
+    Direct,
+    Relay,
+    Mixed,
+    None,
+}
+
+/// How packets to a peer currently travel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathState {
+    pub kind: PathKind,
+    pub remote_addr: Option<SocketAddr>,
+    pub relay_url: Option<RelayUrl>,
+}
+
+impl From<ConnectionType> for PathState {
+    fn from(conn_type: ConnectionType) -> Self {
+        let (kind, remote_addr, relay_url) = match conn_type {
+            ConnectionType::Direct(addr) => (PathKind::Direct, Some(addr), None),
+            ConnectionType::Relay(url) => (PathKind::Relay, None, Some(url)),
+            ConnectionType::Mixed(addr, url) => (PathKind::Mixed, Some(addr), Some(url)),
+            ConnectionType::None => (PathKind::None, None, None),
+        };
+        Self {
+            kind,
+            remote_addr,
+            relay_url,
+        }
+    }
+}
+
+impl fmt::Display for PathState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.remote_addr, &self.relay_url) {
+            (Some(addr), Some(url)) => write!(f, "mixed({addr}, {url})"),
+            (Some(addr), None) => write!(f, "direct({addr})"),
+            (None, Some(url)) => write!(f, "relay({url})"),
+            (None, None) => write!(f, "none"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathChange {
+    /// Time since the stream was opened.
+    pub at: Duration,
+    pub from: PathState,
+    pub to: PathState,
+    pub rtt: Duration,
+}
+
+/// What is known about the path of one relayed stream.
+#[derive(Debug, Clone)]
+pub struct StreamPath {
+    pub id: u64,
+    pub node_id: NodeId,
+    /// The node that opened the stream, when a trusted forwarder brought it
+    /// along a multi-hop route.
+    pub origin: Option<NodeId>,
+    pub route: Arc<str>,
+    pub age: Duration,
+    pub path: PathState,
+    pub rtt: Duration,
+    /// Recent RTT samples, oldest first.
+    pub rtt_samples: Vec<Duration>,
+    pub changes: Vec<PathChange>,
+}
+
+/// Tracks the path of every active stream, so a slow link can be told apart
+/// as relayed or direct.
+#[derive(Clone, Default)]
+pub struct PathReporter {
+    inner: Arc<ReporterInner>,
+}
+
+#[derive(Default)]
+struct ReporterInner {
+    streams: Mutex<HashMap<u64, Record>>,
+    next_id: AtomicU64,
+}
+
+struct Record {
+    node_id: NodeId,
+    origin: Option<NodeId>,
+    route: Arc<str>,
+    opened: Instant,
+    path: PathState,
+    rtt: Duration,
+    rtt_samples: VecDeque<Duration>,
+    changes: VecDeque<PathChange>,
+}
+
+/// Keeps a stream listed in its [`PathReporter`] until dropped.
+pub struct PathTracker {
+    id: u64,
+    reporter: PathReporter,
+    task: JoinHandle<()>,
+}
+
+impl Drop for PathTracker {
+    fn drop(&mut self) {
+        self.task.abort();
+        self.reporter.inner.streams.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl PathTracker {
+    /// Records that the stream was opened by `origin` and forwarded by the peer.
+    pub fn set_origin(&self, origin: NodeId) {
+        let mut streams = self.reporter.inner.streams.lock().unwrap();
+        if let Some(record) = streams.get_mut(&self.id) {
+            record.origin = Some(origin);
+        }
+    }
+}
+
+impl PathReporter {
+    /// Starts recording the path of a stream to `node_id` carried by `conn`.
+    pub fn track(
+        &self,
+        endpoint: &Endpoint,
+        conn: &Connection,
+        node_id: NodeId,
+        route: impl Into<Arc<str>>,
+    ) -> PathTracker {
+        let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+        let watcher = endpoint.conn_type(node_id.0).ok();
+        let path = watcher
+            .as_ref()
+            .and_then(|watcher| watcher.get().ok())
+            .map_or(PathState::from(ConnectionType::None), PathState::from);
+        let rtt = conn.rtt();
+        self.inner.streams.lock().unwrap().insert(
+            id,
+            Record {
+                node_id,
+                origin: None,
+                route: route.into(),
+                opened: Instant::now(),
+                path,
+                rtt,
+                rtt_samples: VecDeque::from([rtt]),
+                changes: VecDeque::new(),
+            },
+        );
+        let task = tokio::spawn(self.clone().watch(id, watcher, conn.clone()));
+        PathTracker {
+            id,
+            reporter: self.clone(),
+            task,
+        }
+    }
+
+    /// The active streams, oldest first.
+    pub fn streams(&self) -> Vec<StreamPath> {
+        let mut streams = self
+            .inner
+            .streams
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, record)| StreamPath {
+                id: *id,
+                node_id: record.node_id,
+                origin: record.origin,
+                route: record.route.clone(),
+                age: record.opened.elapsed(),
+                path: record.path.clone(),
+                rtt: record.rtt,
+                rtt_samples: record.rtt_samples.iter().copied().collect(),
+                changes: record.changes.iter().cloned().collect(),
+            })
+            .collect::<Vec<_>>();
+        streams.sort_by_key(|stream| stream.id);
+        streams
+    }
+
+    /// Logs how many active streams travel each kind of path every
+    /// `interval`, and every stream at debug level.
+    pub async fn log_streams(self, interval: Duration) {
+        loop {
+            tokio::time::sleep(interval).await;
+            let streams = self.streams();
+            if streams.is_empty() {
+                continue;
+            }
+            let count = |kind| streams.iter().filter(|s| s.path.kind == kind).count();
+            info!(
+                streams = streams.len(),
+                direct = count(PathKind::Direct),
+                relay = count(PathKind::Relay),
+                mixed = count(PathKind::Mixed),
+                "Stream paths"
+            );
+            for stream in &streams {
+                debug!(
+                    id = stream.id,
+                    node_id = %stream.node_id,
+                    origin = stream.origin.map(|origin| origin.to_string()),
+                    route = %stream.route,
+                    path = %stream.path,
+                    rtt = ?stream.rtt,
+                    changes = stream.changes.len(),
+                    age = ?stream.age,
+                    "Stream path"
+                );
+            }
+        }
+    }
+
+    async fn watch(self, id: u64, mut watcher: Option<Watcher<ConnectionType>>, conn: Connection) {
+        let mut ticker = tokio::time::interval(RTT_SAMPLE_INTERVAL);
+        ticker.tick().await;
+        loop {
+            tokio::select! {
+                conn_type = async {
+                    match watcher.as_mut() {
+                        Some(watcher) => watcher.updated().await.ok(),
+                        None => std::future::pending().await,
+                    }
+                } => match conn_type {
+                    Some(conn_type) => self.update_path(id, conn_type.into(), conn.rtt()),
+                    None => watcher = None,
+                },
+                _ = ticker.tick() => self.sample_rtt(id, conn.rtt()),
+            }
+        }
+    }
+
+    fn update_path(&self, id: u64, path: PathState, rtt: Duration) {
+        let mut streams = self.inner.streams.lock().unwrap();
+        let Some(record) = streams.get_mut(&id) else {
+            return;
+        };
+        record.rtt = rtt;
+        if record.path == path {
+            return;
+        }
+        info!(
+            node_id = %record.node_id,
+            route = %record.route,
+            from = %record.path,
+            to = %path,
+            ?rtt,
+            "Path changed"
+        );
+        if record.changes.len() == MAX_CHANGES {
+            record.changes.pop_front();
+        }
+        record.changes.push_back(PathChange {
+            at: record.opened.elapsed(),
+            from: std::mem::replace(&mut record.path, path.clone()),
+            to: path,
+            rtt,
+        });
+    }
+
+    fn sample_rtt(&self, id: u64, rtt: Duration) {
+        if let Some(record) = self.inner.streams.lock().unwrap().get_mut(&id) {
+            record.rtt = rtt;
+            if record.rtt_samples.len() == MAX_RTT_SAMPLES {
+                record.rtt_samples.pop_front();
+            }
+            record.rtt_samples.push_back(rtt);
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use iroh::{Endpoint, NodeAddr, PublicKey};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+use tracing::{debug, warn};
+
+const PEER_CACHE_FILE: &str = "peers.toml";
+
+pub const DEFAULT_SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+const CLOSE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Last known direct addresses and relay of every peer this node has talked
+/// to, kept in the state directory so a restarted node can dial them without
+/// waiting for discovery.
+#[derive(Debug, Clone)]
+pub struct PeerCache {
+    path: PathBuf,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+struct CacheFile {
+    #[serde(default)]
+    peers: Vec<NodeAddr>,
+}
+
+impl PeerCache {
+    pub fn new(state_dir: &Path) -> Self {
+        Self {
+            path: state_dir.join(PEER_CACHE_FILE),
+        }
+    }
+
+    pub fn load(&self) -> anyhow::Result<Vec<NodeAddr>> {
+        let content = match std::fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let file: CacheFile = toml::from_str(&content)?;
+        Ok(file.peers)
+    }
+
+    /// Merges the addresses `endpoint` currently has for the peers it has used
+    /// into the cache file.
+    pub fn save(&self, endpoint: &Endpoint) -> anyhow::Result<()> {
+        let mut peers: BTreeMap<PublicKey, NodeAddr> = self
+            .load()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|addr| (addr.node_id, addr))
+            .collect();
+        let mut changed = false;
+        for info in endpoint.remote_info_iter() {
+            if info.last_used.is_none() || !info.has_send_address() {
+                continue;
+            }
+            let addr = NodeAddr::from(info);
+            if peers.get(&addr.node_id) != Some(&addr) {
+                peers.insert(addr.node_id, addr);
+                changed = true;
+            }
+        }
+        if !changed && self.path.exists() {
+            return Ok(());
+        }
+
+        let file = CacheFile {
+            peers: peers.into_values().collect(),
+        };
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let tmp = self.path.with_extension("toml.tmp");
+        std::fs::write(&tmp, toml::to_string(&file)?)?;
+        std::fs::rename(tmp, &self.path)?;
+        debug!(path = %self.path.display(), peers = file.peers.len(), "Saved peer cache");
+        Ok(())
+    }
+
+    /// Saves the cache every `interval` and once more when the endpoint is
+    /// closed. The endpoint handle is held until then, so closure is checked
+    /// often to release its sockets promptly.
+    pub async fn run(self, endpoint: Endpoint, interval: Duration) {
+        let mut ticker = tokio::time::interval(CLOSE_CHECK_INTERVAL.min(interval));
+        let mut last_save = Instant::now();
+        loop {
+            ticker.tick().await;
+            let closed = endpoint.is_closed();
+            if closed || last_save.elapsed() >= interval {
+                self.save(&endpoint)
+                    .inspect_err(
+                        |e| warn!(path = %self.path.display(), "Save peer cache failed: {}", e),
+                    )
+                    .ok();
+                last_save = Instant::now();
+            }
+            if closed {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, endpoint, ALPN};
+    use std::net::{SocketAddr, SocketAddrV4};
+
+    fn config(state_dir: &Path, endpoint_bind: SocketAddrV4) -> Config {
+        Config {
+            offline: true,
+            endpoint_bind: Some(endpoint_bind),
+            state_dir: Some(state_dir.to_path_buf()),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_from_cache() {
+        let state_dir = std::env::temp_dir().join(format!("wave-test-{}", rand::random::<u64>()));
+        let client_dir = state_dir.join("client");
+        let any_port = "127.0.0.1:0".parse().unwrap();
+
+        let server_ep = endpoint::bind(&config(&state_dir.join("server"), any_port))
+            .await
+            .unwrap();
+        let server_addr = server_ep.node_addr().await.unwrap();
+        tokio::spawn(async move {
+            while let Some(incoming) = server_ep.accept().await {
+                tokio::spawn(async move {
+                    if let Ok(conn) = incoming.await {
+                        conn.closed().await;
+                    }
+                });
+            }
+        });
+
+        let client_ep = endpoint::bind(&config(&client_dir, any_port))
+            .await
+            .unwrap();
+        let SocketAddr::V4(client_bind) = client_ep.bound_sockets().0 else {
+            unreachable!()
+        };
+        let conn = client_ep.connect(server_addr.clone(), ALPN).await.unwrap();
+        conn.close(0u8.into(), b"done");
+        PeerCache::new(&client_dir).save(&client_ep).unwrap();
+        client_ep.close().await;
+        drop((conn, client_ep));
+        // The saver task lets go of the endpoint once it notices the close.
+        while std::net::UdpSocket::bind(client_bind).is_err() {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        let cached = PeerCache::new(&client_dir).load().unwrap();
+        assert_eq!(cached.len(), 1);
+        assert_eq!(cached[0].node_id, server_addr.node_id);
+
+        // Restarted on the same port and key, offline, and only given the node id.
+        let client_ep = endpoint::bind(&config(&client_dir, client_bind))
+            .await
+            .unwrap();
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            client_ep.connect(server_addr.node_id, ALPN),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+
+        std::fs::remove_dir_all(state_dir).unwrap();
+    }
+}
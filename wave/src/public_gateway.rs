@@ -0,0 +1,415 @@
+use crate::{
+    client::{
+        gateway::{error_response, read_head, request_host, wave_name, DEFAULT_GATEWAY_PORT},
+        pool::{ConnectionPool, DEFAULT_IDLE_TIMEOUT},
+    },
+    relay::{relay, BufferPool},
+    Stream,
+};
+use bytes::BytesMut;
+use iroh::Endpoint;
+use rustls::{
+    crypto::ring::default_provider,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    ServerConfig,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, SocketAddr, SocketAddrV4},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+};
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, info};
+use wave_core::{Connection, NodeId, WavePacket};
+
+/// How long the public routes a node advertised are trusted.
+const PUBLIC_ROUTES_TTL: Duration = Duration::from_secs(60);
+
+/// Upper bound on the answer to a public routes query.
+const MAX_PUBLIC_ROUTES_REPLY: usize = 64 * 1024;
+
+/// Client addresses tracked before idle ones are dropped.
+const MAX_RATE_LIMITED_CLIENTS: usize = 10_000;
+
+/// A public HTTP listener for clients outside wave: `sub.<node_id>.<domain>`,
+/// or a custom domain mapped to `sub.<node_id>`, reaches route `sub` of that
+/// node on `port`, if the node made the route public.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PublicGatewayConfig {
+    pub bind: SocketAddr,
+    pub domain: String,
+    #[serde(default)]
+    pub custom_domains: HashMap<String, String>,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    /// Serves HTTPS with this certificate, e.g. a wildcard for `*.<domain>`.
+    pub tls: Option<TlsFiles>,
+    #[serde(default)]
+    pub rate_limit: RateLimit,
+    /// Address of the gateway's own endpoint, apart from the node's
+    /// `endpoint_bind` so both can run on one machine.
+    pub endpoint_bind: Option<SocketAddrV4>,
+}
+
+fn default_port() -> u16 {
+    DEFAULT_GATEWAY_PORT
+}
+
+/// A certificate chain and its private key in PEM.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TlsFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// Requests each client address may make: `burst` at once, refilled at
+/// `requests_per_second`.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct RateLimit {
+    pub requests_per_second: f64,
+    pub burst: u32,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            requests_per_second: 10.0,
+            burst: 20,
+        }
+    }
+}
+
+/// A token bucket per client address.
+struct RateLimiter {
+    limit: RateLimit,
+    buckets: Mutex<HashMap<IpAddr, (f64, Instant)>>,
+}
+
+impl RateLimiter {
+    fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            buckets: Mutex::default(),
+        }
+    }
+
+    fn allow(&self, client: IpAddr, now: Instant) -> bool {
+        let burst = f64::from(self.limit.burst);
+        let refill = |tokens: f64, since: Instant| {
+            let elapsed = now.saturating_duration_since(since).as_secs_f64();
+            (tokens + elapsed * self.limit.requests_per_second).min(burst)
+        };
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_RATE_LIMITED_CLIENTS {
+            buckets.retain(|_, (tokens, since)| refill(*tokens, *since) < burst);
+        }
+        let (tokens, since) = buckets.entry(client).or_insert((burst, now));
+        *tokens = refill(*tokens, *since);
+        *since = now;
+        if *tokens < 1.0 {
+            return false;
+        }
+        *tokens -= 1.0;
+        true
+    }
+}
+
+/// A node's public routes and when they were asked for.
+type Advertised = (Instant, Arc<HashSet<String>>);
+
+/// The routes each node advertised as public, asked for on first use.
+struct PublicRoutes {
+    pool: ConnectionPool,
+    cache: Mutex<HashMap<NodeId, Advertised>>,
+}
+
+impl PublicRoutes {
+    async fn contains(&self, node_id: NodeId, route: &str) -> anyhow::Result<bool> {
+        let cached = self.cache.lock().unwrap().get(&node_id).cloned();
+        let routes = match cached {
+            Some((fetched, routes)) if fetched.elapsed() < PUBLIC_ROUTES_TTL => routes,
+            _ => {
+                let routes = Arc::new(self.fetch(node_id).await?);
+                self.cache
+                    .lock()
+                    .unwrap()
+                    .insert(node_id, (Instant::now(), routes.clone()));
+                routes
+            }
+        };
+        Ok(routes.contains(route))
+    }
+
+    async fn fetch(&self, node_id: NodeId) -> anyhow::Result<HashSet<String>> {
+        let (mut send, mut recv, _lease) = self.pool.open_bi(node_id).await?;
+        send.write_all_buf(&mut WavePacket::public_routes().encode())
+            .await?;
+        send.finish()?;
+        let reply = recv.read_to_end(MAX_PUBLIC_ROUTES_REPLY).await?;
+        Ok(std::str::from_utf8(&reply)?
+            .lines()
+            .map(str::to_string)
+            .collect())
+    }
+}
+
+/// Serves [`PublicGatewayConfig`]. Each connection carries one request, so
+/// every request is rate limited and logged; upgrades such as WebSocket stay
+/// open.
+pub struct PublicGateway {
+    listener: TcpListener,
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    pool: ConnectionPool,
+    domain: String,
+    custom_domains: HashMap<String, String>,
+    port: u16,
+    tls: Option<TlsAcceptor>,
+    limiter: RateLimiter,
+    public_routes: PublicRoutes,
+    buffers: BufferPool,
+}
+
+impl PublicGateway {
+    pub async fn bind(config: &PublicGatewayConfig, endpoint: Endpoint) -> anyhow::Result<Self> {
+        let tls = match &config.tls {
+            Some(files) => Some(TlsAcceptor::from(Arc::new(tls_config(files)?))),
+            None => None,
+        };
+        let pool = ConnectionPool::new(endpoint, DEFAULT_IDLE_TIMEOUT);
+        let custom_domains = config
+            .custom_domains
+            .iter()
+            .map(|(domain, name)| (wave_name(domain), name.to_ascii_lowercase()))
+            .collect();
+        Ok(Self {
+            listener: TcpListener::bind(config.bind).await?,
+            inner: Arc::new(Inner {
+                pool: pool.clone(),
+                domain: wave_name(&config.domain),
+                custom_domains,
+                port: config.port,
+                tls,
+                limiter: RateLimiter::new(config.rate_limit),
+                public_routes: PublicRoutes {
+                    pool,
+                    cache: Mutex::default(),
+                },
+                buffers: BufferPool::default(),
+            }),
+        })
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub async fn run(self) -> anyhow::Result<()> {
+        info!(bind = %self.local_addr()?, domain = %self.inner.domain, "Serve public gateway");
+        loop {
+            let (stream, client) = self.listener.accept().await?;
+            let inner = self.inner.clone();
+            tokio::spawn(async move {
+                inner
+                    .serve(stream, client)
+                    .await
+                    .inspect_err(|e| debug!(%client, "Public gateway error: {}", e))
+                    .ok();
+            });
+        }
+    }
+}
+
+impl Inner {
+    async fn serve(&self, stream: TcpStream, client: SocketAddr) -> anyhow::Result<()> {
+        let started = Instant::now();
+        let (mut upstream, proto) = match &self.tls {
+            Some(acceptor) => (
+                Stream::Tls(Box::new(acceptor.accept(stream).await?)),
+                "https",
+            ),
+            None => (Stream::Tcp(stream), "http"),
+        };
+        let mut buf = BytesMut::with_capacity(1024);
+        let Some(head_len) = read_head(&mut upstream, &mut buf).await? else {
+            if !buf.is_empty() {
+                upstream
+                    .write_all_buf(&mut error_response("400 Bad Request"))
+                    .await?;
+            }
+            return Ok(());
+        };
+        let head = buf.split_to(head_len);
+        let request = request_line(&head);
+        let host = request_host(&head).map(wave_name).unwrap_or_default();
+
+        let permitted = if !self.limiter.allow(client.ip(), Instant::now()) {
+            Err("429 Too Many Requests")
+        } else {
+            self.permitted_route(&host).await
+        };
+        let opened = match permitted {
+            Ok((node_id, route)) => match self.pool.open_bi(node_id).await {
+                Ok(opened) => Ok((opened, route)),
+                Err(e) => {
+                    debug!(%node_id, "Public gateway connect error: {}", e);
+                    Err("502 Bad Gateway")
+                }
+            },
+            Err(status) => Err(status),
+        };
+        let ((mut send, recv, _lease), route) = match opened {
+            Ok(opened) => opened,
+            Err(status) => {
+                upstream.write_all_buf(&mut error_response(status)).await?;
+                info!(client = %client.ip(), %host, %request, %status, "Access");
+                return Ok(());
+            }
+        };
+        let mut head = forwarded_head(&head, client.ip(), proto);
+        head.extend_from_slice(&buf);
+        let packet = WavePacket::gateway(self.port, route.parse()?);
+        send.write_all_buf(&mut packet.encode_with_payload(&head))
+            .await?;
+
+        let mut downstream = Stream::Iroh(send, recv);
+        let account = self.buffers.account(host.clone());
+        let relayed = relay(&mut upstream, &mut downstream, &account).await;
+        let (sent, received) = relayed.as_ref().copied().unwrap_or_default();
+        info!(
+            client = %client.ip(),
+            %host,
+            %request,
+            sent,
+            received,
+            ms = started.elapsed().as_millis() as u64,
+            "Access"
+        );
+        relayed?;
+        Ok(())
+    }
+
+    /// The node and route for `host`, or the status refusing it.
+    async fn permitted_route(&self, host: &str) -> Result<(NodeId, String), &'static str> {
+        let (node_id, route) = self.route(host).ok_or("404 Not Found")?;
+        match self.public_routes.contains(node_id, &route).await {
+            Ok(true) => Ok((node_id, route)),
+            Ok(false) => Err("403 Forbidden"),
+            Err(e) => {
+                debug!(%node_id, "Query public routes error: {}", e);
+                Err("502 Bad Gateway")
+            }
+        }
+    }
+
+    /// The node and route a `Host` stands for.
+    fn route(&self, host: &str) -> Option<(NodeId, String)> {
+        let name = match self.custom_domains.get(host) {
+            Some(name) => name.as_str(),
+            None => host.strip_suffix(self.domain.as_str())?.strip_suffix('.')?,
+        };
+        let (_, conn) = Connection::connect(name, self.port).ok()?;
+        Some((conn.node_id(), conn.subdomain().to_string()))
+    }
+}
+
+fn tls_config(files: &TlsFiles) -> anyhow::Result<ServerConfig> {
+    let certs = CertificateDer::pem_slice_iter(&std::fs::read(&files.cert)?)
+        .collect::<Result<Vec<_>, _>>()?;
+    let key = PrivateKeyDer::from_pem_slice(&std::fs::read(&files.key)?)?;
+    let mut config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+fn request_line(head: &[u8]) -> String {
+    let line = head.split(|b| *b == b'\r').next().unwrap_or_default();
+    String::from_utf8_lossy(line).into_owned()
+}
+
+/// The request head as sent to the node: with `X-Forwarded-For` and
+/// `X-Forwarded-Proto` set by the gateway, and `Connection: close` unless the
+/// client asks for an upgrade.
+fn forwarded_head(head: &[u8], client: IpAddr, proto: &str) -> Vec<u8> {
+    let head = String::from_utf8_lossy(head);
+    let mut lines = head.split("\r\n").filter(|line| !line.is_empty());
+    let request = lines.next().unwrap_or_default();
+    let headers: Vec<(&str, &str)> = lines.filter_map(|line| line.split_once(':')).collect();
+    let upgrade = headers
+        .iter()
+        .any(|(name, _)| name.trim().eq_ignore_ascii_case("upgrade"));
+    let replaced: &[&str] = if upgrade {
+        &["x-forwarded-for", "x-forwarded-proto"]
+    } else {
+        &[
+            "x-forwarded-for",
+            "x-forwarded-proto",
+            "connection",
+            "keep-alive",
+        ]
+    };
+
+    let mut out = format!("{request}\r\n");
+    for (name, value) in headers {
+        if !replaced.contains(&name.trim().to_ascii_lowercase().as_str()) {
+            out.push_str(&format!("{name}:{value}\r\n"));
+        }
+    }
+    out.push_str(&format!(
+        "X-Forwarded-For: {client}\r\nX-Forwarded-Proto: {proto}\r\n"
+    ));
+    if !upgrade {
+        out.push_str("Connection: close\r\n");
+    }
+    out.push_str("\r\n");
+    out.into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(RateLimit {
+            requests_per_second: 2.0,
+            burst: 2,
+        });
+        let (a, b) = ("192.0.2.1".parse().unwrap(), "192.0.2.2".parse().unwrap());
+        let now = Instant::now();
+        assert!(limiter.allow(a, now));
+        assert!(limiter.allow(a, now));
+        assert!(!limiter.allow(a, now));
+        assert!(limiter.allow(b, now));
+        assert!(limiter.allow(a, now + Duration::from_millis(500)));
+        assert!(!limiter.allow(a, now + Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn test_forwarded_head() {
+        let client = "192.0.2.1".parse().unwrap();
+        let head = b"GET / HTTP/1.1\r\nHost: a\r\nConnection: keep-alive\r\nX-Forwarded-For: 10.0.0.1\r\n\r\n";
+        assert_eq!(
+            String::from_utf8(forwarded_head(head, client, "https")).unwrap(),
+            "GET / HTTP/1.1\r\nHost: a\r\nX-Forwarded-For: 192.0.2.1\r\nX-Forwarded-Proto: https\r\nConnection: close\r\n\r\n"
+        );
+        let head =
+            b"GET /ws HTTP/1.1\r\nHost: a\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\n";
+        assert_eq!(
+            String::from_utf8(forwarded_head(head, client, "http")).unwrap(),
+            "GET /ws HTTP/1.1\r\nHost: a\r\nConnection: Upgrade\r\nUpgrade: websocket\r\nX-Forwarded-For: 192.0.2.1\r\nX-Forwarded-Proto: http\r\n\r\n"
+        );
+    }
+}
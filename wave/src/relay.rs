@@ -0,0 +1,421 @@
+use bytes::{Buf, BytesMut};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fmt,
+    io::IoSlice,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::{OwnedSemaphorePermit, Semaphore},
+};
+use tracing::{debug, info};
+
+#[cfg(test)]
+mod tests;
+#[cfg(test)]
+mod transfer;
+
+pub const BUFFER_SIZE: usize = 16 * 1024;
+
+/// Bytes a single direction may have read but not yet written.
+pub const DEFAULT_WINDOW: usize = 256 * 1024;
+
+const DEFAULT_MAX_FREE: usize = 1024;
+
+const MAX_IOVECS: usize = 16;
+
+/// Idle relays wait for data in this small per-direction buffer, so they hold
+/// no pooled buffer and no budget until bytes actually arrive.
+const PROBE_SIZE: usize = 2 * 1024;
+
+/// Fixed-size read buffers shared by every relay, handed back once their
+/// contents have been written. With a budget, relays stop reading while the
+/// buffers in use would exceed it.
+#[derive(Clone)]
+pub struct BufferPool {
+    inner: Arc<PoolInner>,
+}
+
+struct PoolInner {
+    free: Mutex<Vec<BytesMut>>,
+    max_free: usize,
+    allocated: AtomicUsize,
+    reused: AtomicUsize,
+    budget: Option<(usize, Arc<Semaphore>)>,
+    accounts: Mutex<HashMap<u64, Arc<AccountUsage>>>,
+    next_account: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolStats {
+    pub allocated: usize,
+    pub reused: usize,
+    pub free: usize,
+}
+
+/// Relay buffer memory currently held, in bytes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    pub limit: Option<usize>,
+    pub in_use: usize,
+    pub routes: BTreeMap<Arc<str>, usize>,
+    pub connections: Vec<ConnectionUsage>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionUsage {
+    pub id: u64,
+    pub route: Arc<str>,
+    pub bytes: usize,
+}
+
+impl fmt::Display for MemoryUsage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.limit {
+            Some(limit) => write!(f, "{} of {} bytes", self.in_use, limit)?,
+            None => write!(f, "{} bytes", self.in_use)?,
+        }
+        for (route, bytes) in &self.routes {
+            write!(f, ", {route}: {bytes}")?;
+        }
+        Ok(())
+    }
+}
+
+struct AccountUsage {
+    route: Arc<str>,
+    bytes: AtomicUsize,
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_FREE)
+    }
+}
+
+impl BufferPool {
+    pub fn new(max_free: usize) -> Self {
+        Self::build(max_free, None)
+    }
+
+    /// A pool whose buffers in use never exceed `limit` bytes.
+    pub fn with_budget(limit: usize) -> Self {
+        let buffers = (limit / BUFFER_SIZE).max(1);
+        Self::build(
+            DEFAULT_MAX_FREE.min(buffers),
+            Some((buffers * BUFFER_SIZE, Arc::new(Semaphore::new(buffers)))),
+        )
+    }
+
+    fn build(max_free: usize, budget: Option<(usize, Arc<Semaphore>)>) -> Self {
+        Self {
+            inner: Arc::new(PoolInner {
+                free: Mutex::new(Vec::new()),
+                max_free,
+                allocated: AtomicUsize::new(0),
+                reused: AtomicUsize::new(0),
+                budget,
+                accounts: Mutex::new(HashMap::new()),
+                next_account: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    pub fn get(&self) -> BytesMut {
+        if let Some(buf) = self.inner.free.lock().unwrap().pop() {
+            self.inner.reused.fetch_add(1, Ordering::Relaxed);
+            return buf;
+        }
+        self.inner.allocated.fetch_add(1, Ordering::Relaxed);
+        BytesMut::with_capacity(BUFFER_SIZE)
+    }
+
+    pub fn put(&self, mut buf: BytesMut) {
+        buf.clear();
+        // Reclaims the space consumed by `advance` without allocating.
+        buf.reserve(BUFFER_SIZE);
+        let mut free = self.inner.free.lock().unwrap();
+        if free.len() < self.inner.max_free {
+            free.push(buf);
+        }
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        PoolStats {
+            allocated: self.inner.allocated.load(Ordering::Relaxed),
+            reused: self.inner.reused.load(Ordering::Relaxed),
+            free: self.inner.free.lock().unwrap().len(),
+        }
+    }
+
+    /// Opens an account for one relayed connection; buffers it holds are
+    /// reported under `route` until the account is dropped.
+    pub fn account(&self, route: impl Into<Arc<str>>) -> Account {
+        let id = self.inner.next_account.fetch_add(1, Ordering::Relaxed);
+        let usage = Arc::new(AccountUsage {
+            route: route.into(),
+            bytes: AtomicUsize::new(0),
+        });
+        self.inner
+            .accounts
+            .lock()
+            .unwrap()
+            .insert(id, usage.clone());
+        Account {
+            id,
+            pool: self.clone(),
+            usage,
+        }
+    }
+
+    pub fn usage(&self) -> MemoryUsage {
+        let mut usage = MemoryUsage {
+            limit: self.inner.budget.as_ref().map(|(limit, _)| *limit),
+            ..Default::default()
+        };
+        for (id, account) in self.inner.accounts.lock().unwrap().iter() {
+            let bytes = account.bytes.load(Ordering::Relaxed);
+            usage.in_use += bytes;
+            *usage.routes.entry(account.route.clone()).or_default() += bytes;
+            usage.connections.push(ConnectionUsage {
+                id: *id,
+                route: account.route.clone(),
+                bytes,
+            });
+        }
+        usage.connections.sort_by_key(|c| c.id);
+        usage
+    }
+
+    /// Logs the buffers held per route every `interval`, and per connection at
+    /// debug level.
+    pub async fn log_usage(self, interval: Duration) {
+        loop {
+            tokio::time::sleep(interval).await;
+            let usage = self.usage();
+            if usage.connections.is_empty() {
+                continue;
+            }
+            info!(
+                connections = usage.connections.len(),
+                "Relay buffers: {}", usage
+            );
+            for conn in &usage.connections {
+                debug!(id = conn.id, route = %conn.route, bytes = conn.bytes, "Relay buffers held");
+            }
+        }
+    }
+}
+
+/// Per-connection handle for taking buffers out of a [`BufferPool`].
+pub struct Account {
+    id: u64,
+    pool: BufferPool,
+    usage: Arc<AccountUsage>,
+}
+
+struct Buffer {
+    buf: BytesMut,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Account {
+    /// Waits until the budget has room for another buffer.
+    async fn acquire(&self) -> Buffer {
+        let permit = match &self.pool.inner.budget {
+            Some((_, permits)) => Some(
+                permits
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .expect("relay budget is never closed"),
+            ),
+            None => None,
+        };
+        self.usage.bytes.fetch_add(BUFFER_SIZE, Ordering::Relaxed);
+        Buffer {
+            buf: self.pool.get(),
+            _permit: permit,
+        }
+    }
+
+    fn release(&self, buffer: Buffer) {
+        self.usage.bytes.fetch_sub(BUFFER_SIZE, Ordering::Relaxed);
+        self.pool.put(buffer.buf);
+    }
+
+    /// An empty reservation for bytes the connection buffers outside
+    /// [`relay`].
+    pub fn reservation(&self) -> Reservation {
+        Reservation {
+            usage: self.usage.clone(),
+            budget: self.pool.inner.budget.clone(),
+            permit: None,
+            bytes: 0,
+        }
+    }
+}
+
+/// Budget held for bytes buffered outside [`relay`], e.g. early data read
+/// while the backend is dialed, counted like pooled buffers until dropped.
+pub struct Reservation {
+    usage: Arc<AccountUsage>,
+    budget: Option<(usize, Arc<Semaphore>)>,
+    permit: Option<OwnedSemaphorePermit>,
+    bytes: usize,
+}
+
+impl Reservation {
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Waits until the budget has room to hold `bytes`, rounded up to whole
+    /// buffers. A reservation never exceeds the whole budget, so it cannot
+    /// wait forever.
+    pub async fn grow_to(&mut self, bytes: usize) {
+        let mut bytes = bytes.div_ceil(BUFFER_SIZE) * BUFFER_SIZE;
+        if let Some((limit, permits)) = &self.budget {
+            bytes = bytes.min(*limit);
+            if bytes <= self.bytes {
+                return;
+            }
+            let more = permits
+                .clone()
+                .acquire_many_owned(((bytes - self.bytes) / BUFFER_SIZE) as u32)
+                .await
+                .expect("relay budget is never closed");
+            match &mut self.permit {
+                Some(permit) => permit.merge(more),
+                None => self.permit = Some(more),
+            }
+        } else if bytes <= self.bytes {
+            return;
+        }
+        self.usage
+            .bytes
+            .fetch_add(bytes - self.bytes, Ordering::Relaxed);
+        self.bytes = bytes;
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.usage.bytes.fetch_sub(self.bytes, Ordering::Relaxed);
+    }
+}
+
+impl Drop for Account {
+    fn drop(&mut self) {
+        self.pool.inner.accounts.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Relays between `a` and `b` until both directions reach EOF, returning the
+/// bytes copied from `a` to `b` and from `b` to `a`.
+pub async fn relay<A, B>(a: A, b: B, account: &Account) -> std::io::Result<(u64, u64)>
+where
+    A: AsyncRead + AsyncWrite,
+    B: AsyncRead + AsyncWrite,
+{
+    let (a_read, a_write) = tokio::io::split(a);
+    let (b_read, b_write) = tokio::io::split(b);
+    futures_lite::future::try_zip(
+        pipe(a_read, b_write, account, DEFAULT_WINDOW),
+        pipe(b_read, a_write, account, DEFAULT_WINDOW),
+    )
+    .await
+}
+
+enum Event {
+    Probed(usize),
+    Acquired(Buffer),
+    Written(usize),
+}
+
+/// Copies `reader` into `writer` with at most `window` bytes in flight, writing
+/// every queued buffer with a single vectored write.
+async fn pipe<R, W>(
+    mut reader: R,
+    mut writer: W,
+    account: &Account,
+    window: usize,
+) -> std::io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let max_chunks = window.div_ceil(BUFFER_SIZE);
+    let mut queue = VecDeque::<Buffer>::with_capacity(max_chunks);
+    let mut probe = [0u8; PROBE_SIZE];
+    let mut probed = 0;
+    let mut in_flight = 0;
+    let mut total = 0;
+    let mut eof = false;
+
+    loop {
+        if eof && probed == 0 && queue.is_empty() {
+            writer.shutdown().await?;
+            return Ok(total);
+        }
+
+        let can_read = !eof && probed == 0 && in_flight < window;
+        let can_acquire = probed > 0 && queue.len() < max_chunks;
+        let event = {
+            let mut slices = [IoSlice::new(&[]); MAX_IOVECS];
+            let count = queue
+                .iter()
+                .zip(slices.iter_mut())
+                .map(|(buffer, slice)| *slice = IoSlice::new(&buffer.buf))
+                .count();
+            tokio::select! {
+                n = reader.read(&mut probe), if can_read => Event::Probed(n?),
+                buffer = account.acquire(), if can_acquire => Event::Acquired(buffer),
+                n = writer.write_vectored(&slices[..count]), if count > 0 => Event::Written(n?),
+            }
+        };
+
+        match event {
+            Event::Probed(0) => eof = true,
+            Event::Probed(n) => probed = n,
+            Event::Acquired(mut buffer) => {
+                buffer.buf.extend_from_slice(&probe[..probed]);
+                in_flight += probed;
+                probed = 0;
+                // Take whatever else is already readable without waiting.
+                while buffer.buf.len() < BUFFER_SIZE {
+                    let read = reader.read_buf(&mut buffer.buf);
+                    match futures_lite::future::poll_once(read).await {
+                        Some(Ok(0)) => {
+                            eof = true;
+                            break;
+                        }
+                        Some(Ok(n)) => in_flight += n,
+                        Some(Err(e)) => return Err(e),
+                        None => break,
+                    }
+                }
+                queue.push_back(buffer);
+            }
+            Event::Written(0) => return Err(std::io::ErrorKind::WriteZero.into()),
+            Event::Written(mut n) => {
+                in_flight -= n;
+                total += n as u64;
+                while n > 0 {
+                    let front = queue.front_mut().unwrap();
+                    let consumed = n.min(front.buf.len());
+                    front.buf.advance(consumed);
+                    n -= consumed;
+                    if front.buf.is_empty() {
+                        account.release(queue.pop_front().unwrap());
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,133 @@
+use super::transfer::transfer;
+use super::*;
+use std::time::Duration;
+use tokio::io::duplex;
+
+/// Kept small, the relay is measured against the legacy loop in
+/// `tests/relay_allocations.rs`.
+const TRANSFER: usize = 1024 * 1024;
+
+#[tokio::test]
+async fn test_bulk_transfer() {
+    let pool = BufferPool::default();
+    for _ in 0..2 {
+        transfer(TRANSFER, |a, b| async {
+            let (sent, received) = relay(a, b, &pool.account("bulk")).await.unwrap();
+            assert_eq!((sent, received), (TRANSFER as u64, 0));
+        })
+        .await;
+    }
+
+    let stats = pool.stats();
+    assert!(stats.allocated <= 2 * (DEFAULT_WINDOW / BUFFER_SIZE + 1));
+    assert_eq!(stats.free, stats.allocated);
+}
+
+#[tokio::test]
+async fn test_pool_reuses_buffers() {
+    let pool = BufferPool::new(1);
+    let mut buf = pool.get();
+    buf.extend_from_slice(b"hello");
+    buf.advance(5);
+    pool.put(buf);
+    pool.put(pool.get());
+    let buf = pool.get();
+    assert!(buf.is_empty() && buf.capacity() >= BUFFER_SIZE);
+    assert_eq!(
+        pool.stats(),
+        PoolStats {
+            allocated: 1,
+            reused: 2,
+            free: 0,
+        }
+    );
+}
+
+#[tokio::test]
+async fn test_budget_backpressure_and_usage() {
+    let pool = BufferPool::with_budget(2 * BUFFER_SIZE);
+    let web = pool.account("web");
+    let db = pool.account("db");
+
+    let web_buffer = web.acquire().await;
+    let db_buffer = db.acquire().await;
+    let usage = pool.usage();
+    assert_eq!(usage.limit, Some(2 * BUFFER_SIZE));
+    assert_eq!(usage.in_use, 2 * BUFFER_SIZE);
+    assert_eq!(usage.routes[&Arc::from("web")], BUFFER_SIZE);
+    assert_eq!(usage.routes[&Arc::from("db")], BUFFER_SIZE);
+    assert_eq!(usage.connections.len(), 2);
+    assert_eq!(
+        usage.to_string(),
+        format!(
+            "{0} of {0} bytes, db: {1}, web: {1}",
+            2 * BUFFER_SIZE,
+            BUFFER_SIZE
+        )
+    );
+
+    assert!(futures_lite::future::poll_once(web.acquire())
+        .await
+        .is_none());
+    db.release(db_buffer);
+    let second = tokio::time::timeout(Duration::from_secs(1), web.acquire())
+        .await
+        .unwrap();
+    assert_eq!(pool.usage().routes[&Arc::from("web")], 2 * BUFFER_SIZE);
+
+    drop(db);
+    web.release(web_buffer);
+    web.release(second);
+    let usage = pool.usage();
+    assert_eq!(usage.in_use, 0);
+    assert_eq!(usage.connections.len(), 1);
+}
+
+#[tokio::test]
+async fn test_reservation_counts_against_budget() {
+    let pool = BufferPool::with_budget(2 * BUFFER_SIZE);
+    let early = pool.account("early");
+    let relayed = pool.account("relayed");
+
+    let mut reservation = early.reservation();
+    reservation.grow_to(1).await;
+    assert_eq!(reservation.bytes(), BUFFER_SIZE);
+    // Capped at the whole budget instead of waiting forever.
+    reservation.grow_to(4 * BUFFER_SIZE).await;
+    assert_eq!(reservation.bytes(), 2 * BUFFER_SIZE);
+    assert_eq!(pool.usage().routes[&Arc::from("early")], 2 * BUFFER_SIZE);
+
+    assert!(futures_lite::future::poll_once(relayed.acquire())
+        .await
+        .is_none());
+    drop(reservation);
+    assert_eq!(pool.usage().in_use, 0);
+    let buffer = tokio::time::timeout(Duration::from_secs(1), relayed.acquire())
+        .await
+        .unwrap();
+    relayed.release(buffer);
+}
+
+#[tokio::test]
+async fn test_relay_within_budget() {
+    let pool = BufferPool::with_budget(4 * BUFFER_SIZE);
+    let account = pool.account("bulk");
+    transfer(TRANSFER, |a, b| async {
+        relay(a, b, &account).await.unwrap();
+    })
+    .await;
+    assert!(pool.stats().allocated <= 4);
+    assert_eq!(pool.usage().in_use, 0);
+}
+
+#[tokio::test]
+async fn test_idle_relay_holds_no_buffers() {
+    let pool = BufferPool::with_budget(BUFFER_SIZE);
+    let (_client, near) = duplex(1024);
+    let (far, _server) = duplex(1024);
+    let account = pool.account("idle");
+    let relay = relay(near, far, &account);
+    assert!(futures_lite::future::poll_once(relay).await.is_none());
+    assert_eq!(pool.usage().in_use, 0);
+    assert_eq!(pool.stats().allocated, 0);
+}
@@ -0,0 +1,38 @@
+//! The transfer both the relay unit tests and `tests/relay_allocations.rs`
+//! push through a relay.
+
+use std::future::Future;
+use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+/// Pushes `len` bytes through `relay_fn` and reads them back on the far side.
+pub async fn transfer<F, Fut>(len: usize, relay_fn: F)
+where
+    F: FnOnce(DuplexStream, DuplexStream) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let (mut source, near) = duplex(64 * 1024);
+    let (far, mut sink) = duplex(64 * 1024);
+
+    let chunk = vec![7u8; 64 * 1024];
+    let write = async {
+        for _ in 0..len / chunk.len() {
+            source.write_all(&chunk).await.unwrap();
+        }
+        source.shutdown().await.unwrap();
+        let mut rest = Vec::new();
+        source.read_to_end(&mut rest).await.unwrap();
+    };
+    let read = async {
+        let mut buf = vec![0u8; 64 * 1024];
+        let mut received = 0;
+        loop {
+            match sink.read(&mut buf).await.unwrap() {
+                0 => break,
+                n => received += n,
+            }
+        }
+        sink.shutdown().await.unwrap();
+        assert_eq!(received, len);
+    };
+    futures_lite::future::zip(relay_fn(near, far), futures_lite::future::zip(write, read)).await;
+}
@@ -0,0 +1,62 @@
+use crate::config::RelayServer;
+use iroh::{defaults::DEFAULT_STUN_PORT, RelayUrl};
+use iroh_relay::server::{AccessConfig, RelayConfig, Server, ServerConfig, StunConfig};
+use std::net::SocketAddr;
+use tracing::info;
+
+pub const DEFAULT_RELAY_HTTP_BIND: &str = "0.0.0.0:3340";
+
+pub const DEFAULT_RELAY_STUN_BIND: &str = "0.0.0.0:3478";
+
+/// An iroh relay and STUN server running in this process.
+///
+/// The relay speaks plain HTTP; put a TLS terminator in front of it when it is
+/// reachable from the internet.
+pub struct SelfHostedRelay {
+    server: Server,
+}
+
+impl SelfHostedRelay {
+    pub async fn spawn(
+        http_bind: SocketAddr,
+        stun_bind: Option<SocketAddr>,
+    ) -> anyhow::Result<Self> {
+        let config = ServerConfig::<(), ()> {
+            relay: Some(RelayConfig {
+                http_bind_addr: http_bind,
+                tls: None,
+                limits: Default::default(),
+                key_cache_capacity: None,
+                access: AccessConfig::Everyone,
+            }),
+            stun: stun_bind.map(|bind_addr| StunConfig { bind_addr }),
+            quic: None,
+            metrics_addr: None,
+        };
+        let server = Server::spawn(config).await?;
+        info!(http = ?server.http_addr(), stun = ?server.stun_addr(), "Relay started");
+        Ok(Self { server })
+    }
+
+    pub fn http_addr(&self) -> SocketAddr {
+        self.server.http_addr().expect("relay is configured")
+    }
+
+    /// The entry for `Config::relays` pointing at this relay, as seen from the
+    /// address it is bound to.
+    pub fn config(&self) -> anyhow::Result<RelayServer> {
+        let url: RelayUrl = format!("http://{}", self.http_addr()).parse()?;
+        Ok(RelayServer {
+            url,
+            stun_port: self
+                .server
+                .stun_addr()
+                .map_or(DEFAULT_STUN_PORT, |addr| addr.port()),
+        })
+    }
+
+    /// Runs until the relay stops.
+    pub async fn run(mut self) -> anyhow::Result<()> {
+        self.server.task_handle().await?
+    }
+}
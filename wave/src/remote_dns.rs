@@ -0,0 +1,81 @@
+use serde::{Deserialize, Serialize};
+use wave_core::NodeId;
+
+/// Names under `suffix`, e.g. `corp.internal` for `db.corp.internal`, are sent
+/// unresolved to `via`, which resolves them with its own resolver.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RemoteDns {
+    pub suffix: String,
+    pub via: NodeId,
+}
+
+/// The remote DNS suffixes of a client; the longest matching suffix wins.
+#[derive(Debug, Clone, Default)]
+pub struct RemoteDnsRules {
+    rules: Vec<(String, NodeId)>,
+}
+
+impl RemoteDnsRules {
+    pub fn new(rules: Vec<RemoteDns>) -> Self {
+        let mut rules: Vec<_> = rules
+            .into_iter()
+            .map(|rule| (normalize(&rule.suffix), rule.via))
+            .collect();
+        rules.sort_by_key(|(suffix, _)| std::cmp::Reverse(suffix.len()));
+        Self { rules }
+    }
+
+    /// The node that resolves `domain`, if a suffix matches it on a label
+    /// boundary.
+    pub fn lookup(&self, domain: &str) -> Option<NodeId> {
+        let domain = normalize(domain);
+        self.rules
+            .iter()
+            .find(|(suffix, _)| {
+                domain
+                    .strip_suffix(suffix.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.ends_with('.'))
+            })
+            .map(|(_, via)| *via)
+    }
+
+    /// The normalised suffixes with the node resolving each.
+    pub fn rules(&self) -> impl Iterator<Item = (&str, NodeId)> {
+        self.rules
+            .iter()
+            .map(|(suffix, via)| (suffix.as_str(), *via))
+    }
+}
+
+fn normalize(name: &str) -> String {
+    name.trim_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(seed: u8) -> NodeId {
+        NodeId(iroh::SecretKey::from_bytes(&[seed; 32]).public())
+    }
+
+    #[test]
+    fn test_suffix_match() {
+        let rules = RemoteDnsRules::new(vec![
+            RemoteDns {
+                suffix: ".internal".to_string(),
+                via: node(1),
+            },
+            RemoteDns {
+                suffix: "corp.internal".to_string(),
+                via: node(2),
+            },
+        ]);
+        assert_eq!(rules.lookup("db.corp.internal"), Some(node(2)));
+        assert_eq!(rules.lookup("DB.Corp.Internal."), Some(node(2)));
+        assert_eq!(rules.lookup("corp.internal"), Some(node(2)));
+        assert_eq!(rules.lookup("db.xcorp.internal"), Some(node(1)));
+        assert_eq!(rules.lookup("internal.example.com"), None);
+        assert_eq!(rules.lookup("example.com"), None);
+    }
+}
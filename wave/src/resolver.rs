@@ -0,0 +1,577 @@
+use crate::dns::{forward, UPSTREAM_TIMEOUT};
+use hickory_proto::{
+    op::{Message, MessageType, OpCode, Query, ResponseCode},
+    rr::{Name, RData, RecordType},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    task::JoinSet,
+};
+use tracing::debug;
+
+/// Upper bound on how long an answer is cached, whatever its TTL.
+const MAX_TTL: Duration = Duration::from_secs(3600);
+
+/// How long a name without addresses is remembered.
+const NEGATIVE_TTL: Duration = Duration::from_secs(30);
+
+const MAX_CACHE_ENTRIES: usize = 4096;
+
+/// How long a connection attempt has before the next address is tried too,
+/// as recommended by RFC 8305.
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+const HOSTS: &str = "/etc/hosts";
+
+pub type Resolving<'a> = Pin<Box<dyn Future<Output = io::Result<Vec<IpAddr>>> + Send + 'a>>;
+
+/// Looks up the addresses of the host names nodes dial.
+pub trait Resolve: Send + Sync {
+    fn resolve<'a>(&'a self, name: &'a str) -> Resolving<'a>;
+}
+
+/// The resolver of the operating system, which ties up a thread of the
+/// blocking pool for every lookup.
+#[derive(Debug, Default)]
+pub struct SystemResolver;
+
+impl Resolve for SystemResolver {
+    fn resolve<'a>(&'a self, name: &'a str) -> Resolving<'a> {
+        Box::pin(async move {
+            Ok(tokio::net::lookup_host((name, 0))
+                .await?
+                .map(|addr| addr.ip())
+                .collect())
+        })
+    }
+}
+
+/// Which DNS servers names are asked of.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ResolverConfig {
+    /// Servers for names no suffix matches. Without them the system resolver
+    /// looks those up, with its search domains and name service switch.
+    #[serde(default)]
+    pub upstreams: Vec<SocketAddr>,
+    #[serde(default)]
+    pub suffixes: Vec<SuffixUpstreams>,
+}
+
+/// Names under `suffix`, e.g. `corp.internal`, are asked of `upstreams`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SuffixUpstreams {
+    pub suffix: String,
+    pub upstreams: Vec<SocketAddr>,
+}
+
+/// An async stub resolver. It asks upstream servers for A and AAAA records
+/// over UDP, and over TCP when an answer is truncated, and caches answers for
+/// their TTL. The hosts file, `localhost` and `invalid` are answered locally,
+/// and names without configured upstreams go to the system resolver.
+#[derive(Debug)]
+pub struct DnsResolver {
+    upstreams: Vec<SocketAddr>,
+    /// By suffix, the longest first.
+    suffixes: Vec<(String, Vec<SocketAddr>)>,
+    hosts: HashMap<String, Vec<IpAddr>>,
+    cache: Mutex<HashMap<String, Cached>>,
+}
+
+#[derive(Debug)]
+struct Cached {
+    expires: Instant,
+    /// Empty for a name without addresses.
+    addrs: Vec<IpAddr>,
+}
+
+impl DnsResolver {
+    pub fn new(config: &ResolverConfig) -> Self {
+        let upstreams = config.upstreams.clone();
+        let mut suffixes: Vec<_> = config
+            .suffixes
+            .iter()
+            .map(|suffix| (normalize(&suffix.suffix), suffix.upstreams.clone()))
+            .collect();
+        suffixes.sort_by_key(|(suffix, _)| std::cmp::Reverse(suffix.len()));
+        Self {
+            upstreams,
+            suffixes,
+            hosts: read_hosts(),
+            cache: Mutex::default(),
+        }
+    }
+
+    fn upstreams(&self, name: &str) -> &[SocketAddr] {
+        self.suffixes
+            .iter()
+            .find(|(suffix, _)| in_domain(name, suffix))
+            .map_or(&self.upstreams, |(_, upstreams)| upstreams)
+    }
+
+    async fn lookup(&self, name: &str) -> io::Result<Vec<IpAddr>> {
+        let name = normalize(name);
+        if let Ok(ip) = name.parse() {
+            return Ok(vec![ip]);
+        }
+        if let Some(addrs) = self.hosts.get(&name) {
+            return Ok(addrs.clone());
+        }
+        if in_domain(&name, "localhost") {
+            return Ok(vec![Ipv6Addr::LOCALHOST.into(), Ipv4Addr::LOCALHOST.into()]);
+        }
+        if in_domain(&name, "invalid") {
+            return Err(not_found(&name));
+        }
+        let upstreams = self.upstreams(&name);
+        if upstreams.is_empty() {
+            return SystemResolver.resolve(&name).await;
+        }
+
+        let cached = self
+            .cache
+            .lock()
+            .unwrap()
+            .get(&name)
+            .filter(|cached| cached.expires > Instant::now())
+            .map(|cached| cached.addrs.clone());
+        let addrs = match cached {
+            Some(addrs) => addrs,
+            None => {
+                let (addrs, ttl) = query_any(upstreams, &name).await?;
+                debug!(%name, ?addrs, ?ttl, "Resolved");
+                self.store(&name, addrs.clone(), ttl);
+                addrs
+            }
+        };
+        match addrs.is_empty() {
+            true => Err(not_found(&name)),
+            false => Ok(addrs),
+        }
+    }
+
+    fn store(&self, name: &str, addrs: Vec<IpAddr>, ttl: Duration) {
+        let now = Instant::now();
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= MAX_CACHE_ENTRIES {
+            cache.retain(|_, cached| cached.expires > now);
+            if cache.len() >= MAX_CACHE_ENTRIES {
+                cache.clear();
+            }
+        }
+        let expires = now + ttl;
+        cache.insert(name.to_string(), Cached { expires, addrs });
+    }
+}
+
+impl Resolve for DnsResolver {
+    fn resolve<'a>(&'a self, name: &'a str) -> Resolving<'a> {
+        Box::pin(self.lookup(name))
+    }
+}
+
+/// Asks the upstreams in turn until one answers, for the addresses of `name`
+/// and how long they may be cached. When only one address family is answered
+/// its addresses are used, and kept briefly so the other is asked again soon.
+async fn query_any(upstreams: &[SocketAddr], name: &str) -> io::Result<(Vec<IpAddr>, Duration)> {
+    let mut error = None;
+    for upstream in upstreams {
+        let (v6, v4) = futures_lite::future::zip(
+            query(*upstream, name, RecordType::AAAA),
+            query(*upstream, name, RecordType::A),
+        )
+        .await;
+        match (v6, v4) {
+            (Ok((v6, v6_ttl)), Ok((v4, v4_ttl))) => {
+                let mut addrs = v6;
+                for ip in v4 {
+                    if !addrs.contains(&ip) {
+                        addrs.push(ip);
+                    }
+                }
+                let ttl = match addrs.is_empty() {
+                    true => NEGATIVE_TTL,
+                    false => v6_ttl.min(v4_ttl).min(MAX_TTL),
+                };
+                return Ok((addrs, ttl));
+            }
+            (Ok((addrs, ttl)), Err(e)) | (Err(e), Ok((addrs, ttl))) if !addrs.is_empty() => {
+                debug!(%upstream, %name, "DNS query error for one address family: {}", e);
+                return Ok((addrs, ttl.min(NEGATIVE_TTL)));
+            }
+            (Err(e), _) | (_, Err(e)) => {
+                debug!(%upstream, %name, "DNS query error: {}", e);
+                error = Some(e);
+            }
+        }
+    }
+    Err(error.unwrap_or_else(|| not_found(name)))
+}
+
+/// The addresses of one record type, with the lowest TTL among them. A name
+/// that does not exist has none.
+async fn query(
+    upstream: SocketAddr,
+    name: &str,
+    record_type: RecordType,
+) -> io::Result<(Vec<IpAddr>, Duration)> {
+    let fqdn = Name::from_ascii(format!("{name}.")).map_err(invalid)?;
+    let mut request = Message::new();
+    request
+        .set_id(rand::random())
+        .set_message_type(MessageType::Query)
+        .set_op_code(OpCode::Query)
+        .set_recursion_desired(true)
+        .add_query(Query::query(fqdn, record_type));
+    let request_bytes = request.to_vec().map_err(invalid)?;
+
+    let mut reply =
+        Message::from_vec(&forward(upstream, &request_bytes).await?).map_err(invalid)?;
+    if reply.id() != request.id() {
+        return Err(invalid("DNS reply for another query"));
+    }
+    if reply.truncated() {
+        reply = query_tcp(upstream, &request_bytes).await?;
+    }
+    match reply.response_code() {
+        ResponseCode::NoError => {}
+        ResponseCode::NXDomain => return Ok((Vec::new(), NEGATIVE_TTL)),
+        code => return Err(io::Error::other(format!("DNS server answered {code}"))),
+    }
+    let mut ttl = MAX_TTL;
+    let addrs = reply
+        .answers()
+        .iter()
+        .filter_map(|record| {
+            let ip = match record.data() {
+                RData::A(a) => IpAddr::V4(a.0),
+                RData::AAAA(aaaa) => IpAddr::V6(aaaa.0),
+                _ => return None,
+            };
+            ttl = ttl.min(Duration::from_secs(record.ttl().into()));
+            Some(ip)
+        })
+        .collect();
+    Ok((addrs, ttl))
+}
+
+async fn query_tcp(upstream: SocketAddr, request: &[u8]) -> io::Result<Message> {
+    tokio::time::timeout(UPSTREAM_TIMEOUT, async {
+        let mut stream = TcpStream::connect(upstream).await?;
+        let len = u16::try_from(request.len()).map_err(invalid)?;
+        stream
+            .write_all(&[&len.to_be_bytes()[..], request].concat())
+            .await?;
+        let mut reply = vec![0u8; usize::from(stream.read_u16().await?)];
+        stream.read_exact(&mut reply).await?;
+        Message::from_vec(&reply).map_err(invalid)
+    })
+    .await?
+}
+
+/// Connects to `host`, a name or an IP address. The addresses of a name are
+/// tried as in Happy Eyeballs (RFC 8305): alternating between IPv6 and IPv4,
+/// a further attempt starts whenever the last one failed or has not connected
+/// within 250 ms, and the first connection wins.
+pub async fn connect(resolver: &dyn Resolve, host: &str, port: u16) -> io::Result<TcpStream> {
+    let addrs = match host.parse::<IpAddr>() {
+        Ok(ip) => vec![ip],
+        Err(_) => resolver.resolve(host).await?,
+    };
+    let addrs = interleave(addrs)
+        .into_iter()
+        .map(|ip| SocketAddr::new(ip, port))
+        .collect();
+    happy_eyeballs(addrs).await
+}
+
+/// Alternates the address families, starting with the family of the first
+/// address.
+fn interleave(addrs: Vec<IpAddr>) -> Vec<IpAddr> {
+    let first_v6 = addrs.first().is_some_and(IpAddr::is_ipv6);
+    let (v6, v4): (Vec<_>, Vec<_>) = addrs.into_iter().partition(IpAddr::is_ipv6);
+    let (first, second) = match first_v6 {
+        true => (v6, v4),
+        false => (v4, v6),
+    };
+    let mut addrs = Vec::with_capacity(first.len() + second.len());
+    let (mut first, mut second) = (first.into_iter(), second.into_iter());
+    loop {
+        match (first.next(), second.next()) {
+            (None, None) => return addrs,
+            (a, b) => addrs.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+async fn happy_eyeballs(addrs: Vec<SocketAddr>) -> io::Result<TcpStream> {
+    let mut addrs = addrs.into_iter().peekable();
+    let mut attempts = JoinSet::new();
+    let mut error = None;
+    loop {
+        if let Some(addr) = addrs.next() {
+            attempts.spawn(async move {
+                TcpStream::connect(addr)
+                    .await
+                    .map_err(|e| io::Error::new(e.kind(), format!("connect to {addr}: {e}")))
+            });
+        }
+        if attempts.is_empty() {
+            return Err(error.unwrap_or_else(|| not_found("host")));
+        }
+        tokio::select! {
+            Some(attempt) = attempts.join_next() => match attempt {
+                Ok(Ok(stream)) => return Ok(stream),
+                Ok(Err(e)) => error = Some(e),
+                Err(e) => error = Some(io::Error::other(e)),
+            },
+            _ = tokio::time::sleep(CONNECTION_ATTEMPT_DELAY), if addrs.peek().is_some() => {}
+        }
+    }
+}
+
+fn read_hosts() -> HashMap<String, Vec<IpAddr>> {
+    let hosts = std::fs::read_to_string(HOSTS).unwrap_or_default();
+    let mut by_name: HashMap<String, Vec<IpAddr>> = HashMap::new();
+    for line in hosts.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut fields = line.split_whitespace();
+        let Some(Ok(ip)) = fields.next().map(str::parse::<IpAddr>) else {
+            continue;
+        };
+        for name in fields {
+            let addrs = by_name.entry(normalize(name)).or_default();
+            if !addrs.contains(&ip) {
+                addrs.push(ip);
+            }
+        }
+    }
+    by_name
+}
+
+/// Whether `name` is `domain` or a name under it.
+fn in_domain(name: &str, domain: &str) -> bool {
+    name.strip_suffix(domain)
+        .is_some_and(|rest| rest.is_empty() || rest.ends_with('.'))
+}
+
+fn normalize(name: &str) -> String {
+    name.trim_matches('.').to_ascii_lowercase()
+}
+
+fn not_found(name: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("{name} does not resolve"))
+}
+
+fn invalid(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_proto::rr::{
+        rdata::{A, AAAA},
+        Record,
+    };
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+    use tokio::net::{TcpListener, UdpSocket};
+
+    /// A stand-in DNS server answering for `names` with their address and
+    /// TTL, SERVFAIL for queries of the `failing` types, and NXDOMAIN for any
+    /// other name. It counts the queries it gets.
+    async fn stand_in(
+        names: &[(&str, IpAddr, u32)],
+        failing: &[RecordType],
+    ) -> (SocketAddr, Arc<AtomicUsize>) {
+        let failing = failing.to_vec();
+        let mut records: HashMap<String, Vec<(IpAddr, u32)>> = HashMap::new();
+        for (name, ip, ttl) in names {
+            records
+                .entry(format!("{name}."))
+                .or_default()
+                .push((*ip, *ttl));
+        }
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let queries = Arc::new(AtomicUsize::new(0));
+        let counter = queries.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 4096];
+            while let Ok((n, from)) = socket.recv_from(&mut buf).await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let request = Message::from_vec(&buf[..n]).unwrap();
+                let mut reply = request.clone();
+                reply.set_message_type(MessageType::Response);
+                let question = request.queries()[0].clone();
+                match records.get(&question.name().to_ascii()) {
+                    _ if failing.contains(&question.query_type()) => {
+                        reply.set_response_code(ResponseCode::ServFail);
+                    }
+                    Some(records) => {
+                        for (ip, ttl) in records {
+                            let rdata = match (ip, question.query_type()) {
+                                (IpAddr::V4(ip), RecordType::A) => RData::A(A(*ip)),
+                                (IpAddr::V6(ip), RecordType::AAAA) => RData::AAAA(AAAA(*ip)),
+                                _ => continue,
+                            };
+                            let name = question.name().clone();
+                            reply.add_answer(Record::from_rdata(name, *ttl, rdata));
+                        }
+                    }
+                    None => {
+                        reply.set_response_code(ResponseCode::NXDomain);
+                    }
+                }
+                socket
+                    .send_to(&reply.to_vec().unwrap(), from)
+                    .await
+                    .unwrap();
+            }
+        });
+        (addr, queries)
+    }
+
+    #[tokio::test]
+    async fn test_resolve() {
+        let (public, public_queries) = stand_in(
+            &[
+                ("www.example.test", "192.0.2.1".parse().unwrap(), 300),
+                ("www.example.test", "2001:db8::1".parse().unwrap(), 300),
+                ("short.example.test", "192.0.2.2".parse().unwrap(), 0),
+            ],
+            &[],
+        )
+        .await;
+        let (corp, corp_queries) =
+            stand_in(&[("db.corp.test", "10.0.0.5".parse().unwrap(), 300)], &[]).await;
+        let resolver = DnsResolver::new(&ResolverConfig {
+            upstreams: vec![public],
+            suffixes: vec![SuffixUpstreams {
+                suffix: ".Corp.test".to_string(),
+                upstreams: vec![corp],
+            }],
+        });
+
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        // Each lookup asks for A and AAAA.
+        assert_eq!(
+            resolver.resolve("WWW.example.test.").await.unwrap(),
+            [ip("2001:db8::1"), ip("192.0.2.1")]
+        );
+        assert_eq!(public_queries.load(Ordering::SeqCst), 2);
+        resolver.resolve("www.example.test").await.unwrap();
+        assert_eq!(public_queries.load(Ordering::SeqCst), 2);
+
+        assert_eq!(
+            resolver.resolve("db.corp.test").await.unwrap(),
+            [ip("10.0.0.5")]
+        );
+        assert_eq!(corp_queries.load(Ordering::SeqCst), 2);
+
+        // A zero TTL is not cached, a missing name is for a while.
+        resolver.resolve("short.example.test").await.unwrap();
+        resolver.resolve("short.example.test").await.unwrap();
+        assert_eq!(public_queries.load(Ordering::SeqCst), 6);
+        let missing = resolver.resolve("missing.example.test").await.unwrap_err();
+        assert_eq!(missing.kind(), io::ErrorKind::NotFound);
+        resolver.resolve("missing.example.test").await.unwrap_err();
+        assert_eq!(public_queries.load(Ordering::SeqCst), 8);
+
+        // Answered without asking.
+        assert!(resolver
+            .resolve("app.localhost")
+            .await
+            .unwrap()
+            .iter()
+            .all(IpAddr::is_loopback));
+        assert!(resolver.resolve("name.invalid").await.is_err());
+        assert_eq!(
+            public_queries.load(Ordering::SeqCst) + corp_queries.load(Ordering::SeqCst),
+            10
+        );
+    }
+
+    #[tokio::test]
+    async fn test_one_family_fails() {
+        let (upstream, queries) = stand_in(
+            &[("www.example.test", "192.0.2.1".parse().unwrap(), 300)],
+            &[RecordType::AAAA],
+        )
+        .await;
+        let resolver = DnsResolver::new(&ResolverConfig {
+            upstreams: vec![upstream],
+            suffixes: Vec::new(),
+        });
+        assert_eq!(
+            resolver.resolve("www.example.test").await.unwrap(),
+            ["192.0.2.1".parse::<IpAddr>().unwrap()]
+        );
+        resolver.resolve("www.example.test").await.unwrap();
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+
+        // Both failing is an error.
+        let (upstream, _) = stand_in(&[], &[RecordType::A, RecordType::AAAA]).await;
+        let resolver = DnsResolver::new(&ResolverConfig {
+            upstreams: vec![upstream],
+            suffixes: Vec::new(),
+        });
+        assert!(resolver.resolve("www.example.test").await.is_err());
+    }
+
+    #[test]
+    fn test_interleave() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let addrs = interleave(vec![
+            ip("2001:db8::1"),
+            ip("2001:db8::2"),
+            ip("2001:db8::3"),
+            ip("192.0.2.1"),
+        ]);
+        assert_eq!(
+            addrs,
+            [
+                ip("2001:db8::1"),
+                ip("192.0.2.1"),
+                ip("2001:db8::2"),
+                ip("2001:db8::3")
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_happy_eyeballs() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let open = listener.local_addr().unwrap();
+        let closed = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        // TEST-NET-1 is not routed: the attempt hangs or fails.
+        let unreachable = "192.0.2.1:9".parse().unwrap();
+
+        let started = Instant::now();
+        let stream = happy_eyeballs(vec![unreachable, closed, open])
+            .await
+            .unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), open);
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        assert!(happy_eyeballs(vec![closed]).await.is_err());
+        assert!(happy_eyeballs(Vec::new()).await.is_err());
+    }
+}
@@ -0,0 +1,312 @@
+use crate::{config, upstream::UpstreamProxy};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    net::IpAddr,
+    str::FromStr,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tracing::{info, warn};
+use wave_core::{
+    server::{Host, WaveTarget},
+    Connection, NodeId,
+};
+use wave_proxy::Address;
+
+/// A routing rule for connections of the client. A destination matches when
+/// it meets every condition the rule sets, so a rule without conditions
+/// matches everything. UDP datagrams are only dropped by `block` rules, the
+/// other actions route TCP connections alone.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Rule {
+    /// Names under this suffix, and the suffix itself.
+    pub domain_suffix: Option<String>,
+    /// This name or IP address exactly.
+    pub host: Option<String>,
+    /// IP addresses in this range; names do not match.
+    pub cidr: Option<IpNet>,
+    pub port: Option<u16>,
+    /// Wave names of this node's routes.
+    pub node: Option<NodeId>,
+    pub action: Action,
+}
+
+/// What happens to a connection a rule matches.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// Dial the destination from this host.
+    Direct,
+    /// Refuse the connection.
+    Block,
+    /// Tunnel through a SOCKS5 or HTTP CONNECT proxy.
+    Proxy(UpstreamProxy),
+    /// Have this exit node dial the destination.
+    Exit(NodeId),
+    /// Connect to this wave route instead.
+    Wave(WaveRoute),
+}
+
+/// A route of a node, written `sub.<node_id>` or `sub.<node_id>:port`.
+/// Without a port the connection keeps its destination port.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct WaveRoute(pub WaveTarget);
+
+impl WaveRoute {
+    /// The wave name to connect to for a destination on `port`.
+    pub fn address(&self, port: u16) -> Address {
+        let WaveTarget {
+            subdomain, node_id, ..
+        } = &self.0;
+        let name = match subdomain.is_empty() {
+            true => node_id.to_string(),
+            false => format!("{subdomain}.{node_id}"),
+        };
+        Address::Domain(Arc::from(name), self.0.port.unwrap_or(port))
+    }
+}
+
+impl TryFrom<String> for WaveRoute {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match Host::from_str(&value) {
+            Ok(Host::Wave(target)) => Ok(Self(target)),
+            _ => Err(format!("expected sub.<node_id>[:port], got {value}")),
+        }
+    }
+}
+
+impl From<WaveRoute> for String {
+    fn from(value: WaveRoute) -> Self {
+        value.0.to_string()
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::Direct => write!(f, "direct"),
+            Action::Block => write!(f, "block"),
+            Action::Proxy(proxy) => write!(f, "proxy {proxy}"),
+            Action::Exit(node_id) => write!(f, "exit {node_id}"),
+            Action::Wave(route) => write!(f, "wave {}", route.0),
+        }
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let conditions = [
+            self.domain_suffix
+                .as_ref()
+                .map(|suffix| format!("domain_suffix = {suffix}")),
+            self.host.as_ref().map(|host| format!("host = {host}")),
+            self.cidr.map(|cidr| format!("cidr = {cidr}")),
+            self.port.map(|port| format!("port = {port}")),
+            self.node.map(|node| format!("node = {node}")),
+        ];
+        let conditions: Vec<_> = conditions.into_iter().flatten().collect();
+        match conditions.is_empty() {
+            true => write!(f, "any -> {}", self.action),
+            false => write!(f, "{} -> {}", conditions.join(", "), self.action),
+        }
+    }
+}
+
+impl Rule {
+    fn matches(&self, target: &Address) -> bool {
+        let (name, ip, port) = match target {
+            Address::Ip(addr) => (None, Some(addr.ip().to_canonical()), addr.port()),
+            Address::Domain(domain, port) => (Some(normalize(domain)), None, *port),
+        };
+        self.port.is_none_or(|p| p == port)
+            && self.host.as_ref().is_none_or(|host| match (&name, ip) {
+                (Some(name), _) => name == host,
+                (None, ip) => ip.map(|ip| ip.to_string()).as_ref() == Some(host),
+            })
+            && self.domain_suffix.as_ref().is_none_or(|suffix| {
+                name.as_ref().is_some_and(|name| {
+                    name.strip_suffix(suffix.as_str())
+                        .is_some_and(|rest| rest.is_empty() || rest.ends_with('.'))
+                })
+            })
+            && self
+                .cidr
+                .is_none_or(|net| ip.is_some_and(|ip| net.contains(&ip)))
+            && self.node.is_none_or(|node| {
+                name.as_ref()
+                    .and_then(|name| Connection::connect(name, port).ok())
+                    .is_some_and(|(_, conn)| conn.node_id() == node)
+            })
+    }
+}
+
+/// The routing rules of a client, tried in order until one matches.
+/// Destinations no rule matches are routed as without rules: by subnet
+/// routes, as wave names, through the exit node, or directly.
+#[derive(Debug, Clone, Default)]
+pub struct Rules {
+    rules: Vec<Rule>,
+}
+
+impl Rules {
+    pub fn new(rules: Vec<Rule>) -> Self {
+        let rules = rules
+            .into_iter()
+            .map(|mut rule| {
+                rule.domain_suffix = rule.domain_suffix.as_deref().map(normalize);
+                rule.host = rule
+                    .host
+                    .as_deref()
+                    .map(|host| match host.parse::<IpAddr>() {
+                        Ok(ip) => ip.to_canonical().to_string(),
+                        Err(_) => normalize(host),
+                    });
+                rule
+            })
+            .collect();
+        Self { rules }
+    }
+
+    /// The first rule matching `target`, with its position in the config.
+    pub fn decide(&self, target: &Address) -> Option<(usize, &Rule)> {
+        self.rules
+            .iter()
+            .enumerate()
+            .find(|(_, rule)| rule.matches(target))
+    }
+}
+
+/// The rules in use, shared with the running client so a reloaded config
+/// applies to the connections that follow.
+#[derive(Debug, Clone, Default)]
+pub struct RuleTable(Arc<RwLock<Arc<Rules>>>);
+
+impl RuleTable {
+    pub fn new(rules: Rules) -> Self {
+        Self(Arc::new(RwLock::new(Arc::new(rules))))
+    }
+
+    pub fn load(&self) -> Arc<Rules> {
+        self.0.read().unwrap().clone()
+    }
+
+    pub fn store(&self, rules: Rules) {
+        *self.0.write().unwrap() = Arc::new(rules);
+    }
+
+    /// Swaps in the rules of the config file `config_name`, given without its
+    /// extension, whenever it changes, checking every `interval`. A config
+    /// that fails to load leaves the rules in use.
+    pub async fn reload_on_change(self, config_name: String, interval: Duration) {
+        let mut modified = config::config_modified(&config_name);
+        loop {
+            tokio::time::sleep(interval).await;
+            let changed = config::config_modified(&config_name);
+            if changed == modified {
+                continue;
+            }
+            modified = changed;
+            match config::load_config(&config_name) {
+                Ok(config) => {
+                    info!(rules = config.rules.len(), "Reloaded routing rules");
+                    self.store(Rules::new(config.rules));
+                }
+                Err(e) => warn!("Reload config error: {}", e),
+            }
+        }
+    }
+}
+
+fn normalize(name: &str) -> String {
+    name.trim_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(seed: u8) -> NodeId {
+        NodeId(iroh::SecretKey::from_bytes(&[seed; 32]).public())
+    }
+
+    #[test]
+    fn test_first_match() {
+        let rules: Vec<Rule> = toml::from_str::<toml::Table>(&format!(
+            r#"
+            [[rules]]
+            host = "ads.example.com"
+            action = "block"
+
+            [[rules]]
+            domain_suffix = ".Example.com"
+            port = 22
+            action = {{ exit = "{exit}" }}
+
+            [[rules]]
+            domain_suffix = "example.com"
+            action = {{ proxy = "socks5://127.0.0.1:1080" }}
+
+            [[rules]]
+            cidr = "10.0.0.0/8"
+            action = {{ wave = "db.{exit}:5432" }}
+
+            [[rules]]
+            node = "{exit}"
+            action = "direct"
+            "#,
+            exit = node(1)
+        ))
+        .unwrap()["rules"]
+            .clone()
+            .try_into()
+            .unwrap();
+        let rules = Rules::new(rules);
+        let decide = |target: &str| {
+            rules
+                .decide(&target.parse().unwrap())
+                .map(|(index, rule)| (index, rule.action.to_string()))
+        };
+
+        assert_eq!(decide("ADS.example.com:443"), Some((0, "block".into())));
+        assert_eq!(
+            decide("git.example.com:22"),
+            Some((1, format!("exit {}", node(1))))
+        );
+        assert_eq!(
+            decide("example.com:443"),
+            Some((2, "proxy socks5://127.0.0.1:1080".into()))
+        );
+        assert_eq!(decide("notexample.com:443"), None);
+        assert_eq!(
+            decide("10.1.2.3:80"),
+            Some((3, format!("wave db.{}:5432", node(1))))
+        );
+        assert_eq!(
+            decide("[::ffff:10.1.2.3]:80").map(|(index, _)| index),
+            Some(3)
+        );
+        assert_eq!(
+            decide(&format!("web.{}:80", node(1))),
+            Some((4, "direct".into()))
+        );
+        assert_eq!(decide(&format!("web.{}:80", node(2))), None);
+        assert_eq!(decide("192.168.1.1:80"), None);
+    }
+
+    #[test]
+    fn test_wave_route() {
+        let route = WaveRoute::try_from(format!("db.{}", node(1))).unwrap();
+        assert_eq!(
+            route.address(5432).to_string(),
+            format!("db.{}:5432", node(1))
+        );
+        let route = WaveRoute::try_from(format!("{}:8080", node(1))).unwrap();
+        assert_eq!(route.address(80).to_string(), format!("{}:8080", node(1)));
+        assert!(WaveRoute::try_from("db.example.com".to_string()).is_err());
+    }
+}
@@ -1,32 +1,203 @@
-use crate::Stream;
-use bytes::BytesMut;
-use futures_lite::FutureExt;
-use iroh::{endpoint::Incoming, Endpoint};
-use std::sync::Arc;
+use crate::{
+    client::pool::{ConnectionPool, DEFAULT_IDLE_TIMEOUT},
+    exit::{self, ExitError, ExitPolicy},
+    path::{self, PathPolicy, PathReporter, DIRECT_PATH_TIMEOUT},
+    relay::{relay, BufferPool, Reservation},
+    resolver::{self, DnsResolver, Resolve, ResolverConfig},
+    subnet::SubnetGrants,
+    udp::{self, DatagramRouter, Flow, DEFAULT_UDP_IDLE_TIMEOUT},
+    upstream::UpstreamProxy,
+    Stream,
+};
+use bytes::{BufMut, BytesMut};
+use iroh::{
+    endpoint::{self, ConnectionError, Incoming, RecvStream, SendStream, VarInt},
+    Endpoint,
+};
+use sni::{Peek, SniRoutes};
+use std::{
+    collections::{HashMap, HashSet},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::pin,
+    sync::Arc,
+    time::Duration,
+};
 use tokio::{
     io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
-    net::TcpStream,
+    net::{TcpStream, UdpSocket},
+    sync::Semaphore,
 };
-use tracing::info;
-use wave_core::{server::Host, Connection, NodeId, Server, WavePacket};
+use tracing::{debug, info, warn};
+use wave_core::{
+    connection::Forwarded,
+    server::{Fallback, Host, WaveTarget},
+    Connection, NodeId, PacketKind, WavePacket,
+};
+use wave_proxy::{protocol::socks5::types::ConnectedStatus, Address};
+
+pub mod sni;
+
+pub const DEFAULT_MAX_CONCURRENT_STREAMS: u32 = 128;
+
+/// Upper bound on payload buffered from the peer while the backend is dialed.
+const MAX_EARLY_DATA: usize = 64 * 1024;
+
+/// How long a stream of a route with SNI routes may take to send its
+/// ClientHello before it goes to the route's own target.
+const SNI_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Nodes a stream may be forwarded by along multi-hop routes, so routes that
+/// point at each other cannot loop forever.
+pub const MAX_HOPS: u8 = 8;
 
+#[derive(Clone)]
 pub struct ServerService {
     server: Arc<wave_core::Server>,
     endpoint: Endpoint,
+    max_concurrent_streams: u32,
+    buffers: BufferPool,
+    route_paths: Arc<HashMap<String, PathPolicy>>,
+    direct_path_timeout: Duration,
+    path_reporter: PathReporter,
+    udp_routes: Arc<HashSet<String>>,
+    udp_idle_timeout: Duration,
+    exit_policy: Option<Arc<ExitPolicy>>,
+    subnets: Arc<SubnetGrants>,
+    sni_routes: Arc<SniRoutes>,
+    public_routes: Arc<HashSet<String>>,
+    resolver: Arc<dyn Resolve>,
+    route_proxies: Arc<HashMap<String, UpstreamProxy>>,
+    forwarders: Arc<HashSet<NodeId>>,
+    /// Connections to the nodes multi-hop routes lead to.
+    pool: ConnectionPool,
 }
 
 impl ServerService {
     pub fn new(server: Arc<wave_core::Server>, endpoint: Endpoint) -> Self {
-        Self { server, endpoint }
+        Self {
+            server,
+            pool: ConnectionPool::new(endpoint.clone(), DEFAULT_IDLE_TIMEOUT),
+            endpoint,
+            max_concurrent_streams: DEFAULT_MAX_CONCURRENT_STREAMS,
+            buffers: BufferPool::default(),
+            route_paths: Arc::default(),
+            direct_path_timeout: DIRECT_PATH_TIMEOUT,
+            path_reporter: PathReporter::default(),
+            udp_routes: Arc::default(),
+            udp_idle_timeout: DEFAULT_UDP_IDLE_TIMEOUT,
+            exit_policy: None,
+            subnets: Arc::default(),
+            sni_routes: Arc::default(),
+            public_routes: Arc::default(),
+            resolver: Arc::new(DnsResolver::new(&ResolverConfig::default())),
+            route_proxies: Arc::default(),
+            forwarders: Arc::default(),
+        }
+    }
+
+    pub fn with_buffer_pool(mut self, buffers: BufferPool) -> Self {
+        self.buffers = buffers;
+        self
+    }
+
+    /// Limits how many bi-streams a single peer connection may have open at once.
+    pub fn with_max_concurrent_streams(mut self, max: u32) -> Self {
+        self.max_concurrent_streams = max.max(1);
+        self
+    }
+
+    /// Sets the path policy per route subdomain. Direct-only routes hold each
+    /// stream until the peer is reached directly and refuse it otherwise.
+    pub fn with_route_paths(mut self, route_paths: HashMap<String, PathPolicy>) -> Self {
+        self.route_paths = Arc::new(route_paths);
+        self
+    }
+
+    pub fn with_direct_path_timeout(mut self, timeout: Duration) -> Self {
+        self.direct_path_timeout = timeout;
+        self
+    }
+
+    /// Records the path of every served stream in `reporter`.
+    pub fn with_path_reporter(mut self, reporter: PathReporter) -> Self {
+        self.path_reporter = reporter;
+        self
+    }
+
+    pub fn path_reporter(&self) -> &PathReporter {
+        &self.path_reporter
+    }
+
+    /// Sets the route subdomains that also serve UDP flows, relayed to the
+    /// same host and port as their TCP streams.
+    pub fn with_udp_routes(mut self, udp_routes: HashSet<String>) -> Self {
+        self.udp_routes = Arc::new(udp_routes);
+        self
+    }
+
+    /// How long a UDP flow may go without a datagram in either direction.
+    pub fn with_udp_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.udp_idle_timeout = timeout;
+        self
+    }
+
+    /// Makes this node an exit node for the peers `policy` lists.
+    pub fn with_exit_policy(mut self, policy: ExitPolicy) -> Self {
+        self.exit_policy = Some(Arc::new(policy));
+        self
+    }
+
+    /// Routes the granted subnets of this node's network for their peers,
+    /// which reach them by IP address without a route per host.
+    pub fn with_subnets(mut self, subnets: SubnetGrants) -> Self {
+        self.subnets = Arc::new(subnets);
+        self
+    }
+
+    /// Sends TCP streams of routes with SNI routes to the backend their TLS
+    /// server name picks, so one port can serve several HTTPS backends.
+    pub fn with_sni_routes(mut self, sni_routes: SniRoutes) -> Self {
+        self.sni_routes = Arc::new(sni_routes);
+        self
+    }
+
+    /// Sets the route subdomains public gateways may serve to clients outside
+    /// wave. They are advertised to any peer that asks.
+    pub fn with_public_routes(mut self, public_routes: HashSet<String>) -> Self {
+        self.public_routes = Arc::new(public_routes);
+        self
+    }
+
+    /// Resolves the host names of route targets and exit destinations.
+    pub fn with_resolver(mut self, resolver: Arc<dyn Resolve>) -> Self {
+        self.resolver = resolver;
+        self
+    }
+
+    /// Sets the proxy per route subdomain that TCP streams of the route dial
+    /// its target through, for backends only reachable via a proxy. The
+    /// proxy resolves names itself.
+    pub fn with_route_proxies(mut self, route_proxies: HashMap<String, UpstreamProxy>) -> Self {
+        self.route_proxies = Arc::new(route_proxies);
+        self
+    }
+
+    /// Sets the nodes trusted to say which node opened a stream they forward
+    /// along a multi-hop route. Other peers are taken as the origin of their
+    /// streams, whatever they claim.
+    pub fn with_forwarders(mut self, forwarders: HashSet<NodeId>) -> Self {
+        self.forwarders = Arc::new(forwarders);
+        self
     }
 
     pub async fn run(self) -> anyhow::Result<()> {
         loop {
             tokio::select! {
                 Some(conn) = self.endpoint.accept() => {
-                    let server = self.server.clone();
+                    let service = self.clone();
                     tokio::spawn(async move {
-                        Self::handle(conn, server)
+                        service
+                            .handle(conn)
                             .await
                             .inspect_err(|e| {
                                 tracing::error!("handle incomming error: {}", e);
@@ -38,73 +209,504 @@ impl ServerService {
         }
     }
 
-    async fn handle(incoming: Incoming, server: Arc<Server>) -> anyhow::Result<()> {
-        let iroh_conn = incoming.await?;
-        let (mut send_stream, mut recv_stream) = iroh_conn.accept_bi().await?;
+    async fn handle(self, incoming: Incoming) -> anyhow::Result<()> {
+        let iroh_conn = match incoming.await {
+            Ok(conn) => conn,
+            Err(e) => {
+                debug!("Incoming connection failed: {}", e);
+                return Ok(());
+            }
+        };
+        let remote_node_id = NodeId(iroh_conn.remote_node_id()?);
+        let max_streams = self.max_concurrent_streams;
+        iroh_conn.set_max_concurrent_bi_streams(VarInt::from_u32(max_streams));
+        let permits = Arc::new(Semaphore::new(max_streams as usize));
+        let datagrams = DatagramRouter::new(iroh_conn.clone());
+
+        loop {
+            let permit = permits.clone().acquire_owned().await?;
+            let (send_stream, recv_stream) = match iroh_conn.accept_bi().await {
+                Ok(stream) => stream,
+                Err(
+                    ConnectionError::ApplicationClosed(_)
+                    | ConnectionError::ConnectionClosed(_)
+                    | ConnectionError::LocallyClosed
+                    | ConnectionError::Reset
+                    | ConnectionError::TimedOut,
+                ) => {
+                    debug!(%remote_node_id, "Connection closed");
+                    return Ok(());
+                }
+                // The peer broke the protocol, which ends only its connection.
+                Err(e) => {
+                    warn!(%remote_node_id, "Connection lost: {}", e);
+                    return Ok(());
+                }
+            };
+            let service = self.clone();
+            let iroh_conn = iroh_conn.clone();
+            let datagrams = datagrams.clone();
+            tokio::spawn(async move {
+                let _permit = permit;
+                service
+                    .handle_bi(
+                        send_stream,
+                        recv_stream,
+                        &iroh_conn,
+                        &datagrams,
+                        remote_node_id,
+                    )
+                    .await
+                    .inspect_err(|e| tracing::error!(%remote_node_id, "handle stream error: {}", e))
+                    .ok();
+            });
+        }
+    }
 
+    async fn handle_bi(
+        self,
+        mut send_stream: SendStream,
+        mut recv_stream: RecvStream,
+        iroh_conn: &endpoint::Connection,
+        datagrams: &DatagramRouter,
+        remote_node_id: NodeId,
+    ) -> anyhow::Result<()> {
         let mut upstream_buf = BytesMut::with_capacity(1024);
-        let wave_packet = loop {
-            recv_stream.read_buf(&mut upstream_buf).await?;
+        let mut wave_packet = loop {
+            if recv_stream.read_buf(&mut upstream_buf).await? == 0 {
+                return Ok(());
+            }
             if let Some(wave_packet) = WavePacket::decode(&mut upstream_buf)? {
                 break wave_packet;
             }
         };
-        let remote_node_id = iroh_conn.remote_node_id()?;
-        let (conn, host) = server.accept(NodeId(remote_node_id), wave_packet);
+        match wave_packet.kind {
+            PacketKind::Subnets => {
+                let advertised = self.subnets.advertised(&remote_node_id);
+                let lines: String = advertised.iter().map(|net| format!("{}\n", net)).collect();
+                send_stream.write_all(lines.as_bytes()).await?;
+                send_stream.finish()?;
+                return Ok(());
+            }
+            PacketKind::PublicRoutes => {
+                let lines: String = self
+                    .public_routes
+                    .iter()
+                    .map(|route| format!("{}\n", route))
+                    .collect();
+                send_stream.write_all(lines.as_bytes()).await?;
+                send_stream.finish()?;
+                return Ok(());
+            }
+            PacketKind::Exit { .. } => {
+                return self
+                    .handle_exit(
+                        send_stream,
+                        recv_stream,
+                        upstream_buf,
+                        iroh_conn,
+                        remote_node_id,
+                        wave_packet,
+                    )
+                    .await;
+            }
+            PacketKind::Stream | PacketKind::Udp | PacketKind::Gateway => {}
+        }
+
+        if let Some(forwarded) = wave_packet.forwarded.as_mut() {
+            if !self.forwarders.contains(&remote_node_id) {
+                info!(
+                    %remote_node_id,
+                    claimed_origin = %forwarded.origin,
+                    "Ignore unverified origin of stream from a peer that is not a forwarder"
+                );
+                // The hop count still bounds the route.
+                forwarded.origin = remote_node_id;
+            }
+        }
+        let udp = wave_packet.kind == PacketKind::Udp;
+        let gateway = wave_packet.kind == PacketKind::Gateway;
+        let (conn, host) = self.server.accept(remote_node_id, wave_packet);
 
         let host = match host {
-            Ok(host) => host,
+            Ok(_) if gateway && !self.public_routes.contains(conn.subdomain().as_str()) => {
+                send_stream
+                    .write_all_buf(&mut Fallback::default().bytes())
+                    .await?;
+                send_stream.finish()?;
+                return Err(anyhow::anyhow!(
+                    "route {:?} is not public",
+                    conn.subdomain().as_str()
+                ));
+            }
+            Ok(host) if !udp || self.udp_routes.contains(conn.subdomain().as_str()) => host,
+            Ok(_) => {
+                send_stream.finish()?;
+                return Err(anyhow::anyhow!(
+                    "route {:?} does not serve UDP",
+                    conn.subdomain().as_str()
+                ));
+            }
             Err(fallback) => {
-                send_stream.write_all_buf(&mut fallback.bytes()).await?;
+                if !udp {
+                    send_stream.write_all_buf(&mut fallback.bytes()).await?;
+                }
+                send_stream.finish()?;
                 return Ok(());
             }
         };
 
-        Self::handle_stream(
-            Stream::Iroh(send_stream, recv_stream),
-            upstream_buf,
-            conn,
-            host,
-        )
-        .await?;
+        let path = self.path_reporter.track(
+            &self.endpoint,
+            iroh_conn,
+            remote_node_id,
+            conn.subdomain().as_str(),
+        );
+        if conn.origin() != remote_node_id {
+            info!(origin = %conn.origin(), hops = conn.hops(), "Forwarded stream");
+            path.set_origin(conn.origin());
+        }
+        let direct_only =
+            self.route_paths.get(conn.subdomain().as_str()) == Some(&PathPolicy::DirectOnly);
+        if direct_only {
+            let waited =
+                path::wait_direct(&self.endpoint, remote_node_id, self.direct_path_timeout).await;
+            if let Err(e) = waited {
+                if !udp {
+                    send_stream
+                        .write_all_buf(&mut Fallback::default().bytes())
+                        .await?;
+                }
+                send_stream.finish()?;
+                return Err(e);
+            }
+        }
+
+        let host = if udp {
+            host
+        } else {
+            let route = conn.subdomain();
+            self.sni_target(&mut recv_stream, &mut upstream_buf, route.as_str(), host)
+                .await?
+        };
+
+        let serving = async {
+            if udp {
+                let flow = datagrams
+                    .accept(send_stream, recv_stream, upstream_buf)
+                    .await?;
+                self.handle_udp(flow, conn, host).await
+            } else if let Host::Wave(target) = host {
+                self.handle_hop(
+                    Stream::Iroh(send_stream, recv_stream),
+                    upstream_buf,
+                    &conn,
+                    target,
+                )
+                .await
+            } else {
+                self.handle_stream(
+                    Stream::Iroh(send_stream, recv_stream),
+                    upstream_buf,
+                    host,
+                    conn.port(),
+                    conn.subdomain().as_str(),
+                    self.route_proxies.get(conn.subdomain().as_str()),
+                )
+                .await
+            }
+        };
+        if direct_only {
+            tokio::select! {
+                res = serving => res?,
+                e = path::direct_lost(&self.endpoint, remote_node_id) => return Err(e),
+            }
+        } else {
+            serving.await?;
+        }
 
         Ok(())
     }
 
+    /// The backend of a stream for `route`, picked by the server name of the
+    /// TLS ClientHello it starts with when the route has SNI routes. Streams
+    /// without a matching name keep the route's target.
+    async fn sni_target(
+        &self,
+        recv_stream: &mut RecvStream,
+        upstream_buf: &mut BytesMut,
+        route: &str,
+        host: Host,
+    ) -> anyhow::Result<Host> {
+        if !self.sni_routes.serves(route) {
+            return Ok(host);
+        }
+        let account = self.buffers.account(route);
+        let mut reservation = account.reservation();
+        let peeking = async {
+            loop {
+                match sni::peek_server_name(upstream_buf) {
+                    Peek::Incomplete if upstream_buf.len() < MAX_EARLY_DATA => {
+                        if read_reserved(recv_stream, upstream_buf, &mut reservation).await? == 0 {
+                            return Ok::<_, std::io::Error>(None);
+                        }
+                    }
+                    Peek::ServerName(name) => return Ok(Some(name)),
+                    Peek::Incomplete | Peek::None => return Ok(None),
+                }
+            }
+        };
+        let server_name = tokio::time::timeout(SNI_TIMEOUT, peeking)
+            .await
+            .unwrap_or(Ok(None))?;
+        let Some(server_name) = server_name else {
+            return Ok(host);
+        };
+        match self.sni_routes.lookup(route, &server_name) {
+            Some(target) => {
+                info!(%server_name, %target, "Route by server name");
+                Ok(target)
+            }
+            None => Ok(host),
+        }
+    }
+
     async fn handle_stream<S>(
+        &self,
         mut upstream: S,
         mut upstream_buf: BytesMut,
-        conn: Connection,
         target: Host,
+        port: u16,
+        account: &str,
+        proxy: Option<&UpstreamProxy>,
     ) -> anyhow::Result<()>
     where
         S: AsyncRead + AsyncWrite + Unpin,
     {
         let downstream_host = target;
 
-        let mut downstream = match &downstream_host {
-            Host::Ip(ip) => TcpStream::connect((*ip, conn.port())).await?,
-            Host::Domain(domain) => TcpStream::connect((domain.as_ref(), conn.port())).await?,
+        let mut dial = pin!(async {
+            match (&downstream_host, proxy) {
+                (Host::Wave(target), _) => {
+                    anyhow::bail!("{} is reached with a hop, not dialed", target)
+                }
+                (Host::Ip(ip), Some(proxy)) => {
+                    proxy
+                        .connect(&Address::Ip(SocketAddr::new(*ip, port)))
+                        .await
+                }
+                (Host::Domain(domain), Some(proxy)) => {
+                    let target = Address::Domain(domain.clone(), port);
+                    proxy.connect(&target).await
+                }
+                (Host::Ip(ip), None) => Ok(TcpStream::connect((*ip, port)).await?),
+                (Host::Domain(domain), None) => {
+                    Ok(resolver::connect(&*self.resolver, domain, port).await?)
+                }
+            }
+        });
+        let account = self.buffers.account(account);
+        let mut early_data = account.reservation();
+        early_data.grow_to(upstream_buf.len()).await;
+        let mut upstream_eof = false;
+        let mut downstream = loop {
+            let reading = !upstream_eof && upstream_buf.len() < MAX_EARLY_DATA;
+            tokio::select! {
+                stream = &mut dial => break stream?,
+                n = read_reserved(&mut upstream, &mut upstream_buf, &mut early_data), if reading => {
+                    upstream_eof = n? == 0
+                }
+            }
         };
 
-        info!("proxy to {}:{}", downstream_host, conn.port());
+        match proxy {
+            Some(proxy) => info!("proxy to {}:{} via {}", downstream_host, port, proxy),
+            None => info!("proxy to {}:{}", downstream_host, port),
+        }
 
-        upstream_buf.reserve(1024);
-        let mut downstream_buf = BytesMut::with_capacity(1024);
+        if !upstream_buf.is_empty() {
+            downstream.write_all_buf(&mut upstream_buf).await?;
+        }
+        drop(early_data);
+        let (sent, received) = relay(&mut upstream, &mut downstream, &account).await?;
+        debug!(
+            sent,
+            received, "proxy to {}:{} finished", downstream_host, port
+        );
 
-        loop {
-            if !downstream_buf.is_empty() {
-                upstream.write_all_buf(&mut downstream_buf).await?;
+        Ok(())
+    }
+
+    /// Relays a stream along a multi-hop route to `target` on another node,
+    /// telling it who opened the stream.
+    async fn handle_hop(
+        &self,
+        mut upstream: Stream,
+        mut upstream_buf: BytesMut,
+        conn: &Connection,
+        target: WaveTarget,
+    ) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            conn.hops() < MAX_HOPS,
+            "stream for {} already took {} hops",
+            target,
+            conn.hops()
+        );
+        anyhow::ensure!(
+            target.node_id.0 != self.endpoint.node_id(),
+            "route to {} loops back to this node",
+            target
+        );
+
+        let (mut send, recv, _lease) = self.pool.open_bi(target.node_id).await?;
+        let mut packet =
+            WavePacket::new(target.port.unwrap_or(conn.port()), target.subdomain.clone());
+        packet.forwarded = Some(Forwarded {
+            origin: conn.origin(),
+            hops: conn.hops() + 1,
+        });
+        send.write_all_buf(&mut packet.encode_with_payload(&upstream_buf.split()))
+            .await?;
+        info!(origin = %conn.origin(), "forward to {}", target);
+
+        let mut downstream = Stream::Iroh(send, recv);
+        let account = self.buffers.account(conn.subdomain().as_str());
+        let (sent, received) = relay(&mut upstream, &mut downstream, &account).await?;
+        debug!(sent, received, "forward to {} finished", target);
+
+        Ok(())
+    }
+
+    /// Dials the destination a peer asked for as its subnet router or exit
+    /// node, when a subnet grant or the exit policy permits it.
+    async fn handle_exit(
+        &self,
+        mut send_stream: SendStream,
+        recv_stream: RecvStream,
+        upstream_buf: BytesMut,
+        iroh_conn: &endpoint::Connection,
+        remote_node_id: NodeId,
+        packet: WavePacket,
+    ) -> anyhow::Result<()> {
+        let host = packet.subdomain.as_str();
+        let permitted = self.resolve_exit(&remote_node_id, host, packet.port).await;
+        let status = match &permitted {
+            Ok(_) => ConnectedStatus::Succeeded,
+            Err(e) => e.status(),
+        };
+        if packet.kind == (PacketKind::Exit { resolve: true }) {
+            send_stream.write_all(&[status as u8]).await?;
+        }
+        let (addr, kind) = match permitted {
+            Ok(permitted) => permitted,
+            Err(e) => {
+                send_stream.finish()?;
+                return Err(anyhow::anyhow!(
+                    "{} may not reach {}:{} via this node: {}",
+                    remote_node_id,
+                    host,
+                    packet.port,
+                    e
+                ));
             }
-            if !upstream_buf.is_empty() {
-                downstream.write_all_buf(&mut upstream_buf).await?;
+        };
+
+        let _path = self.path_reporter.track(
+            &self.endpoint,
+            iroh_conn,
+            remote_node_id,
+            format!("{}:{}", kind, addr),
+        );
+        info!(%remote_node_id, "{} to {}:{} as {}", kind, host, packet.port, addr);
+        self.handle_stream(
+            Stream::Iroh(send_stream, recv_stream),
+            upstream_buf,
+            Host::Ip(addr.ip()),
+            addr.port(),
+            kind,
+            None,
+        )
+        .await
+    }
+
+    /// The first address of `host` that a subnet grant or the exit policy lets
+    /// `node_id` reach, and which of the two did. Names are only resolved for
+    /// peers that may use this node.
+    async fn resolve_exit(
+        &self,
+        node_id: &NodeId,
+        host: &str,
+        port: u16,
+    ) -> Result<(SocketAddr, &'static str), ExitError> {
+        let exit = self
+            .exit_policy
+            .as_deref()
+            .filter(|policy| policy.permits_peer(node_id));
+        if exit.is_none() && !self.subnets.serves(node_id) {
+            return Err(ExitError::NotPermitted);
+        }
+        for addr in exit::lookup(&*self.resolver, host, port).await? {
+            if self.subnets.permits(node_id, addr) {
+                return Ok((addr, "subnet"));
+            }
+            if exit.is_some_and(|policy| policy.permits(addr)) {
+                return Ok((addr, "exit"));
             }
-            upstream
-                .read_buf(&mut upstream_buf)
-                .race(downstream.read_buf(&mut downstream_buf))
-                .await?;
+            debug!(%host, %addr, "Destination refused by policy");
         }
+        Err(ExitError::NotPermitted)
+    }
+
+    async fn handle_udp(
+        &self,
+        mut flow: Flow,
+        conn: Connection,
+        target: Host,
+    ) -> anyhow::Result<()> {
+        let addr = match &target {
+            Host::Ip(ip) => SocketAddr::new(*ip, conn.port()),
+            Host::Domain(domain) => self
+                .resolver
+                .resolve(domain)
+                .await?
+                .first()
+                .map(|ip| SocketAddr::new(*ip, conn.port()))
+                .ok_or_else(|| anyhow::anyhow!("{} did not resolve", domain))?,
+            Host::Wave(target) => anyhow::bail!("UDP is not forwarded to {}", target),
+        };
+        let bind = match addr {
+            SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+        };
+        let socket = UdpSocket::bind(bind).await?;
+        socket.connect(addr).await?;
+
+        info!("proxy udp to {}", addr);
+        udp::relay_socket(&mut flow, &socket, self.udp_idle_timeout).await?;
+        debug!("proxy udp to {} finished", addr);
+
+        Ok(())
+    }
+}
+
+/// Reads from `stream` into `buf` no more than `reservation` holds budget for,
+/// growing it by a buffer once it is full. With the whole budget reserved it
+/// waits for the caller to stop reading.
+async fn read_reserved<S>(
+    stream: &mut S,
+    buf: &mut BytesMut,
+    reservation: &mut Reservation,
+) -> std::io::Result<usize>
+where
+    S: AsyncRead + Unpin,
+{
+    reservation.grow_to(buf.len() + 1).await;
+    let room = reservation.bytes().saturating_sub(buf.len());
+    if room == 0 {
+        return std::future::pending().await;
     }
+    buf.reserve(room);
+    stream.read_buf(&mut buf.limit(room)).await
 }
 
 // pub struct SelfConnecting {
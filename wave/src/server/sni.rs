@@ -0,0 +1,244 @@
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, str::FromStr};
+use wave_core::server::Host;
+
+const HANDSHAKE: u8 = 22;
+
+const CLIENT_HELLO: u8 = 1;
+
+const SERVER_NAME: usize = 0;
+
+const HOST_NAME: usize = 0;
+
+/// Streams for `route` whose TLS ClientHello names `server_name`, exactly or
+/// as `*.example.com` for any name below it, go to `target` instead of the
+/// route's own target. TLS is passed through, not terminated.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SniRoute {
+    #[serde(default)]
+    pub route: String,
+    pub server_name: String,
+    pub target: String,
+}
+
+/// The SNI routes of a server by route subdomain. An exact name wins over
+/// wildcards, and a longer wildcard over a shorter one.
+#[derive(Debug, Clone, Default)]
+pub struct SniRoutes {
+    routes: HashMap<String, Vec<(String, Host)>>,
+}
+
+impl SniRoutes {
+    pub fn new(routes: Vec<SniRoute>) -> Result<Self, wave_core::Error> {
+        let mut by_route: HashMap<String, Vec<(String, Host)>> = HashMap::new();
+        for route in routes {
+            let target = Host::from_str(&route.target)?;
+            let name = route.server_name.trim_end_matches('.').to_ascii_lowercase();
+            by_route
+                .entry(route.route)
+                .or_default()
+                .push((name, target));
+        }
+        for names in by_route.values_mut() {
+            names.sort_by_key(|(name, _)| (name.starts_with("*."), std::cmp::Reverse(name.len())));
+        }
+        Ok(Self { routes: by_route })
+    }
+
+    /// Whether streams for `route` are routed by server name.
+    pub fn serves(&self, route: &str) -> bool {
+        self.routes.contains_key(route)
+    }
+
+    pub fn lookup(&self, route: &str, server_name: &str) -> Option<Host> {
+        self.routes
+            .get(route)?
+            .iter()
+            .find(|(name, _)| match name.strip_prefix('*') {
+                Some(suffix) => server_name.ends_with(suffix),
+                None => name == server_name,
+            })
+            .map(|(_, target)| target.clone())
+    }
+}
+
+/// What the first bytes of a stream tell about its server name.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Peek {
+    /// More bytes are needed.
+    Incomplete,
+    /// A ClientHello naming this server, in lower case.
+    ServerName(String),
+    /// Not a ClientHello, or one without a host name.
+    None,
+}
+
+/// Reads the server name from a ClientHello at the start of `buf`, which may
+/// span several TLS records.
+pub fn peek_server_name(buf: &[u8]) -> Peek {
+    if buf.first().is_some_and(|ty| *ty != HANDSHAKE) {
+        return Peek::None;
+    }
+    let mut handshake = Vec::new();
+    let mut records = buf;
+    loop {
+        let Some(header) = records.get(..5) else {
+            return Peek::Incomplete;
+        };
+        if header[0] != HANDSHAKE {
+            return Peek::None;
+        }
+        let len = usize::from(u16::from_be_bytes([header[3], header[4]]));
+        let Some(fragment) = records.get(5..5 + len) else {
+            return Peek::Incomplete;
+        };
+        handshake.extend_from_slice(fragment);
+        records = &records[5 + len..];
+
+        if handshake.len() < 4 {
+            continue;
+        }
+        if handshake[0] != CLIENT_HELLO {
+            return Peek::None;
+        }
+        let hello_len = u32::from_be_bytes([0, handshake[1], handshake[2], handshake[3]]) as usize;
+        if let Some(hello) = handshake.get(4..4 + hello_len) {
+            return server_name(hello).map_or(Peek::None, Peek::ServerName);
+        }
+    }
+}
+
+fn server_name(hello: &[u8]) -> Option<String> {
+    let mut hello = Reader(hello);
+    // Version and random.
+    hello.take(2 + 32)?;
+    let session_id = hello.u8()?;
+    hello.take(session_id)?;
+    let cipher_suites = hello.u16()?;
+    hello.take(cipher_suites)?;
+    let compression = hello.u8()?;
+    hello.take(compression)?;
+    let extensions = hello.u16()?;
+    let mut extensions = Reader(hello.take(extensions)?);
+    while !extensions.0.is_empty() {
+        let ty = extensions.u16()?;
+        let len = extensions.u16()?;
+        let data = extensions.take(len)?;
+        if ty != SERVER_NAME {
+            continue;
+        }
+        let mut list = Reader(data);
+        let len = list.u16()?;
+        let mut list = Reader(list.take(len)?);
+        while !list.0.is_empty() {
+            let kind = list.u8()?;
+            let len = list.u16()?;
+            let name = list.take(len)?;
+            if kind == HOST_NAME {
+                return std::str::from_utf8(name)
+                    .ok()
+                    .map(|name| name.trim_end_matches('.').to_ascii_lowercase());
+            }
+        }
+        return None;
+    }
+    None
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (taken, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn u8(&mut self) -> Option<usize> {
+        self.take(1).map(|b| usize::from(b[0]))
+    }
+
+    fn u16(&mut self) -> Option<usize> {
+        self.take(2)
+            .map(|b| usize::from(u16::from_be_bytes([b[0], b[1]])))
+    }
+}
+
+/// The first flight of a TLS client connecting to `server_name`.
+#[cfg(test)]
+pub(crate) fn client_hello(server_name: &str) -> Vec<u8> {
+    use std::sync::Arc;
+    let config = rustls::ClientConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .unwrap()
+    .with_root_certificates(rustls::RootCertStore::empty())
+    .with_no_client_auth();
+    let mut conn = rustls::ClientConnection::new(
+        Arc::new(config),
+        server_name.to_string().try_into().unwrap(),
+    )
+    .unwrap();
+    let mut hello = Vec::new();
+    conn.write_tls(&mut hello).unwrap();
+    hello
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_peek_server_name() {
+        let hello = client_hello("App.Example.com");
+        assert_eq!(
+            peek_server_name(&hello),
+            Peek::ServerName("app.example.com".to_string())
+        );
+        assert_eq!(
+            peek_server_name(&hello[..hello.len() - 1]),
+            Peek::Incomplete
+        );
+        assert_eq!(peek_server_name(&hello[..3]), Peek::Incomplete);
+        assert_eq!(peek_server_name(b"GET / HTTP/1.1\r\n"), Peek::None);
+
+        // The same ClientHello split over two records.
+        let body = &hello[5..];
+        let mut split = Vec::new();
+        for part in [&body[..40], &body[40..]] {
+            split.extend_from_slice(&[HANDSHAKE, 3, 1]);
+            split.extend_from_slice(&(part.len() as u16).to_be_bytes());
+            split.extend_from_slice(part);
+        }
+        assert_eq!(
+            peek_server_name(&split),
+            Peek::ServerName("app.example.com".to_string())
+        );
+    }
+
+    #[test]
+    fn test_sni_routes() {
+        let route = |server_name: &str, target: &str| SniRoute {
+            route: "tls".to_string(),
+            server_name: server_name.to_string(),
+            target: target.to_string(),
+        };
+        let routes = SniRoutes::new(vec![
+            route("*.example.com", "10.0.0.1"),
+            route("api.example.com", "10.0.0.2"),
+            route("*.eu.example.com", "10.0.0.3"),
+        ])
+        .unwrap();
+        let lookup = |name| routes.lookup("tls", name).map(|host| host.to_string());
+        assert_eq!(lookup("api.example.com").as_deref(), Some("10.0.0.2"));
+        assert_eq!(lookup("www.example.com").as_deref(), Some("10.0.0.1"));
+        assert_eq!(lookup("www.eu.example.com").as_deref(), Some("10.0.0.3"));
+        assert_eq!(lookup("example.com"), None);
+        assert!(routes.serves("tls"));
+        assert!(!routes.serves("web"));
+    }
+}
@@ -0,0 +1,134 @@
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    net::{IpAddr, SocketAddr},
+};
+use wave_core::NodeId;
+
+/// Client side: addresses in `net` are reached through the node `via`, which
+/// dials them on its network.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SubnetRoute {
+    pub net: IpNet,
+    pub via: NodeId,
+}
+
+/// The subnet routes of a client; the longest matching prefix wins.
+#[derive(Debug, Clone, Default)]
+pub struct SubnetRoutes {
+    routes: Vec<SubnetRoute>,
+}
+
+impl SubnetRoutes {
+    pub fn new(mut routes: Vec<SubnetRoute>) -> Self {
+        routes.sort_by_key(|route| std::cmp::Reverse(route.net.prefix_len()));
+        Self { routes }
+    }
+
+    pub fn lookup(&self, ip: IpAddr) -> Option<NodeId> {
+        let ip = ip.to_canonical();
+        self.routes
+            .iter()
+            .find(|route| route.net.contains(&ip))
+            .map(|route| route.via)
+    }
+
+    pub fn routes(&self) -> &[SubnetRoute] {
+        &self.routes
+    }
+}
+
+/// Server side: a range of this node's network it routes for `peers`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SubnetGrant {
+    pub net: IpNet,
+    pub peers: HashSet<NodeId>,
+}
+
+/// The subnets a node serves. Unlike an exit node, a subnet router only dials
+/// IP addresses inside its grants, and SSRF protection does not apply since
+/// the ranges are named explicitly.
+#[derive(Debug, Clone, Default)]
+pub struct SubnetGrants {
+    grants: Vec<SubnetGrant>,
+}
+
+impl SubnetGrants {
+    pub fn new(grants: Vec<SubnetGrant>) -> Self {
+        Self { grants }
+    }
+
+    /// Whether any subnet is granted to `node_id`.
+    pub fn serves(&self, node_id: &NodeId) -> bool {
+        self.grants
+            .iter()
+            .any(|grant| grant.peers.contains(node_id))
+    }
+
+    pub fn permits(&self, node_id: &NodeId, addr: SocketAddr) -> bool {
+        let ip = addr.ip().to_canonical();
+        self.grants
+            .iter()
+            .any(|grant| grant.net.contains(&ip) && grant.peers.contains(node_id))
+    }
+
+    /// The subnets advertised to `node_id`.
+    pub fn advertised(&self, node_id: &NodeId) -> Vec<IpNet> {
+        self.grants
+            .iter()
+            .filter(|grant| grant.peers.contains(node_id))
+            .map(|grant| grant.net)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(seed: u8) -> NodeId {
+        NodeId(iroh::SecretKey::from_bytes(&[seed; 32]).public())
+    }
+
+    #[test]
+    fn test_longest_prefix() {
+        let routes = SubnetRoutes::new(vec![
+            SubnetRoute {
+                net: "10.0.0.0/8".parse().unwrap(),
+                via: node(1),
+            },
+            SubnetRoute {
+                net: "10.20.0.0/16".parse().unwrap(),
+                via: node(2),
+            },
+        ]);
+        assert_eq!(routes.lookup("10.20.3.4".parse().unwrap()), Some(node(2)));
+        assert_eq!(routes.lookup("10.30.3.4".parse().unwrap()), Some(node(1)));
+        assert_eq!(
+            routes.lookup("::ffff:10.20.3.4".parse().unwrap()),
+            Some(node(2))
+        );
+        assert_eq!(routes.lookup("192.168.1.1".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn test_grants() {
+        let grants = SubnetGrants::new(vec![SubnetGrant {
+            net: "10.20.0.0/16".parse().unwrap(),
+            peers: HashSet::from([node(1)]),
+        }]);
+        let addr = |s: &str| s.parse().unwrap();
+        assert!(grants.permits(&node(1), addr("10.20.3.4:5432")));
+        assert!(grants.permits(&node(1), addr("[::ffff:10.20.3.4]:5432")));
+        assert!(!grants.permits(&node(2), addr("10.20.3.4:5432")));
+        assert!(!grants.permits(&node(1), addr("10.21.3.4:5432")));
+        assert!(grants.serves(&node(1)));
+        assert!(!grants.serves(&node(2)));
+        assert_eq!(
+            grants.advertised(&node(1)),
+            ["10.20.0.0/16".parse().unwrap()]
+        );
+        assert!(grants.advertised(&node(2)).is_empty());
+    }
+}
@@ -1,9 +1,53 @@
-use crate::{client::Client, server::ServerService, ALPN};
-use iroh::Endpoint;
+use crate::{
+    ca::LocalCa,
+    client::{gateway::GatewayConfig, Client},
+    config::{Config, PeerAddr, RelayServer},
+    dns::{DnsServer, FakeIpPool, DEFAULT_FAKE_IPV4, DEFAULT_FAKE_IPV6},
+    endpoint,
+    exit::ExitPolicy,
+    path::{ClientPathPolicy, PathKind, PathPolicy, PathReporter},
+    public_gateway::{PublicGateway, PublicGatewayConfig, RateLimit, TlsFiles},
+    relay_server::SelfHostedRelay,
+    remote_dns::{RemoteDns, RemoteDnsRules},
+    resolver::{DnsResolver, ResolverConfig, SuffixUpstreams},
+    rules::{Action, Rule, RuleTable, Rules},
+    server::{
+        sni::{client_hello, SniRoute, SniRoutes},
+        ServerService, DEFAULT_MAX_CONCURRENT_STREAMS,
+    },
+    subnet::{SubnetGrant, SubnetGrants, SubnetRoute, SubnetRoutes},
+    ALPN,
+};
+use hickory_proto::{
+    op::{Message, MessageType, Query},
+    rr::{
+        rdata::{A, AAAA},
+        Name, RData, Record, RecordType,
+    },
+};
+use iroh::{
+    endpoint::{ConnectionError, PathSelection, TransportConfig},
+    Endpoint, NodeAddr, RelayMap, RelayMode, RelayNode,
+};
 use reqwest::Proxy;
-use std::sync::Arc;
+use rustls::pki_types::{pem::PemObject, CertificateDer};
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, Once,
+    },
+    thread::ThreadId,
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::mpsc,
+};
 use tracing::info;
-use wave_core::{NodeId, Server};
+use wave_core::{server::Fallback, NodeId, Server, WavePacket};
 
 const SERVER_ENDPOINT: &str = "127.0.0.1:8282";
 
@@ -92,3 +136,2095 @@ async fn http_client(node_id: NodeId) -> anyhow::Result<String> {
         .await?;
     Ok(res)
 }
+
+/// Echo backend on an ephemeral port; reports when each connection is accepted.
+async fn echo_app() -> (std::net::SocketAddr, mpsc::UnboundedReceiver<Instant>) {
+    echo_app_on(DOWNSTREAM).await
+}
+
+async fn echo_app_on(host: &str) -> (std::net::SocketAddr, mpsc::UnboundedReceiver<Instant>) {
+    let listener = TcpListener::bind((host, 0)).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tx.send(Instant::now()).ok();
+            tokio::spawn(async move {
+                let (mut read, mut write) = stream.split();
+                tokio::io::copy(&mut read, &mut write).await.ok();
+            });
+        }
+    });
+    (addr, rx)
+}
+
+async fn local_endpoint(alpns: Vec<Vec<u8>>) -> Endpoint {
+    Endpoint::builder()
+        .alpns(alpns)
+        .relay_mode(RelayMode::Disabled)
+        .bind()
+        .await
+        .unwrap()
+}
+
+async fn echo_service(max_concurrent_streams: u32) -> NodeAddr {
+    let mut server = Server::default();
+    server.add("echo".parse().unwrap(), DOWNSTREAM.parse().unwrap());
+    let server_ep = local_endpoint(vec![ALPN.into()]).await;
+    let server_addr = server_ep.node_addr().await.unwrap();
+    let service = ServerService::new(Arc::new(server), server_ep)
+        .with_max_concurrent_streams(max_concurrent_streams);
+    tokio::spawn(service.run());
+    server_addr
+}
+
+#[tokio::test]
+async fn test_streams_share_connection() {
+    let (echo, _) = echo_app().await;
+    let server_addr = echo_service(2).await;
+
+    let client_ep = local_endpoint(vec![]).await;
+    let conn = client_ep.connect(server_addr, ALPN).await.unwrap();
+
+    let requests = (0..5).map(|i| {
+        let conn = conn.clone();
+        async move {
+            let (mut send, mut recv) = conn.open_bi().await.unwrap();
+            let packet = WavePacket::new(echo.port(), "echo".parse().unwrap()).encode();
+            send.write_all(&packet).await.unwrap();
+            send.write_all(format!("stream {i}").as_bytes())
+                .await
+                .unwrap();
+            send.finish().unwrap();
+            recv.read_to_end(64).await.unwrap()
+        }
+    });
+    let requests = requests.map(tokio::spawn).collect::<Vec<_>>();
+    let mut responses = Vec::new();
+    for request in requests {
+        responses.push(request.await.unwrap());
+    }
+
+    for (i, response) in responses.into_iter().enumerate() {
+        assert_eq!(response, format!("stream {i}").as_bytes());
+    }
+}
+
+#[tokio::test]
+async fn test_early_data() {
+    let (echo, mut accepted) = echo_app().await;
+    let server_addr = echo_service(DEFAULT_MAX_CONCURRENT_STREAMS).await;
+    let client_ep = local_endpoint(vec![]).await;
+    let conn = client_ep.connect(server_addr, ALPN).await.unwrap();
+
+    // The backend is dialed on the header alone, while no payload was sent.
+    let (mut send, mut recv) = conn.open_bi().await.unwrap();
+    let packet = WavePacket::new(echo.port(), "echo".parse().unwrap());
+    send.write_all(&packet.encode()).await.unwrap();
+    tokio::time::timeout(Duration::from_secs(5), accepted.recv())
+        .await
+        .expect("backend dialed only after the payload")
+        .unwrap();
+    send.write_all(b"late payload").await.unwrap();
+    send.finish().unwrap();
+    assert_eq!(recv.read_to_end(64).await.unwrap(), b"late payload");
+}
+
+/// A node that reports the first chunk read from every stream opened to it.
+async fn first_chunk_node() -> (Endpoint, mpsc::UnboundedReceiver<bytes::BytesMut>) {
+    let ep = offline_node(&[]).await;
+    let (tx, rx) = mpsc::unbounded_channel();
+    let node = ep.clone();
+    tokio::spawn(async move {
+        while let Some(incoming) = node.accept().await {
+            let conn = incoming.await.unwrap();
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                while let Ok((_send, mut recv)) = conn.accept_bi().await {
+                    let mut chunk = bytes::BytesMut::new();
+                    recv.read_buf(&mut chunk).await.unwrap();
+                    tx.send(chunk).ok();
+                }
+            });
+        }
+    });
+    (ep, rx)
+}
+
+#[tokio::test]
+async fn test_client_coalesces_early_data() {
+    let (node, mut chunks) = first_chunk_node().await;
+    let node_id = NodeId(node.node_id());
+    let client_ep = offline_node(&[&node]).await;
+    let client = Client::new((DOWNSTREAM, 0), client_ep, Arc::default())
+        .await
+        .unwrap();
+    let proxy = client.local_addr().unwrap();
+    tokio::spawn(client.run());
+
+    // Written right after the SOCKS reply, the payload travels with the header.
+    let mut stream = socks_connect(proxy, &format!("echo.{node_id}"), 80).await;
+    stream.write_all(b"ping").await.unwrap();
+    let mut first = chunks.recv().await.unwrap();
+    let packet = WavePacket::decode(&mut first).unwrap().unwrap();
+    assert_eq!(packet.port, 80);
+    assert_eq!(&first[..], b"ping");
+}
+
+#[tokio::test]
+async fn test_client_header_without_early_data() {
+    let (node, mut chunks) = first_chunk_node().await;
+    let node_id = NodeId(node.node_id());
+    let client_ep = offline_node(&[&node]).await;
+    let client = Client::new((DOWNSTREAM, 0), client_ep, Arc::default())
+        .await
+        .unwrap();
+    let proxy = client.local_addr().unwrap();
+    tokio::spawn(client.run());
+
+    // For protocols where the server speaks first the header goes out alone.
+    let _stream = socks_connect(proxy, &format!("echo.{node_id}"), 80).await;
+    let mut first = tokio::time::timeout(Duration::from_secs(5), chunks.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    let packet = WavePacket::decode(&mut first).unwrap().unwrap();
+    assert_eq!(packet.port, 80);
+    assert!(first.is_empty());
+}
+
+async fn hello_app() -> std::net::SocketAddr {
+    let router = axum::Router::new().route("/", axum::routing::get(|| async { "hello world" }));
+    let listener = TcpListener::bind((DOWNSTREAM, 0)).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+    addr
+}
+
+async fn socks_get(proxy: std::net::SocketAddr, url: String) -> anyhow::Result<String> {
+    let http_client = reqwest::Client::builder()
+        .proxy(Proxy::all(format!("socks5h://{}", proxy))?)
+        .timeout(Duration::from_secs(20))
+        .build()?;
+    Ok(http_client.get(url).send().await?.text().await?)
+}
+
+fn offline_config(peers: Vec<PeerAddr>) -> Config {
+    Config {
+        offline: true,
+        peers,
+        endpoint_bind: Some("127.0.0.1:0".parse().unwrap()),
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn test_offline_static_peers() {
+    let app = hello_app().await;
+
+    let server_ep = endpoint::bind(&offline_config(vec![])).await.unwrap();
+    let server_id = NodeId(server_ep.node_id());
+    let peer = PeerAddr {
+        node_id: server_id,
+        addr: server_ep.bound_sockets().0,
+    };
+    let mut server = Server::default();
+    server.add("".parse().unwrap(), DOWNSTREAM.parse().unwrap());
+    tokio::spawn(ServerService::new(Arc::new(server), server_ep).run());
+
+    let client_ep = endpoint::bind(&offline_config(vec![peer])).await.unwrap();
+    let client = Client::new((DOWNSTREAM, 0), client_ep, Arc::default())
+        .await
+        .unwrap();
+    let proxy = client.local_addr().unwrap();
+    tokio::spawn(client.run());
+
+    let res = socks_get(proxy, format!("http://{}:{}", server_id, app.port()))
+        .await
+        .unwrap();
+    assert_eq!(res, "hello world");
+}
+
+#[tokio::test]
+async fn test_self_hosted_relay() {
+    let app = hello_app().await;
+    let (_relay, relay) = local_relay().await;
+    let config = relay_config(&relay);
+    let home_relay = |ep: &Endpoint| {
+        let mut watcher = ep.home_relay();
+        async move {
+            tokio::time::timeout(Duration::from_secs(10), watcher.initialized())
+                .await
+                .unwrap()
+                .unwrap()
+        }
+    };
+
+    let server_ep = endpoint::bind(&config).await.unwrap();
+    assert_eq!(home_relay(&server_ep).await, relay.url);
+    let server_id = NodeId(server_ep.node_id());
+    let mut server = Server::default();
+    server.add("".parse().unwrap(), DOWNSTREAM.parse().unwrap());
+    tokio::spawn(ServerService::new(Arc::new(server), server_ep).run());
+
+    // The client only learns the server's relay, no direct address.
+    let client_ep = endpoint::bind(&config).await.unwrap();
+    assert_eq!(home_relay(&client_ep).await, relay.url);
+    client_ep
+        .add_node_addr(NodeAddr::new(server_id.0).with_relay_url(relay.url))
+        .unwrap();
+    let client = Client::new((DOWNSTREAM, 0), client_ep, Arc::default())
+        .await
+        .unwrap();
+    let proxy = client.local_addr().unwrap();
+    tokio::spawn(client.run());
+
+    let res = socks_get(proxy, format!("http://{}:{}", server_id, app.port()))
+        .await
+        .unwrap();
+    assert_eq!(res, "hello world");
+}
+
+async fn local_relay() -> (SelfHostedRelay, RelayServer) {
+    let relay = SelfHostedRelay::spawn(
+        "127.0.0.1:0".parse().unwrap(),
+        Some("127.0.0.1:0".parse().unwrap()),
+    )
+    .await
+    .unwrap();
+    let config = relay.config().unwrap();
+    (relay, config)
+}
+
+fn relay_config(relay: &RelayServer) -> Config {
+    Config {
+        relays: vec![relay.clone()],
+        endpoint_bind: Some("127.0.0.1:0".parse().unwrap()),
+        ..Default::default()
+    }
+}
+
+/// An endpoint that only ever sends through the relay, so its paths to other
+/// nodes never become direct.
+async fn relay_only_endpoint(relay: &RelayServer, alpns: Vec<Vec<u8>>) -> Endpoint {
+    let relay_map = RelayMap::from_nodes([RelayNode {
+        url: relay.url.clone(),
+        stun_only: false,
+        stun_port: relay.stun_port,
+        quic: None,
+    }])
+    .unwrap();
+    Endpoint::builder()
+        .alpns(alpns)
+        .relay_mode(RelayMode::Custom(relay_map))
+        .path_selection(PathSelection::RelayOnly)
+        .bind()
+        .await
+        .unwrap()
+}
+
+/// How long the direct-only tests wait for a direct path.
+const DIRECT_WAIT: Duration = Duration::from_millis(500);
+
+/// A client whose endpoint only reaches `node_id` through the relay, set up
+/// further by `configure`.
+async fn relayed_client(
+    relay: &RelayServer,
+    client_ep: Endpoint,
+    node_id: NodeId,
+    configure: impl FnOnce(Client) -> Client,
+) -> SocketAddr {
+    client_ep
+        .add_node_addr(NodeAddr::new(node_id.0).with_relay_url(relay.url.clone()))
+        .unwrap();
+    let client = Client::new((DOWNSTREAM, 0), client_ep, Arc::default())
+        .await
+        .unwrap()
+        .with_direct_path_timeout(DIRECT_WAIT);
+    let client = configure(client);
+    let proxy = client.local_addr().unwrap();
+    tokio::spawn(client.run());
+    proxy
+}
+
+/// A backend that counts the connections it accepts.
+async fn counting_backend() -> (SocketAddr, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind((DOWNSTREAM, 0)).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let counter = accepted.clone();
+    tokio::spawn(async move {
+        while listener.accept().await.is_ok() {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+    });
+    (addr, accepted)
+}
+
+/// Asserts that the proxy answers `stream` with the fallback page and closes
+/// it, once the wait for a direct path that started at `since` ran out.
+async fn assert_refused_relay(stream: &mut TcpStream, since: Instant) {
+    let mut response = Vec::new();
+    tokio::time::timeout(Duration::from_secs(10), stream.read_to_end(&mut response))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(response, Fallback::default().bytes());
+    assert!(since.elapsed() >= DIRECT_WAIT);
+}
+
+/// A client using a node as exit node over the relay, which treats the node
+/// as direct only or not.
+async fn relayed_exit_proxy(relay: &RelayServer, direct_only: bool) -> SocketAddr {
+    let client_ep = relay_only_endpoint(relay, vec![]).await;
+    let exit_ep = endpoint::bind(&relay_config(relay)).await.unwrap();
+    let exit_id = NodeId(exit_ep.node_id());
+    let policy = ExitPolicy {
+        peers: HashSet::from([NodeId(client_ep.node_id())]),
+        allow_ips: vec!["127.0.0.0/8".parse().unwrap()],
+        ..Default::default()
+    };
+    tokio::spawn(
+        ServerService::new(Arc::default(), exit_ep)
+            .with_exit_policy(policy)
+            .run(),
+    );
+
+    let mut paths = ClientPathPolicy::default();
+    if direct_only {
+        paths.peers.insert(exit_id, PathPolicy::DirectOnly);
+    }
+    relayed_client(relay, client_ep, exit_id, |client| {
+        client.with_exit_node(exit_id).with_path_policy(paths)
+    })
+    .await
+}
+
+#[tokio::test]
+async fn test_direct_only_exit_node() {
+    let (echo, _) = echo_app().await;
+    let (backend, accepted) = counting_backend().await;
+    let (_relay, relay) = local_relay().await;
+
+    let proxy = relayed_exit_proxy(&relay, false).await;
+    let mut stream = socks_connect_ip(proxy, echo).await;
+    assert_echo(&mut stream).await;
+
+    let proxy = relayed_exit_proxy(&relay, true).await;
+    let started = Instant::now();
+    let mut stream = socks_connect_ip(proxy, backend).await;
+    assert_refused_relay(&mut stream, started).await;
+    assert_eq!(accepted.load(Ordering::Relaxed), 0);
+}
+
+/// A client routing 127.0.0.1 through a node over the relay, which treats the
+/// node as direct only or not.
+async fn relayed_subnet_proxy(relay: &RelayServer, direct_only: bool) -> SocketAddr {
+    let client_ep = relay_only_endpoint(relay, vec![]).await;
+    let router_ep = endpoint::bind(&relay_config(relay)).await.unwrap();
+    let router_id = NodeId(router_ep.node_id());
+    let grants = SubnetGrants::new(vec![SubnetGrant {
+        net: "127.0.0.1/32".parse().unwrap(),
+        peers: HashSet::from([NodeId(client_ep.node_id())]),
+    }]);
+    tokio::spawn(
+        ServerService::new(Arc::default(), router_ep)
+            .with_subnets(grants)
+            .run(),
+    );
+
+    let mut paths = ClientPathPolicy::default();
+    if direct_only {
+        paths.peers.insert(router_id, PathPolicy::DirectOnly);
+    }
+    let routes = SubnetRoutes::new(vec![SubnetRoute {
+        net: "127.0.0.1/32".parse().unwrap(),
+        via: router_id,
+    }]);
+    relayed_client(relay, client_ep, router_id, |client| {
+        client.with_subnet_routes(routes).with_path_policy(paths)
+    })
+    .await
+}
+
+#[tokio::test]
+async fn test_direct_only_subnet_router() {
+    let (echo, _) = echo_app().await;
+    let (backend, accepted) = counting_backend().await;
+    let (_relay, relay) = local_relay().await;
+
+    let proxy = relayed_subnet_proxy(&relay, false).await;
+    let mut stream = socks_connect_ip(proxy, echo).await;
+    assert_echo(&mut stream).await;
+
+    let proxy = relayed_subnet_proxy(&relay, true).await;
+    let started = Instant::now();
+    let mut stream = socks_connect_ip(proxy, backend).await;
+    assert_refused_relay(&mut stream, started).await;
+    assert_eq!(accepted.load(Ordering::Relaxed), 0);
+}
+
+/// A client resolving `localhost` on a node over the relay, which treats the
+/// node as direct only or not.
+async fn relayed_resolver_proxy(relay: &RelayServer, direct_only: bool) -> SocketAddr {
+    let client_ep = relay_only_endpoint(relay, vec![]).await;
+    let resolver_ep = endpoint::bind(&relay_config(relay)).await.unwrap();
+    let resolver_id = NodeId(resolver_ep.node_id());
+    let policy = ExitPolicy {
+        peers: HashSet::from([NodeId(client_ep.node_id())]),
+        allow_ips: vec!["127.0.0.0/8".parse().unwrap(), "::1/128".parse().unwrap()],
+        ..Default::default()
+    };
+    tokio::spawn(
+        ServerService::new(Arc::default(), resolver_ep)
+            .with_exit_policy(policy)
+            .run(),
+    );
+
+    let mut paths = ClientPathPolicy::default();
+    if direct_only {
+        paths.peers.insert(resolver_id, PathPolicy::DirectOnly);
+    }
+    let remote_dns = RemoteDnsRules::new(vec![RemoteDns {
+        suffix: "localhost".to_string(),
+        via: resolver_id,
+    }]);
+    relayed_client(relay, client_ep, resolver_id, |client| {
+        client.with_remote_dns(remote_dns).with_path_policy(paths)
+    })
+    .await
+}
+
+#[tokio::test]
+async fn test_direct_only_remote_resolver() {
+    record_panics();
+    let (echo, _) = echo_app().await;
+    let (backend, accepted) = counting_backend().await;
+    let (_relay, relay) = local_relay().await;
+
+    let proxy = relayed_resolver_proxy(&relay, false).await;
+    let mut stream = socks_connect(proxy, "localhost", echo.port()).await;
+    assert_echo(&mut stream).await;
+
+    let proxy = relayed_resolver_proxy(&relay, true).await;
+    let started = Instant::now();
+    let mut target = vec![0x03, 9];
+    target.extend_from_slice(b"localhost");
+    target.extend_from_slice(&backend.port().to_be_bytes());
+    let (mut stream, status) = socks_request_status(proxy, target).await;
+    assert_eq!(status, 0x01);
+    assert!(started.elapsed() >= DIRECT_WAIT);
+    assert_closed(&mut stream).await;
+    assert_eq!(accepted.load(Ordering::Relaxed), 0);
+    assert!(!task_panicked());
+}
+
+fn secret_server() -> Server {
+    let mut server = Server::default();
+    server.add("".parse().unwrap(), DOWNSTREAM.parse().unwrap());
+    server.add("secret".parse().unwrap(), DOWNSTREAM.parse().unwrap());
+    server
+}
+
+#[tokio::test]
+async fn test_client_direct_only_subdomain() {
+    let app = hello_app().await;
+    let (backend, accepted) = counting_backend().await;
+    let (_relay, relay) = local_relay().await;
+
+    let server_ep = endpoint::bind(&relay_config(&relay)).await.unwrap();
+    let server_id = NodeId(server_ep.node_id());
+    tokio::spawn(ServerService::new(Arc::new(secret_server()), server_ep).run());
+
+    let client_ep = relay_only_endpoint(&relay, vec![]).await;
+    let mut paths = ClientPathPolicy::default();
+    paths
+        .subdomains
+        .insert("secret".to_string(), PathPolicy::DirectOnly);
+    let proxy = relayed_client(&relay, client_ep, server_id, |client| {
+        client.with_path_policy(paths)
+    })
+    .await;
+
+    let res = socks_get(proxy, format!("http://{}:{}", server_id, app.port()))
+        .await
+        .unwrap();
+    assert_eq!(res, "hello world");
+
+    let started = Instant::now();
+    let mut stream = socks_connect(proxy, &format!("secret.{}", server_id), backend.port()).await;
+    assert_refused_relay(&mut stream, started).await;
+    assert_eq!(accepted.load(Ordering::Relaxed), 0);
+}
+
+#[tokio::test]
+async fn test_server_direct_only_route() {
+    let app = hello_app().await;
+    let (backend, accepted) = counting_backend().await;
+    let (_relay, relay) = local_relay().await;
+
+    let server_ep = endpoint::bind(&relay_config(&relay)).await.unwrap();
+    let server_id = NodeId(server_ep.node_id());
+    let route_paths = [("secret".to_string(), PathPolicy::DirectOnly)].into();
+    let service = ServerService::new(Arc::new(secret_server()), server_ep)
+        .with_route_paths(route_paths)
+        .with_direct_path_timeout(DIRECT_WAIT);
+    tokio::spawn(service.run());
+
+    let client_ep = relay_only_endpoint(&relay, vec![]).await;
+    let proxy = relayed_client(&relay, client_ep, server_id, |client| client).await;
+
+    let res = socks_get(proxy, format!("http://{}:{}", server_id, app.port()))
+        .await
+        .unwrap();
+    assert_eq!(res, "hello world");
+
+    let started = Instant::now();
+    let mut stream = socks_connect(proxy, &format!("secret.{}", server_id), backend.port()).await;
+    assert_refused_relay(&mut stream, started).await;
+    assert_eq!(accepted.load(Ordering::Relaxed), 0);
+}
+
+#[tokio::test]
+async fn test_direct_only_over_direct_path() {
+    let app = hello_app().await;
+
+    let server_ep = endpoint::bind(&offline_config(vec![])).await.unwrap();
+    let server_id = NodeId(server_ep.node_id());
+    let peer = PeerAddr {
+        node_id: server_id,
+        addr: server_ep.bound_sockets().0,
+    };
+    let route_paths = [("secret".to_string(), PathPolicy::DirectOnly)].into();
+    let service =
+        ServerService::new(Arc::new(secret_server()), server_ep).with_route_paths(route_paths);
+    tokio::spawn(service.run());
+
+    let client_ep = endpoint::bind(&offline_config(vec![peer])).await.unwrap();
+    let mut paths = ClientPathPolicy::default();
+    paths.peers.insert(server_id, PathPolicy::DirectOnly);
+    let client = Client::new((DOWNSTREAM, 0), client_ep, Arc::default())
+        .await
+        .unwrap()
+        .with_path_policy(paths);
+    let proxy = client.local_addr().unwrap();
+    tokio::spawn(client.run());
+
+    let res = socks_get(proxy, format!("http://secret.{}:{}", server_id, app.port()))
+        .await
+        .unwrap();
+    assert_eq!(res, "hello world");
+}
+
+/// Opens a SOCKS5 CONNECT tunnel to `host:port` through `proxy`.
+async fn socks_connect(proxy: std::net::SocketAddr, host: &str, port: u16) -> TcpStream {
+    let mut target = vec![0x03, host.len() as u8];
+    target.extend_from_slice(host.as_bytes());
+    target.extend_from_slice(&port.to_be_bytes());
+    socks_request(proxy, target).await
+}
+
+/// Opens a SOCKS5 CONNECT tunnel to an IP target, sent as an address rather
+/// than a domain.
+async fn socks_connect_ip(proxy: std::net::SocketAddr, addr: std::net::SocketAddr) -> TcpStream {
+    let mut target = match addr.ip() {
+        std::net::IpAddr::V4(ip) => [&[0x01][..], &ip.octets()].concat(),
+        std::net::IpAddr::V6(ip) => [&[0x04][..], &ip.octets()].concat(),
+    };
+    target.extend_from_slice(&addr.port().to_be_bytes());
+    socks_request(proxy, target).await
+}
+
+async fn socks_request(proxy: std::net::SocketAddr, target: Vec<u8>) -> TcpStream {
+    let (stream, status) = socks_request_status(proxy, target).await;
+    assert_eq!(status, 0x00, "SOCKS connect failed");
+    stream
+}
+
+/// Sends a SOCKS CONNECT and returns the reply code along with the stream.
+async fn socks_request_status(proxy: std::net::SocketAddr, target: Vec<u8>) -> (TcpStream, u8) {
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    stream.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply, [0x05, 0x00]);
+
+    let request = [&[0x05, 0x01, 0x00][..], &target].concat();
+    stream.write_all(&request).await.unwrap();
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await.unwrap();
+    let bound_len = match reply[3] {
+        0x01 => 4 + 2,
+        0x04 => 16 + 2,
+        atyp => panic!("unexpected address type {atyp}"),
+    };
+    let mut bound = vec![0u8; bound_len];
+    stream.read_exact(&mut bound).await.unwrap();
+    (stream, reply[1])
+}
+
+/// Threads a panic started on. Tests run on a current-thread runtime, so the
+/// tasks they spawn panic on the test's thread.
+static PANICKED: Mutex<Vec<ThreadId>> = Mutex::new(Vec::new());
+
+fn record_panics() {
+    static HOOK: Once = Once::new();
+    HOOK.call_once(|| {
+        let default = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            PANICKED.lock().unwrap().push(std::thread::current().id());
+            default(info);
+        }));
+    });
+}
+
+fn task_panicked() -> bool {
+    PANICKED
+        .lock()
+        .unwrap()
+        .contains(&std::thread::current().id())
+}
+
+async fn wait_for_path(reporter: &PathReporter, kind: PathKind) {
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while !reporter.streams().iter().any(|s| s.path.kind == kind) {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn test_path_report() {
+    let (echo, _) = echo_app().await;
+
+    let server_ep = endpoint::bind(&offline_config(vec![])).await.unwrap();
+    let server_id = NodeId(server_ep.node_id());
+    let server_addr = server_ep.bound_sockets().0;
+    let mut server = Server::default();
+    server.add("echo".parse().unwrap(), DOWNSTREAM.parse().unwrap());
+    let service = ServerService::new(Arc::new(server), server_ep);
+    let server_paths = service.path_reporter().clone();
+    tokio::spawn(service.run());
+
+    let peer = PeerAddr {
+        node_id: server_id,
+        addr: server_addr,
+    };
+    let client_ep = endpoint::bind(&offline_config(vec![peer])).await.unwrap();
+    let client_id = NodeId(client_ep.node_id());
+    let client = Client::new((DOWNSTREAM, 0), client_ep, Arc::default())
+        .await
+        .unwrap();
+    let client_paths = client.path_reporter().clone();
+    let proxy = client.local_addr().unwrap();
+    tokio::spawn(client.run());
+
+    let mut stream = socks_connect(proxy, &format!("echo.{}", server_id), echo.port()).await;
+    assert_echo(&mut stream).await;
+
+    wait_for_path(&client_paths, PathKind::Direct).await;
+    let streams = client_paths.streams();
+    assert_eq!(streams.len(), 1);
+    assert_eq!(streams[0].node_id, server_id);
+    assert_eq!(
+        &*streams[0].route,
+        format!("echo.{}:{}", server_id, echo.port())
+    );
+    assert_eq!(streams[0].path.remote_addr, Some(server_addr));
+    assert!(streams[0].rtt > Duration::ZERO);
+
+    wait_for_path(&server_paths, PathKind::Direct).await;
+    let streams = server_paths.streams();
+    assert_eq!(streams.len(), 1);
+    assert_eq!(streams[0].node_id, client_id);
+    assert_eq!(&*streams[0].route, "echo");
+
+    drop(stream);
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while !client_paths.streams().is_empty() || !server_paths.streams().is_empty() {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .unwrap();
+}
+
+async fn assert_echo(stream: &mut TcpStream) {
+    stream.write_all(b"ping").await.unwrap();
+    let mut pong = [0u8; 4];
+    stream.read_exact(&mut pong).await.unwrap();
+    assert_eq!(&pong, b"ping");
+}
+
+#[tokio::test]
+async fn test_ipv6_socks_target() {
+    let (echo, _) = echo_app_on("::1").await;
+
+    let client_ep = endpoint::bind(&offline_config(vec![])).await.unwrap();
+    let client = Client::new((DOWNSTREAM, 0), client_ep, Arc::default())
+        .await
+        .unwrap()
+        .listen("[::1]:0")
+        .await
+        .unwrap();
+    let proxies = client.local_addrs().unwrap();
+    assert!(proxies[0].is_ipv4());
+    assert!(proxies[1].is_ipv6());
+    tokio::spawn(client.run());
+
+    for proxy in proxies {
+        let mut stream = socks_connect_ip(proxy, echo).await;
+        assert_echo(&mut stream).await;
+    }
+}
+
+#[tokio::test]
+async fn test_ipv6_endpoint() {
+    let (echo, _) = echo_app().await;
+    let v6_config = |peers| Config {
+        endpoint_bind_v6: Some("[::1]:0".parse().unwrap()),
+        ..offline_config(peers)
+    };
+
+    let server_ep = endpoint::bind(&v6_config(vec![])).await.unwrap();
+    let server_id = NodeId(server_ep.node_id());
+    let server_addr = server_ep.bound_sockets().1.unwrap();
+    assert!(server_addr.is_ipv6());
+    let mut server = Server::default();
+    server.add("".parse().unwrap(), DOWNSTREAM.parse().unwrap());
+    tokio::spawn(ServerService::new(Arc::new(server), server_ep).run());
+
+    let peer = PeerAddr {
+        node_id: server_id,
+        addr: server_addr,
+    };
+    let client_ep = endpoint::bind(&v6_config(vec![peer])).await.unwrap();
+    let client = Client::new("[::1]:0", client_ep, Arc::default())
+        .await
+        .unwrap();
+    let client_paths = client.path_reporter().clone();
+    let proxy = client.local_addr().unwrap();
+    tokio::spawn(client.run());
+
+    let mut stream = socks_connect(proxy, &server_id.to_string(), echo.port()).await;
+    assert_echo(&mut stream).await;
+    wait_for_path(&client_paths, PathKind::Direct).await;
+    assert_eq!(
+        client_paths.streams()[0].path.remote_addr,
+        Some(server_addr)
+    );
+}
+
+async fn udp_echo_app() -> SocketAddr {
+    let socket = UdpSocket::bind((DOWNSTREAM, 0)).await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; 64 * 1024];
+        while let Ok((n, from)) = socket.recv_from(&mut buf).await {
+            socket.send_to(&buf[..n], from).await.ok();
+        }
+    });
+    addr
+}
+
+/// A server exposing `dns` over TCP and UDP and `web` over TCP only.
+async fn udp_server() -> (NodeId, SocketAddr, PathReporter) {
+    let server_ep = endpoint::bind(&offline_config(vec![])).await.unwrap();
+    let server_id = NodeId(server_ep.node_id());
+    let server_addr = server_ep.bound_sockets().0;
+    let mut server = Server::default();
+    server.add("dns".parse().unwrap(), DOWNSTREAM.parse().unwrap());
+    server.add("web".parse().unwrap(), DOWNSTREAM.parse().unwrap());
+    let service = ServerService::new(Arc::new(server), server_ep)
+        .with_udp_routes(HashSet::from(["dns".to_string()]))
+        .with_udp_idle_timeout(Duration::from_millis(300));
+    let paths = service.path_reporter().clone();
+    tokio::spawn(service.run());
+    (server_id, server_addr, paths)
+}
+
+async fn udp_client(server_id: NodeId, server_addr: SocketAddr) -> Client {
+    let peer = PeerAddr {
+        node_id: server_id,
+        addr: server_addr,
+    };
+    let client_ep = endpoint::bind(&offline_config(vec![peer])).await.unwrap();
+    Client::new((DOWNSTREAM, 0), client_ep, Arc::default())
+        .await
+        .unwrap()
+}
+
+async fn udp_roundtrip(socket: &UdpSocket, payload: &[u8]) -> Vec<u8> {
+    socket.send(payload).await.unwrap();
+    let mut buf = vec![0u8; 64 * 1024];
+    let n = tokio::time::timeout(Duration::from_secs(5), socket.recv(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    buf.truncate(n);
+    buf
+}
+
+#[tokio::test]
+async fn test_udp_forward() {
+    let echo = udp_echo_app().await;
+    let (server_id, server_addr, server_paths) = udp_server().await;
+    let client = udp_client(server_id, server_addr).await;
+    let target = format!("dns.{}:{}", server_id, echo.port())
+        .parse()
+        .unwrap();
+    let forward = client
+        .udp_forward("127.0.0.1:0".parse().unwrap(), target)
+        .await
+        .unwrap();
+    let forward_addr = forward.local_addr().unwrap();
+    tokio::spawn(forward.run());
+
+    // A flow per local sender; the large payload does not fit a datagram.
+    let senders = [
+        UdpSocket::bind((DOWNSTREAM, 0)).await.unwrap(),
+        UdpSocket::bind((DOWNSTREAM, 0)).await.unwrap(),
+    ];
+    for socket in &senders {
+        socket.connect(forward_addr).await.unwrap();
+        for payload in [&b"query"[..], &[7; 20_000]] {
+            assert_eq!(udp_roundtrip(socket, payload).await, payload);
+        }
+    }
+    assert_eq!(server_paths.streams().len(), 2);
+
+    // Flows close once idle.
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while !server_paths.streams().is_empty() {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(udp_roundtrip(&senders[0], b"again").await, b"again");
+}
+
+#[tokio::test]
+async fn test_udp_route_required() {
+    let echo = udp_echo_app().await;
+    let (server_id, server_addr, _) = udp_server().await;
+    let client = udp_client(server_id, server_addr).await;
+    let target = format!("web.{}:{}", server_id, echo.port())
+        .parse()
+        .unwrap();
+    let forward = client
+        .udp_forward("127.0.0.1:0".parse().unwrap(), target)
+        .await
+        .unwrap();
+    let forward_addr = forward.local_addr().unwrap();
+    tokio::spawn(forward.run());
+
+    let socket = UdpSocket::bind((DOWNSTREAM, 0)).await.unwrap();
+    socket.send_to(b"query", forward_addr).await.unwrap();
+    let mut buf = [0u8; 16];
+    let received = tokio::time::timeout(Duration::from_millis(500), socket.recv(&mut buf)).await;
+    assert!(received.is_err());
+
+    let not_wave = format!("{}", echo).parse().unwrap();
+    assert!(client
+        .udp_forward("127.0.0.1:0".parse().unwrap(), not_wave)
+        .await
+        .is_err());
+}
+
+/// Requests a UDP ASSOCIATE of `proxy`, returning the control connection that
+/// keeps it open and a socket connected to its relay.
+async fn udp_associate(proxy: SocketAddr) -> (TcpStream, UdpSocket) {
+    let mut control = TcpStream::connect(proxy).await.unwrap();
+    control.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+    let mut reply = [0u8; 2];
+    control.read_exact(&mut reply).await.unwrap();
+    control
+        .write_all(&[0x05, 0x03, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+        .await
+        .unwrap();
+    let mut reply = [0u8; 10];
+    control.read_exact(&mut reply).await.unwrap();
+    assert_eq!(&reply[..4], &[0x05, 0x00, 0x00, 0x01]);
+    let relay = SocketAddr::from((
+        [reply[4], reply[5], reply[6], reply[7]],
+        u16::from_be_bytes([reply[8], reply[9]]),
+    ));
+
+    let socket = UdpSocket::bind((DOWNSTREAM, 0)).await.unwrap();
+    socket.connect(relay).await.unwrap();
+    (control, socket)
+}
+
+/// The SOCKS5 UDP header for a datagram to `host:port`.
+fn udp_domain_header(host: &str, port: u16) -> Vec<u8> {
+    let mut header = vec![0, 0, 0, 0x03, host.len() as u8];
+    header.extend_from_slice(host.as_bytes());
+    header.extend_from_slice(&port.to_be_bytes());
+    header
+}
+
+#[tokio::test]
+async fn test_socks_udp_associate() {
+    let echo = udp_echo_app().await;
+    let (server_id, server_addr, _) = udp_server().await;
+    let client = udp_client(server_id, server_addr).await;
+    let proxy = client.local_addr().unwrap();
+    tokio::spawn(client.run());
+
+    let (_control, socket) = udp_associate(proxy).await;
+    let wave_header = udp_domain_header(&format!("dns.{}", server_id), echo.port());
+    let mut direct_header = vec![0, 0, 0, 0x01, 127, 0, 0, 1];
+    direct_header.extend_from_slice(&echo.port().to_be_bytes());
+
+    for header in [wave_header, direct_header] {
+        let request = [&header[..], b"query"].concat();
+        assert_eq!(udp_roundtrip(&socket, &request).await, request);
+    }
+}
+
+#[tokio::test]
+async fn test_socks_udp_associate_resolver() {
+    let echo = udp_echo_app().await;
+    let upstream = upstream_dns(std::net::Ipv4Addr::LOCALHOST).await;
+    let resolver = DnsResolver::new(&ResolverConfig {
+        upstreams: Vec::new(),
+        suffixes: vec![SuffixUpstreams {
+            suffix: "corp.test".to_string(),
+            upstreams: vec![upstream],
+        }],
+    });
+    let client_ep = offline_node(&[]).await;
+    let client = Client::new((DOWNSTREAM, 0), client_ep, Arc::default())
+        .await
+        .unwrap()
+        .with_resolver(Arc::new(resolver));
+    let proxy = client.local_addr().unwrap();
+    tokio::spawn(client.run());
+
+    // Only the configured upstream knows the name.
+    let (_control, socket) = udp_associate(proxy).await;
+    let request = [
+        &udp_domain_header("echo.corp.test", echo.port())[..],
+        b"query",
+    ]
+    .concat();
+    assert_eq!(udp_roundtrip(&socket, &request).await, request);
+}
+
+/// A SOCKS proxy whose client uses an exit node with the policy `policy` builds
+/// from the client's node id.
+async fn exit_proxy(policy: impl FnOnce(NodeId) -> ExitPolicy) -> SocketAddr {
+    let exit_ep = endpoint::bind(&offline_config(vec![])).await.unwrap();
+    let exit_id = NodeId(exit_ep.node_id());
+    let peer = PeerAddr {
+        node_id: exit_id,
+        addr: exit_ep.bound_sockets().0,
+    };
+    let client_ep = endpoint::bind(&offline_config(vec![peer])).await.unwrap();
+    let client_id = NodeId(client_ep.node_id());
+
+    let service = ServerService::new(Arc::default(), exit_ep).with_exit_policy(policy(client_id));
+    tokio::spawn(service.run());
+
+    let client = Client::new((DOWNSTREAM, 0), client_ep, Arc::default())
+        .await
+        .unwrap()
+        .with_exit_node(exit_id);
+    let proxy = client.local_addr().unwrap();
+    tokio::spawn(client.run());
+    proxy
+}
+
+async fn assert_closed(stream: &mut TcpStream) {
+    stream.write_all(b"ping").await.ok();
+    let mut buf = [0u8; 4];
+    let read = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf))
+        .await
+        .unwrap();
+    assert!(matches!(read, Ok(0) | Err(_)));
+}
+
+#[tokio::test]
+async fn test_exit_node() {
+    let (echo, _) = echo_app().await;
+    let proxy = exit_proxy(|client_id| ExitPolicy {
+        peers: HashSet::from([client_id]),
+        allow_ips: vec!["127.0.0.0/8".parse().unwrap()],
+        ..Default::default()
+    })
+    .await;
+
+    let mut stream = socks_connect_ip(proxy, echo).await;
+    assert_echo(&mut stream).await;
+    let mut stream = socks_connect(proxy, "localhost", echo.port()).await;
+    assert_echo(&mut stream).await;
+
+    // Outside `allow_ips`.
+    let mut stream = socks_connect_ip(proxy, "[::1]:80".parse().unwrap()).await;
+    assert_closed(&mut stream).await;
+}
+
+#[tokio::test]
+async fn test_exit_node_ssrf_protection() {
+    let (echo, _) = echo_app().await;
+    let proxy = exit_proxy(|client_id| ExitPolicy {
+        peers: HashSet::from([client_id]),
+        ..Default::default()
+    })
+    .await;
+
+    let mut stream = socks_connect_ip(proxy, echo).await;
+    assert_closed(&mut stream).await;
+    let mut stream = socks_connect(proxy, "localhost", echo.port()).await;
+    assert_closed(&mut stream).await;
+}
+
+#[tokio::test]
+async fn test_exit_node_requires_listed_peer() {
+    let (echo, _) = echo_app().await;
+    let proxy = exit_proxy(|_| ExitPolicy {
+        allow_ips: vec!["127.0.0.0/8".parse().unwrap()],
+        ..Default::default()
+    })
+    .await;
+
+    let mut stream = socks_connect_ip(proxy, echo).await;
+    assert_closed(&mut stream).await;
+}
+
+#[tokio::test]
+async fn test_subnet_routing() {
+    let (granted, _) = echo_app_on("127.0.0.1").await;
+    let (ungranted, _) = echo_app_on("127.0.0.2").await;
+
+    let router_ep = endpoint::bind(&offline_config(vec![])).await.unwrap();
+    let router_id = NodeId(router_ep.node_id());
+    let peer = PeerAddr {
+        node_id: router_id,
+        addr: router_ep.bound_sockets().0,
+    };
+    let client_ep = endpoint::bind(&offline_config(vec![peer])).await.unwrap();
+    let grants = SubnetGrants::new(vec![SubnetGrant {
+        net: "127.0.0.1/32".parse().unwrap(),
+        peers: HashSet::from([NodeId(client_ep.node_id())]),
+    }]);
+    let service = ServerService::new(Arc::default(), router_ep).with_subnets(grants);
+    let path_reporter = service.path_reporter().clone();
+    tokio::spawn(service.run());
+
+    let client = Client::new((DOWNSTREAM, 0), client_ep, Arc::default())
+        .await
+        .unwrap()
+        .with_subnet_routes(SubnetRoutes::new(vec![SubnetRoute {
+            net: "127.0.0.0/8".parse().unwrap(),
+            via: router_id,
+        }]));
+    let advertised = client.advertised_subnets(router_id).await.unwrap();
+    assert_eq!(advertised, ["127.0.0.1/32".parse().unwrap()]);
+    let proxy = client.local_addr().unwrap();
+    tokio::spawn(client.run());
+
+    let mut stream = socks_connect_ip(proxy, granted).await;
+    assert_echo(&mut stream).await;
+    let routed = format!("subnet:{}", granted);
+    assert!(path_reporter
+        .streams()
+        .iter()
+        .any(|path| *path.route == *routed));
+
+    // Routed to the node, which does not grant it.
+    let mut stream = socks_connect_ip(proxy, ungranted).await;
+    assert_closed(&mut stream).await;
+}
+
+#[tokio::test]
+async fn test_remote_dns() {
+    record_panics();
+    let (echo, _) = echo_app().await;
+
+    let exit_ep = endpoint::bind(&offline_config(vec![])).await.unwrap();
+    let exit_id = NodeId(exit_ep.node_id());
+    let peer = PeerAddr {
+        node_id: exit_id,
+        addr: exit_ep.bound_sockets().0,
+    };
+    let client_ep = endpoint::bind(&offline_config(vec![peer])).await.unwrap();
+    let policy = ExitPolicy {
+        peers: HashSet::from([NodeId(client_ep.node_id())]),
+        allow_ips: vec!["127.0.0.0/8".parse().unwrap(), "::1/128".parse().unwrap()],
+        ..Default::default()
+    };
+    let service = ServerService::new(Arc::default(), exit_ep).with_exit_policy(policy);
+    tokio::spawn(service.run());
+
+    let client = Client::new((DOWNSTREAM, 0), client_ep, Arc::default())
+        .await
+        .unwrap()
+        .with_remote_dns(RemoteDnsRules::new(vec![
+            RemoteDns {
+                suffix: "localhost".to_string(),
+                via: exit_id,
+            },
+            RemoteDns {
+                suffix: "invalid".to_string(),
+                via: exit_id,
+            },
+        ]));
+    let proxy = client.local_addr().unwrap();
+    tokio::spawn(client.run());
+
+    let mut stream = socks_connect(proxy, "localhost", echo.port()).await;
+    assert_echo(&mut stream).await;
+
+    // Resolution and policy failures come back as the SOCKS reply.
+    let mut target = vec![0x03, 12];
+    target.extend_from_slice(b"name.invalid");
+    target.extend_from_slice(&80u16.to_be_bytes());
+    let (mut stream, status) = socks_request_status(proxy, target).await;
+    assert_eq!(status, 0x04);
+    assert_closed(&mut stream).await;
+    assert!(!task_panicked());
+}
+
+#[tokio::test]
+async fn test_server_survives_idle_timeout() {
+    record_panics();
+    let server_ep = offline_node(&[]).await;
+    let server_id = server_ep.node_id();
+    let server_addr = server_ep.bound_sockets().0;
+    tokio::spawn(ServerService::new(Arc::default(), server_ep).run());
+
+    // Neither side sends keep-alives, so the connection times out on both.
+    let mut transport = TransportConfig::default();
+    transport.max_idle_timeout(Some(Duration::from_millis(300).try_into().unwrap()));
+    let client_ep = Endpoint::builder()
+        .transport_config(transport)
+        .relay_mode(RelayMode::Disabled)
+        .bind()
+        .await
+        .unwrap();
+    let server = NodeAddr::new(server_id).with_direct_addresses([server_addr]);
+    let conn = client_ep.connect(server, ALPN).await.unwrap();
+    let closed = tokio::time::timeout(Duration::from_secs(5), conn.closed())
+        .await
+        .unwrap();
+    assert!(matches!(closed, ConnectionError::TimedOut));
+
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(!task_panicked());
+}
+
+/// An offline endpoint that knows the direct addresses of `peers`.
+async fn offline_node(peers: &[&Endpoint]) -> Endpoint {
+    let ep = endpoint::bind(&offline_config(vec![])).await.unwrap();
+    for peer in peers {
+        ep.add_node_addr(
+            NodeAddr::new(peer.node_id()).with_direct_addresses([peer.bound_sockets().0]),
+        )
+        .unwrap();
+    }
+    ep
+}
+
+/// Sends a stream from a client through a bastion to the final node of a
+/// multi-hop route, which trusts the bastion as a forwarder or not. Returns
+/// the ids of the bastion and the client, and the paths the final node saw.
+async fn multi_hop_streams(trust_bastion: bool) -> (NodeId, NodeId, PathReporter) {
+    let (echo, _) = echo_app().await;
+
+    let final_ep = offline_node(&[]).await;
+    let final_id = NodeId(final_ep.node_id());
+    let bastion_ep = offline_node(&[&final_ep]).await;
+    let bastion_id = NodeId(bastion_ep.node_id());
+    let client_ep = offline_node(&[&bastion_ep]).await;
+    let client_id = NodeId(client_ep.node_id());
+
+    let final_server =
+        Server::try_from_iter([("app".to_string(), DOWNSTREAM.to_string())]).unwrap();
+    let mut final_service = ServerService::new(Arc::new(final_server), final_ep);
+    if trust_bastion {
+        final_service = final_service.with_forwarders(HashSet::from([bastion_id]));
+    }
+    let final_paths = final_service.path_reporter().clone();
+    tokio::spawn(final_service.run());
+    let bastion_server = Server::try_from_iter([(
+        "db".to_string(),
+        format!("app.{}:{}", final_id, echo.port()),
+    )])
+    .unwrap();
+    tokio::spawn(ServerService::new(Arc::new(bastion_server), bastion_ep).run());
+
+    let client = Client::new((DOWNSTREAM, 0), client_ep, Arc::default())
+        .await
+        .unwrap();
+    let proxy = client.local_addr().unwrap();
+    tokio::spawn(client.run());
+
+    let mut stream = socks_connect(proxy, &format!("db.{}", bastion_id), 1).await;
+    assert_echo(&mut stream).await;
+    (bastion_id, client_id, final_paths)
+}
+
+#[tokio::test]
+async fn test_multi_hop_route() {
+    let (bastion_id, client_id, final_paths) = multi_hop_streams(true).await;
+    let streams = final_paths.streams();
+    assert_eq!(streams.len(), 1);
+    assert_eq!(streams[0].node_id, bastion_id);
+    assert_eq!(streams[0].origin, Some(client_id));
+}
+
+#[tokio::test]
+async fn test_forwarded_from_non_forwarder() {
+    // The bastion could claim any origin, it is only known as the sender.
+    let (bastion_id, _, final_paths) = multi_hop_streams(false).await;
+    let streams = final_paths.streams();
+    assert_eq!(streams.len(), 1);
+    assert_eq!(streams[0].node_id, bastion_id);
+    assert_eq!(streams[0].origin, None);
+}
+
+#[tokio::test]
+async fn test_multi_hop_loop() {
+    let a_ep = offline_node(&[]).await;
+    let b_ep = offline_node(&[&a_ep]).await;
+    a_ep.add_node_addr(
+        NodeAddr::new(b_ep.node_id()).with_direct_addresses([b_ep.bound_sockets().0]),
+    )
+    .unwrap();
+    let client_ep = offline_node(&[&a_ep]).await;
+    let (a_id, b_id) = (NodeId(a_ep.node_id()), NodeId(b_ep.node_id()));
+
+    let a_server = Server::try_from_iter([("x".to_string(), format!("x.{}", b_id))]).unwrap();
+    let b_server = Server::try_from_iter([("x".to_string(), format!("x.{}", a_id))]).unwrap();
+    tokio::spawn(ServerService::new(Arc::new(a_server), a_ep).run());
+    tokio::spawn(ServerService::new(Arc::new(b_server), b_ep).run());
+
+    let client = Client::new((DOWNSTREAM, 0), client_ep, Arc::default())
+        .await
+        .unwrap();
+    let proxy = client.local_addr().unwrap();
+    tokio::spawn(client.run());
+
+    let mut stream = socks_connect(proxy, &format!("x.{}", a_id), 80).await;
+    assert_closed(&mut stream).await;
+}
+
+async fn dns_query(server: SocketAddr, name: &str, record_type: RecordType) -> Message {
+    let mut query = Message::new();
+    query
+        .set_id(7)
+        .set_recursion_desired(true)
+        .add_query(Query::query(Name::from_ascii(name).unwrap(), record_type));
+    let socket = UdpSocket::bind((DOWNSTREAM, 0)).await.unwrap();
+    socket
+        .send_to(&query.to_vec().unwrap(), server)
+        .await
+        .unwrap();
+    let mut buf = vec![0u8; 4096];
+    let n = tokio::time::timeout(Duration::from_secs(5), socket.recv(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    let reply = Message::from_vec(&buf[..n]).unwrap();
+    assert_eq!(reply.id(), 7);
+    reply
+}
+
+/// A stand-in upstream DNS server that answers every A query with `ip`.
+async fn upstream_dns(ip: std::net::Ipv4Addr) -> SocketAddr {
+    let socket = UdpSocket::bind((DOWNSTREAM, 0)).await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; 4096];
+        while let Ok((n, from)) = socket.recv_from(&mut buf).await {
+            let request = Message::from_vec(&buf[..n]).unwrap();
+            let mut reply = request.clone();
+            reply.set_message_type(MessageType::Response);
+            let name = request.queries()[0].name().clone();
+            reply.add_answer(Record::from_rdata(name, 300, RData::A(A(ip))));
+            socket
+                .send_to(&reply.to_vec().unwrap(), from)
+                .await
+                .unwrap();
+        }
+    });
+    addr
+}
+
+fn answer_ip(reply: &Message) -> IpAddr {
+    match reply.answers()[0].data() {
+        RData::A(A(ip)) => IpAddr::V4(*ip),
+        RData::AAAA(AAAA(ip)) => IpAddr::V6(*ip),
+        data => panic!("unexpected answer {data:?}"),
+    }
+}
+
+#[tokio::test]
+async fn test_fake_ip_dns() {
+    let (echo, _) = echo_app().await;
+    let server_ep = offline_node(&[]).await;
+    let server_id = NodeId(server_ep.node_id());
+    let client_ep = offline_node(&[&server_ep]).await;
+    let server = Server::try_from_iter([("web".to_string(), DOWNSTREAM.to_string())]).unwrap();
+    tokio::spawn(ServerService::new(Arc::new(server), server_ep).run());
+
+    let pool = Arc::new(
+        FakeIpPool::new(
+            DEFAULT_FAKE_IPV4.parse().unwrap(),
+            DEFAULT_FAKE_IPV6.parse().unwrap(),
+        )
+        .unwrap(),
+    );
+    let upstream = upstream_dns("192.0.2.1".parse().unwrap()).await;
+    let dns = DnsServer::bind("127.0.0.1:0".parse().unwrap(), upstream, pool.clone())
+        .await
+        .unwrap();
+    let dns_addr = dns.local_addr().unwrap();
+    tokio::spawn(dns.run());
+    let client = Client::new((DOWNSTREAM, 0), client_ep, Arc::default())
+        .await
+        .unwrap()
+        .with_fake_ips(pool.clone());
+    let proxy = client.local_addr().unwrap();
+    tokio::spawn(client.run());
+
+    let name = format!("web.{}.", server_id);
+    let fake_v4 = answer_ip(&dns_query(dns_addr, &name, RecordType::A).await);
+    let fake_v6 = answer_ip(&dns_query(dns_addr, &name, RecordType::AAAA).await);
+    assert!(pool.contains(fake_v4) && pool.contains(fake_v6));
+    let reply = dns_query(dns_addr, &name, RecordType::MX).await;
+    assert!(reply.answers().is_empty());
+    let reply = dns_query(dns_addr, "example.com.", RecordType::A).await;
+    assert_eq!(answer_ip(&reply), "192.0.2.1".parse::<IpAddr>().unwrap());
+
+    let mut stream = socks_connect_ip(proxy, SocketAddr::new(fake_v4, echo.port())).await;
+    assert_echo(&mut stream).await;
+    let mut stream = socks_connect_ip(proxy, SocketAddr::new(fake_v6, echo.port())).await;
+    assert_echo(&mut stream).await;
+    // Addresses of the pool that were never handed out are not dialed.
+    let unused = SocketAddr::new("198.19.255.254".parse().unwrap(), echo.port());
+    let mut stream = socks_connect_ip(proxy, unused).await;
+    stream.write_all(b"ping").await.unwrap();
+    let mut reply = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut reply))
+        .await
+        .unwrap()
+        .ok();
+    assert_eq!(reply, Fallback::default().bytes());
+}
+
+#[tokio::test]
+async fn test_pac_file() {
+    let via_ep = offline_node(&[]).await;
+    let via = NodeId(via_ep.node_id());
+    let client_ep = offline_node(&[&via_ep]).await;
+    let client = Client::new((DOWNSTREAM, 0), client_ep, Arc::default())
+        .await
+        .unwrap()
+        .with_subnet_routes(SubnetRoutes::new(vec![SubnetRoute {
+            net: "10.20.0.0/16".parse().unwrap(),
+            via,
+        }]))
+        .with_remote_dns(RemoteDnsRules::new(vec![RemoteDns {
+            suffix: "Corp.Internal.".to_string(),
+            via,
+        }]));
+    let proxy = client.local_addr().unwrap();
+    let pac = client
+        .pac_server("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let pac_addr = pac.local_addr().unwrap();
+    tokio::spawn(pac.run());
+
+    let http_client = reqwest::Client::builder().no_proxy().build().unwrap();
+    let res = http_client
+        .get(format!("http://{}/proxy.pac", pac_addr))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(
+        res.headers()["content-type"],
+        "application/x-ns-proxy-autoconfig"
+    );
+    let script = res.text().await.unwrap();
+    assert!(script.contains(&format!("SOCKS5 {}", proxy)));
+    // Any node id matches, not only those of nodes the client has met.
+    assert!(script.contains("/(^|\\.)[0-9a-v]{52}$/.test(host)"));
+    assert!(script.contains("dnsDomainIs(host, \".corp.internal\")"));
+    assert!(script.contains("isInNet(host, \"10.20.0.0\", \"255.255.0.0\")"));
+    assert!(script.contains("return \"DIRECT\";"));
+
+    let res = http_client
+        .get(format!("http://{}/other", pac_addr))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), reqwest::StatusCode::NOT_FOUND);
+}
+
+/// Answers a WebSocket upgrade, then echoes.
+async fn upgrade_app() -> SocketAddr {
+    let listener = TcpListener::bind((DOWNSTREAM, 0)).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut head = Vec::new();
+                while !head.ends_with(b"\r\n\r\n") {
+                    head.push(stream.read_u8().await.unwrap());
+                }
+                assert!(String::from_utf8(head)
+                    .unwrap()
+                    .contains("Upgrade: websocket"));
+                stream
+                    .write_all(b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n")
+                    .await
+                    .unwrap();
+                let (mut read, mut write) = stream.split();
+                tokio::io::copy(&mut read, &mut write).await.ok();
+            });
+        }
+    });
+    addr
+}
+
+async fn read_until(stream: &mut TcpStream, needle: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    while !buf.windows(needle.len()).any(|w| w == needle) {
+        let n = tokio::time::timeout(Duration::from_secs(10), stream.read_buf(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_ne!(n, 0, "closed before {:?}", String::from_utf8_lossy(needle));
+    }
+    buf
+}
+
+#[tokio::test]
+async fn test_http_gateway() {
+    let hello = hello_app().await;
+    let ws = upgrade_app().await;
+    let server_ep = offline_node(&[]).await;
+    let server_id = NodeId(server_ep.node_id());
+    let client_ep = offline_node(&[&server_ep]).await;
+    let server = Server::try_from_iter([("web".to_string(), DOWNSTREAM.to_string())]).unwrap();
+    tokio::spawn(ServerService::new(Arc::new(server), server_ep).run());
+
+    let gateway = |port| GatewayConfig {
+        bind: "127.0.0.1:0".parse().unwrap(),
+        port,
+        tls: false,
+    };
+    let client = Client::new((DOWNSTREAM, 0), client_ep.clone(), Arc::default())
+        .await
+        .unwrap()
+        .with_http_gateway(&gateway(hello.port()), None)
+        .await
+        .unwrap();
+    let hello_gateway = client.gateway_addr().unwrap().unwrap();
+    tokio::spawn(client.run());
+    let client = Client::new((DOWNSTREAM, 0), client_ep, Arc::default())
+        .await
+        .unwrap()
+        .with_http_gateway(&gateway(ws.port()), None)
+        .await
+        .unwrap();
+    let ws_gateway = client.gateway_addr().unwrap().unwrap();
+    tokio::spawn(client.run());
+
+    // Two requests on one keep-alive connection.
+    let host = format!("web.{}.localhost:{}", server_id, hello_gateway.port());
+    let request = format!("GET / HTTP/1.1\r\nHost: {host}\r\n\r\n");
+    let mut stream = TcpStream::connect(hello_gateway).await.unwrap();
+    for _ in 0..2 {
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let reply = read_until(&mut stream, b"hello world").await;
+        assert!(reply.starts_with(b"HTTP/1.1 200 OK"));
+    }
+
+    let mut stream = TcpStream::connect(ws_gateway).await.unwrap();
+    let request = format!(
+        "GET /chat HTTP/1.1\r\nHost: web.{server_id}.localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n"
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+    read_until(&mut stream, b"101 Switching Protocols").await;
+    stream.write_all(b"frame").await.unwrap();
+    read_until(&mut stream, b"frame").await;
+
+    // Other hosts are not dialed.
+    let mut stream = TcpStream::connect(hello_gateway).await.unwrap();
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n")
+        .await
+        .unwrap();
+    read_until(&mut stream, b"404 Not Found").await;
+}
+
+/// A TLS client trusting only `root`.
+fn tls_connector(root: CertificateDer<'static>) -> tokio_rustls::TlsConnector {
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(root).unwrap();
+    let tls = rustls::ClientConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .unwrap()
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+    tokio_rustls::TlsConnector::from(Arc::new(tls))
+}
+
+#[tokio::test]
+async fn test_https_gateway() {
+    let hello = hello_app().await;
+    let server_ep = offline_node(&[]).await;
+    let server_id = NodeId(server_ep.node_id());
+    let client_ep = offline_node(&[&server_ep]).await;
+    let server = Server::try_from_iter([("web".to_string(), DOWNSTREAM.to_string())]).unwrap();
+    tokio::spawn(ServerService::new(Arc::new(server), server_ep).run());
+
+    let ca = Arc::new(LocalCa::generate().unwrap());
+    let config = GatewayConfig {
+        bind: "127.0.0.1:0".parse().unwrap(),
+        port: hello.port(),
+        tls: true,
+    };
+    let client = Client::new((DOWNSTREAM, 0), client_ep, Arc::default())
+        .await
+        .unwrap()
+        .with_http_gateway(&config, Some(ca.clone()))
+        .await
+        .unwrap();
+    let gateway = client.gateway_addr().unwrap().unwrap();
+    tokio::spawn(client.run());
+
+    let connector =
+        tls_connector(CertificateDer::from_pem_slice(ca.cert_pem().as_bytes()).unwrap());
+
+    let host = format!("web.{server_id}.localhost");
+    let stream = TcpStream::connect(gateway).await.unwrap();
+    let mut stream = connector
+        .connect(host.clone().try_into().unwrap(), stream)
+        .await
+        .unwrap();
+    stream
+        .write_all(
+            format!("GET / HTTP/1.1\r\nHost: {host}\r\nConnection: close\r\n\r\n").as_bytes(),
+        )
+        .await
+        .unwrap();
+    let mut reply = Vec::new();
+    stream.read_to_end(&mut reply).await.ok();
+    assert!(reply.starts_with(b"HTTP/1.1 200 OK"));
+    assert!(reply.ends_with(b"hello world"));
+
+    // No certificate for names outside wave.
+    let stream = TcpStream::connect(gateway).await.unwrap();
+    let res = connector
+        .connect("example.com".try_into().unwrap(), stream)
+        .await;
+    assert!(res.is_err());
+}
+
+/// A TLS backend on `ip:port` that answers with its address, followed by the
+/// first record it received.
+async fn sni_backend(ip: &'static str, port: u16) -> Option<u16> {
+    let listener = TcpListener::bind((ip, port)).await.ok()?;
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut record = vec![0; 5];
+                stream.read_exact(&mut record).await?;
+                let len = u16::from_be_bytes([record[3], record[4]]) as usize;
+                record.resize(5 + len, 0);
+                stream.read_exact(&mut record[5..]).await?;
+                stream.write_all(ip.as_bytes()).await?;
+                stream.write_all(&record).await
+            });
+        }
+    });
+    Some(port)
+}
+
+async fn tls_roundtrip(proxy: SocketAddr, host: &str, port: u16, server_name: &str) -> Vec<u8> {
+    let hello = client_hello(server_name);
+    let mut stream = socks_connect(proxy, host, port).await;
+    stream.write_all(&hello).await.unwrap();
+    let mut reply = Vec::new();
+    stream.read_to_end(&mut reply).await.unwrap();
+    let backend = reply
+        .strip_suffix(hello.as_slice())
+        .expect("ClientHello passed through");
+    backend.to_vec()
+}
+
+#[tokio::test]
+async fn test_sni_routing() {
+    // Two backends on one port, told apart by their loopback address.
+    let port = loop {
+        let port = sni_backend("127.0.0.2", 0).await.unwrap();
+        if sni_backend("127.0.0.1", port).await.is_some() {
+            break port;
+        }
+    };
+    let server_ep = offline_node(&[]).await;
+    let server_id = NodeId(server_ep.node_id());
+    let client_ep = offline_node(&[&server_ep]).await;
+    let server = Server::try_from_iter([("tls".to_string(), DOWNSTREAM.to_string())]).unwrap();
+    let sni_routes = SniRoutes::new(vec![SniRoute {
+        route: "tls".to_string(),
+        server_name: "*.example.com".to_string(),
+        target: "127.0.0.2".to_string(),
+    }])
+    .unwrap();
+    tokio::spawn(
+        ServerService::new(Arc::new(server), server_ep)
+            .with_sni_routes(sni_routes)
+            .run(),
+    );
+    let client = Client::new((DOWNSTREAM, 0), client_ep, Arc::default())
+        .await
+        .unwrap();
+    let proxy = client.local_addr().unwrap();
+    tokio::spawn(client.run());
+
+    let host = format!("tls.{}", server_id);
+    let backend = tls_roundtrip(proxy, &host, port, "api.example.com").await;
+    assert_eq!(backend, b"127.0.0.2");
+    // Names without an SNI route keep the route's target.
+    let backend = tls_roundtrip(proxy, &host, port, "other.test").await;
+    assert_eq!(backend, b"127.0.0.1");
+}
+
+async fn gateway_get(
+    gateway: SocketAddr,
+    host: &str,
+    tls: Option<&tokio_rustls::TlsConnector>,
+) -> String {
+    let request = format!("GET / HTTP/1.1\r\nHost: {host}\r\n\r\n");
+    let stream = TcpStream::connect(gateway).await.unwrap();
+    let mut reply = Vec::new();
+    match tls {
+        Some(connector) => {
+            let name = host.to_string().try_into().unwrap();
+            let mut stream = connector.connect(name, stream).await.unwrap();
+            stream.write_all(request.as_bytes()).await.unwrap();
+            stream.read_to_end(&mut reply).await.ok();
+        }
+        None => {
+            let mut stream = stream;
+            stream.write_all(request.as_bytes()).await.unwrap();
+            stream.read_to_end(&mut reply).await.unwrap();
+        }
+    }
+    String::from_utf8(reply).unwrap()
+}
+
+/// A node serving `web` publicly and `private` only to wave peers.
+async fn public_node() -> Endpoint {
+    let server_ep = offline_node(&[]).await;
+    let server = Server::try_from_iter([
+        ("web".to_string(), DOWNSTREAM.to_string()),
+        ("private".to_string(), DOWNSTREAM.to_string()),
+    ])
+    .unwrap();
+    let service = ServerService::new(Arc::new(server), server_ep.clone())
+        .with_public_routes(HashSet::from(["web".to_string()]));
+    tokio::spawn(service.run());
+    server_ep
+}
+
+fn public_gateway_config(port: u16) -> PublicGatewayConfig {
+    PublicGatewayConfig {
+        bind: "127.0.0.1:0".parse().unwrap(),
+        domain: "gw.test".to_string(),
+        custom_domains: Default::default(),
+        port,
+        tls: None,
+        rate_limit: Default::default(),
+        endpoint_bind: None,
+    }
+}
+
+#[tokio::test]
+async fn test_public_gateway() {
+    let hello = hello_app().await;
+    let server_ep = public_node().await;
+    let server_id = NodeId(server_ep.node_id());
+    let gateway_ep = offline_node(&[&server_ep]).await;
+    let mut config = public_gateway_config(hello.port());
+    config
+        .custom_domains
+        .insert("app.partner.test".to_string(), format!("web.{server_id}"));
+    config.rate_limit = RateLimit {
+        requests_per_second: 0.001,
+        burst: 4,
+    };
+    let gateway = PublicGateway::bind(&config, gateway_ep.clone())
+        .await
+        .unwrap();
+    let addr = gateway.local_addr().unwrap();
+    tokio::spawn(gateway.run());
+
+    let reply = gateway_get(addr, &format!("web.{server_id}.gw.test"), None).await;
+    assert!(reply.starts_with("HTTP/1.1 200 OK") && reply.ends_with("hello world"));
+    let reply = gateway_get(addr, "app.partner.test:8080", None).await;
+    assert!(reply.starts_with("HTTP/1.1 200 OK") && reply.ends_with("hello world"));
+    let reply = gateway_get(addr, &format!("private.{server_id}.gw.test"), None).await;
+    assert!(reply.starts_with("HTTP/1.1 403 Forbidden"));
+    let reply = gateway_get(addr, "example.com", None).await;
+    assert!(reply.starts_with("HTTP/1.1 404 Not Found"));
+    let reply = gateway_get(addr, &format!("web.{server_id}.gw.test"), None).await;
+    assert!(reply.starts_with("HTTP/1.1 429 Too Many Requests"));
+
+    // The node itself refuses gateway streams for routes that are not public.
+    let conn = gateway_ep.connect(server_ep.node_id(), ALPN).await.unwrap();
+    let (mut send, mut recv) = conn.open_bi().await.unwrap();
+    let packet = WavePacket::gateway(hello.port(), "private".parse().unwrap());
+    send.write_all(&packet.encode_with_payload(b"GET / HTTP/1.1\r\n\r\n"))
+        .await
+        .unwrap();
+    let reply = recv.read_to_end(64 * 1024).await.unwrap();
+    assert_eq!(reply, Fallback::default().bytes());
+}
+
+#[tokio::test]
+async fn test_public_gateway_https() {
+    let hello = hello_app().await;
+    let server_ep = public_node().await;
+    let server_id = NodeId(server_ep.node_id());
+    let gateway_ep = offline_node(&[&server_ep]).await;
+
+    let key = rcgen::KeyPair::generate().unwrap();
+    let cert = rcgen::CertificateParams::new([format!("*.{server_id}.gw.test")])
+        .unwrap()
+        .self_signed(&key)
+        .unwrap();
+    let dir = std::env::temp_dir().join(format!("wave-test-{}", rand::random::<u64>()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let files = TlsFiles {
+        cert: dir.join("cert.pem"),
+        key: dir.join("key.pem"),
+    };
+    std::fs::write(&files.cert, cert.pem()).unwrap();
+    std::fs::write(&files.key, key.serialize_pem()).unwrap();
+    let mut config = public_gateway_config(hello.port());
+    config.tls = Some(files);
+    let gateway = PublicGateway::bind(&config, gateway_ep).await.unwrap();
+    let addr = gateway.local_addr().unwrap();
+    tokio::spawn(gateway.run());
+    std::fs::remove_dir_all(dir).unwrap();
+
+    let connector = tls_connector(cert.der().clone());
+    let reply = gateway_get(addr, &format!("web.{server_id}.gw.test"), Some(&connector)).await;
+    assert!(reply.starts_with("HTTP/1.1 200 OK") && reply.ends_with("hello world"));
+}
+
+/// A stand-in HTTP CONNECT proxy that reports the target of each tunnel.
+async fn connect_proxy() -> (SocketAddr, mpsc::UnboundedReceiver<String>) {
+    let listener = TcpListener::bind((DOWNSTREAM, 0)).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let head = read_until(&mut stream, b"\r\n\r\n").await;
+                let head = String::from_utf8(head).unwrap();
+                let target = head.split(' ').nth(1).unwrap().to_string();
+                tx.send(target.clone()).ok();
+                let mut downstream = TcpStream::connect(target).await.unwrap();
+                stream
+                    .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                    .await
+                    .unwrap();
+                tokio::io::copy_bidirectional(&mut stream, &mut downstream)
+                    .await
+                    .ok();
+            });
+        }
+    });
+    (addr, rx)
+}
+
+#[tokio::test]
+async fn test_routing_rules() {
+    let (echo, _) = echo_app().await;
+    let (proxy_addr, mut tunnels) = connect_proxy().await;
+
+    let server_ep = offline_node(&[]).await;
+    let server_id = NodeId(server_ep.node_id());
+    let server = Server::try_from_iter([("web".to_string(), DOWNSTREAM.to_string())]).unwrap();
+    tokio::spawn(ServerService::new(Arc::new(server), server_ep.clone()).run());
+
+    let rule = |host: &str, action: Action| Rule {
+        domain_suffix: None,
+        host: Some(host.to_string()),
+        cidr: None,
+        port: None,
+        node: None,
+        action,
+    };
+    let rules = RuleTable::new(Rules::new(vec![
+        rule("blocked.test", Action::Block),
+        rule(
+            "localhost",
+            Action::Proxy(format!("http://{proxy_addr}").parse().unwrap()),
+        ),
+        rule(
+            "app.test",
+            Action::Wave(format!("web.{server_id}").try_into().unwrap()),
+        ),
+    ]));
+    let client_ep = offline_node(&[&server_ep]).await;
+    let client = Client::new((DOWNSTREAM, 0), client_ep, Arc::default())
+        .await
+        .unwrap()
+        .with_rules(rules.clone());
+    let proxy = client.local_addr().unwrap();
+    tokio::spawn(client.run());
+
+    let mut target = vec![0x03, 12];
+    target.extend_from_slice(b"blocked.test");
+    target.extend_from_slice(&80u16.to_be_bytes());
+    let (_, status) = socks_request_status(proxy, target).await;
+    assert_eq!(status, 0x02);
+
+    let mut stream = socks_connect(proxy, "localhost", echo.port()).await;
+    assert_echo(&mut stream).await;
+    assert_eq!(
+        tunnels.recv().await.unwrap(),
+        format!("localhost:{}", echo.port())
+    );
+
+    // Sent to the node's `web` route on the same port.
+    let mut stream = socks_connect(proxy, "app.test", echo.port()).await;
+    assert_echo(&mut stream).await;
+
+    // A reload applies to the next connection.
+    rules.store(Rules::new(vec![rule("localhost", Action::Block)]));
+    let mut target = vec![0x03, 9];
+    target.extend_from_slice(b"localhost");
+    target.extend_from_slice(&echo.port().to_be_bytes());
+    let (_, status) = socks_request_status(proxy, target).await;
+    assert_eq!(status, 0x02);
+}
+
+#[tokio::test]
+async fn test_rules_reload_from_file() {
+    let (echo, _) = echo_app().await;
+    let dir = std::env::temp_dir().join(format!("wave-test-{}", rand::random::<u64>()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let config_name = dir.join("config");
+    let config_file = dir.join("config.toml");
+    std::fs::write(&config_file, "[router]\n").unwrap();
+
+    let rules = RuleTable::default();
+    tokio::spawn(rules.clone().reload_on_change(
+        config_name.to_str().unwrap().to_string(),
+        Duration::from_millis(50),
+    ));
+    let client_ep = offline_node(&[]).await;
+    let client = Client::new((DOWNSTREAM, 0), client_ep, Arc::default())
+        .await
+        .unwrap()
+        .with_rules(rules);
+    let proxy = client.local_addr().unwrap();
+    tokio::spawn(client.run());
+
+    let mut stream = socks_connect(proxy, "localhost", echo.port()).await;
+    assert_echo(&mut stream).await;
+
+    std::fs::write(
+        &config_file,
+        "[router]\n\n[[rules]]\nhost = \"localhost\"\naction = \"block\"\n",
+    )
+    .unwrap();
+    // Coarse file system clocks could leave the time of the change as it was.
+    std::fs::File::options()
+        .write(true)
+        .open(&config_file)
+        .unwrap()
+        .set_modified(std::time::SystemTime::now() + Duration::from_secs(1))
+        .unwrap();
+
+    let mut target = vec![0x03, 9];
+    target.extend_from_slice(b"localhost");
+    target.extend_from_slice(&echo.port().to_be_bytes());
+    let blocked = async {
+        while socks_request_status(proxy, target.clone()).await.1 != 0x02 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(5), blocked)
+        .await
+        .unwrap();
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_udp_block_rule() {
+    let echo = udp_echo_app().await;
+    let client_ep = offline_node(&[]).await;
+    let client = Client::new((DOWNSTREAM, 0), client_ep, Arc::default())
+        .await
+        .unwrap()
+        .with_rules(RuleTable::new(Rules::new(vec![Rule {
+            domain_suffix: None,
+            host: Some("localhost".to_string()),
+            cidr: None,
+            port: None,
+            node: None,
+            action: Action::Block,
+        }])));
+    let proxy = client.local_addr().unwrap();
+    tokio::spawn(client.run());
+
+    let (_control, socket) = udp_associate(proxy).await;
+    let request = [&udp_domain_header("localhost", echo.port())[..], b"query"].concat();
+    socket.send(&request).await.unwrap();
+    let mut buf = [0u8; 64];
+    let reply = tokio::time::timeout(Duration::from_millis(500), socket.recv(&mut buf)).await;
+    assert!(reply.is_err());
+
+    // The rule names the host, not its address.
+    let mut header = vec![0, 0, 0, 0x01, 127, 0, 0, 1];
+    header.extend_from_slice(&echo.port().to_be_bytes());
+    let request = [&header[..], b"query"].concat();
+    assert_eq!(udp_roundtrip(&socket, &request).await, request);
+}
+
+#[tokio::test]
+async fn test_route_target_resolver() {
+    let (echo, _) = echo_app().await;
+    let upstream = upstream_dns(std::net::Ipv4Addr::LOCALHOST).await;
+    let resolver = DnsResolver::new(&ResolverConfig {
+        upstreams: Vec::new(),
+        suffixes: vec![SuffixUpstreams {
+            suffix: "corp.test".to_string(),
+            upstreams: vec![upstream],
+        }],
+    });
+
+    let server_ep = offline_node(&[]).await;
+    let server_id = NodeId(server_ep.node_id());
+    let server = Server::try_from_iter([("web".to_string(), "app.corp.test".to_string())]).unwrap();
+    let service =
+        ServerService::new(Arc::new(server), server_ep.clone()).with_resolver(Arc::new(resolver));
+    tokio::spawn(service.run());
+
+    let client_ep = offline_node(&[&server_ep]).await;
+    let client = Client::new((DOWNSTREAM, 0), client_ep, Arc::default())
+        .await
+        .unwrap();
+    let proxy = client.local_addr().unwrap();
+    tokio::spawn(client.run());
+
+    let mut stream = socks_connect(proxy, &format!("web.{server_id}"), echo.port()).await;
+    assert_echo(&mut stream).await;
+}
+
+/// A SOCKS5 proxy that wants `wave:secret` and tunnels every name to
+/// `DOWNSTREAM`, reporting the targets it was asked for.
+async fn socks_auth_proxy() -> (SocketAddr, mpsc::UnboundedReceiver<String>) {
+    let listener = TcpListener::bind((DOWNSTREAM, 0)).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let mut greeting = [0u8; 2];
+                stream.read_exact(&mut greeting).await.unwrap();
+                let mut methods = vec![0u8; greeting[1].into()];
+                stream.read_exact(&mut methods).await.unwrap();
+                assert!(methods.contains(&0x02));
+                stream.write_all(&[0x05, 0x02]).await.unwrap();
+
+                assert_eq!(stream.read_u8().await.unwrap(), 0x01);
+                let mut fields = Vec::new();
+                for _ in 0..2 {
+                    let mut field = vec![0u8; stream.read_u8().await.unwrap().into()];
+                    stream.read_exact(&mut field).await.unwrap();
+                    fields.push(String::from_utf8(field).unwrap());
+                }
+                if fields != ["wave", "secret"] {
+                    stream.write_all(&[0x01, 0x01]).await.unwrap();
+                    return;
+                }
+                stream.write_all(&[0x01, 0x00]).await.unwrap();
+
+                let mut request = [0u8; 4];
+                stream.read_exact(&mut request).await.unwrap();
+                assert_eq!(request[3], 0x03);
+                let mut name = vec![0u8; stream.read_u8().await.unwrap().into()];
+                stream.read_exact(&mut name).await.unwrap();
+                let port = stream.read_u16().await.unwrap();
+                tx.send(format!("{}:{port}", String::from_utf8(name).unwrap()))
+                    .ok();
+                let mut downstream = TcpStream::connect((DOWNSTREAM, port)).await.unwrap();
+                stream
+                    .write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+                    .await
+                    .unwrap();
+                tokio::io::copy_bidirectional(&mut stream, &mut downstream)
+                    .await
+                    .ok();
+            });
+        }
+    });
+    (addr, rx)
+}
+
+#[tokio::test]
+async fn test_route_proxy() {
+    let (echo, _) = echo_app().await;
+    let (proxy_addr, mut tunnels) = socks_auth_proxy().await;
+
+    let server_ep = offline_node(&[]).await;
+    let server_id = NodeId(server_ep.node_id());
+    let server = Server::try_from_iter([
+        ("web".to_string(), "backend.test".to_string()),
+        ("denied".to_string(), "backend.test".to_string()),
+    ])
+    .unwrap();
+    let route_proxies = HashMap::from([
+        (
+            "web".to_string(),
+            format!("socks5://wave:secret@{proxy_addr}")
+                .parse()
+                .unwrap(),
+        ),
+        (
+            "denied".to_string(),
+            format!("socks5://wave:guess@{proxy_addr}").parse().unwrap(),
+        ),
+    ]);
+    let service =
+        ServerService::new(Arc::new(server), server_ep.clone()).with_route_proxies(route_proxies);
+    tokio::spawn(service.run());
+
+    let client_ep = offline_node(&[&server_ep]).await;
+    let client = Client::new((DOWNSTREAM, 0), client_ep, Arc::default())
+        .await
+        .unwrap();
+    let proxy = client.local_addr().unwrap();
+    tokio::spawn(client.run());
+
+    // Only the proxy knows `backend.test`.
+    let mut stream = socks_connect(proxy, &format!("web.{server_id}"), echo.port()).await;
+    assert_echo(&mut stream).await;
+    assert_eq!(
+        tunnels.recv().await.unwrap(),
+        format!("backend.test:{}", echo.port())
+    );
+
+    let mut stream = socks_connect(proxy, &format!("denied.{server_id}"), echo.port()).await;
+    assert_closed(&mut stream).await;
+}
@@ -0,0 +1,314 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use iroh::endpoint::{Connection, RecvStream, SendStream};
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    sync::{Arc, Mutex, Weak},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UdpSocket,
+    sync::mpsc,
+};
+use tracing::debug;
+
+pub const DEFAULT_UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Largest UDP payload a flow carries.
+pub const MAX_PAYLOAD: usize = u16::MAX as usize;
+
+const FLOW_ID_LEN: usize = 8;
+
+const FRAME_HEADER_LEN: usize = 2;
+
+/// Written by the server once it accepted a flow; datagrams sent before that
+/// could arrive ahead of the stream and be dropped.
+const FLOW_ACCEPTED: u8 = 0;
+
+/// Payloads queued for a flow before further ones are dropped, as a full
+/// socket buffer would.
+pub const FLOW_QUEUE: usize = 256;
+
+type Flows = Mutex<HashMap<u64, mpsc::Sender<Bytes>>>;
+
+/// Hands the QUIC datagrams of one connection to the flows they belong to.
+///
+/// A UDP flow is a bi-stream opened with `WavePacket::udp`. Its payloads travel
+/// as datagrams prefixed with the stream id, or as length-prefixed frames on the
+/// stream itself until the server accepted the flow, when the peer takes no
+/// datagrams or when a payload is too large for one.
+#[derive(Clone)]
+pub struct DatagramRouter {
+    conn: Connection,
+    flows: Arc<Flows>,
+}
+
+impl DatagramRouter {
+    pub fn new(conn: Connection) -> Self {
+        let flows = Arc::default();
+        tokio::spawn(dispatch(conn.clone(), Arc::downgrade(&flows)));
+        Self { conn, flows }
+    }
+
+    /// Starts a flow on a bi-stream this side opened, after its `WavePacket`
+    /// was written.
+    pub fn open(&self, send: SendStream, recv: RecvStream) -> Flow {
+        self.flow(send, recv, false)
+    }
+
+    /// Accepts the flow the peer opened on `send`/`recv`; `buf` holds what was
+    /// read past its `WavePacket`.
+    pub async fn accept(
+        &self,
+        mut send: SendStream,
+        recv: RecvStream,
+        buf: BytesMut,
+    ) -> anyhow::Result<Flow> {
+        send.write_all(&[FLOW_ACCEPTED]).await?;
+        let mut flow = self.flow(send, recv, true);
+        flow.buf = buf;
+        Ok(flow)
+    }
+
+    fn flow(&self, send: SendStream, recv: RecvStream, accepted: bool) -> Flow {
+        let id = u64::from(send.id());
+        let (tx, rx) = mpsc::channel(FLOW_QUEUE);
+        self.flows.lock().unwrap().insert(id, tx);
+        Flow {
+            id,
+            conn: self.conn.clone(),
+            flows: self.flows.clone(),
+            send,
+            recv,
+            datagrams: rx,
+            buf: BytesMut::new(),
+            accepted,
+            datagrams_ok: accepted,
+        }
+    }
+}
+
+async fn dispatch(conn: Connection, flows: Weak<Flows>) {
+    while let Ok(mut datagram) = conn.read_datagram().await {
+        let Some(flows) = flows.upgrade() else {
+            return;
+        };
+        if datagram.len() < FLOW_ID_LEN {
+            continue;
+        }
+        let id = datagram.get_u64();
+        let flow = flows.lock().unwrap().get(&id).cloned();
+        if let Some(flow) = flow {
+            flow.try_send(datagram).ok();
+        }
+    }
+}
+
+/// One UDP flow to a peer. Dropping it ends the flow.
+pub struct Flow {
+    id: u64,
+    conn: Connection,
+    flows: Arc<Flows>,
+    send: SendStream,
+    recv: RecvStream,
+    datagrams: mpsc::Receiver<Bytes>,
+    buf: BytesMut,
+    /// The acceptance byte was seen, or is not expected on this side.
+    accepted: bool,
+    /// The peer routes datagrams for this flow.
+    datagrams_ok: bool,
+}
+
+impl Drop for Flow {
+    fn drop(&mut self) {
+        self.flows.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl Flow {
+    /// Sends one payload to the peer, as a datagram when it fits and framed on
+    /// the stream otherwise.
+    pub async fn send(&mut self, payload: &[u8]) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            payload.len() <= MAX_PAYLOAD,
+            "UDP payload of {} bytes is too large",
+            payload.len()
+        );
+        let fits = self.datagrams_ok
+            && self
+                .conn
+                .max_datagram_size()
+                .is_some_and(|max| FLOW_ID_LEN + payload.len() <= max);
+        if fits {
+            let mut datagram = BytesMut::with_capacity(FLOW_ID_LEN + payload.len());
+            datagram.put_u64(self.id);
+            datagram.put(payload);
+            match self.conn.send_datagram(datagram.freeze()) {
+                Ok(()) => return Ok(()),
+                Err(e) => debug!(flow = self.id, "Send datagram failed, framing it: {}", e),
+            }
+        }
+        let mut frame = BytesMut::with_capacity(FRAME_HEADER_LEN + payload.len());
+        frame.put_u16(payload.len() as u16);
+        frame.put(payload);
+        self.send.write_all_buf(&mut frame).await?;
+        Ok(())
+    }
+
+    /// The next payload from the peer, or `None` once the peer ended the flow;
+    /// a server that refuses the flow ends it straight away. Cancel safe.
+    pub async fn recv(&mut self) -> anyhow::Result<Option<Bytes>> {
+        loop {
+            if !self.accepted && !self.buf.is_empty() {
+                anyhow::ensure!(
+                    self.buf.get_u8() == FLOW_ACCEPTED,
+                    "invalid UDP flow acceptance"
+                );
+                self.accepted = true;
+                self.datagrams_ok = true;
+            }
+            if let Some(payload) = self.take_frame() {
+                return Ok(Some(payload));
+            }
+            tokio::select! {
+                Some(datagram) = self.datagrams.recv() => {
+                    self.datagrams_ok = true;
+                    return Ok(Some(datagram));
+                }
+                n = self.recv.read_buf(&mut self.buf) => {
+                    if n? == 0 {
+                        return Ok(None);
+                    }
+                }
+            }
+        }
+    }
+
+    fn take_frame(&mut self) -> Option<Bytes> {
+        let len = u16::from_be_bytes(self.buf.get(..FRAME_HEADER_LEN)?.try_into().unwrap());
+        if self.buf.len() < FRAME_HEADER_LEN + len as usize {
+            return None;
+        }
+        self.buf.advance(FRAME_HEADER_LEN);
+        Some(self.buf.split_to(len as usize).freeze())
+    }
+}
+
+/// Relays between `flow` and `socket`, which is connected to the target, until
+/// the peer ends the flow or it stays idle for `idle_timeout`.
+pub async fn relay_socket(
+    flow: &mut Flow,
+    socket: &UdpSocket,
+    idle_timeout: Duration,
+) -> anyhow::Result<()> {
+    let mut buf = vec![0u8; MAX_PAYLOAD];
+    loop {
+        tokio::select! {
+            payload = flow.recv() => {
+                let Some(payload) = payload? else {
+                    return Ok(());
+                };
+                ignore_refused(socket.send(&payload).await)?;
+            }
+            n = socket.recv(&mut buf) => {
+                if let Some(n) = ignore_refused(n)? {
+                    flow.send(&buf[..n]).await?;
+                }
+            }
+            _ = tokio::time::sleep(idle_timeout) => {
+                debug!(flow = flow.id, "UDP flow idle");
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Relays between `flow` and a local peer that shares a socket with others:
+/// its payloads arrive on `incoming` and the peer's replies go to `reply`.
+pub async fn relay_channel(
+    flow: &mut Flow,
+    incoming: &mut mpsc::Receiver<Bytes>,
+    mut reply: impl FnMut(Bytes),
+    idle_timeout: Duration,
+) -> anyhow::Result<()> {
+    loop {
+        tokio::select! {
+            payload = flow.recv() => match payload? {
+                Some(payload) => reply(payload),
+                None => return Ok(()),
+            },
+            payload = incoming.recv() => match payload {
+                Some(payload) => flow.send(&payload).await?,
+                None => return Ok(()),
+            },
+            _ = tokio::time::sleep(idle_timeout) => {
+                debug!(flow = flow.id, "UDP flow idle");
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// An ICMP port unreachable for an earlier datagram surfaces as a refused
+/// error on a connected socket; the flow goes on regardless.
+fn ignore_refused<T>(res: std::io::Result<T>) -> std::io::Result<Option<T>> {
+    match res {
+        Ok(value) => Ok(Some(value)),
+        Err(e) if e.kind() == ErrorKind::ConnectionRefused => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ALPN;
+    use iroh::{Endpoint, RelayMode};
+
+    async fn pair() -> (DatagramRouter, DatagramRouter) {
+        let bind = || {
+            Endpoint::builder()
+                .alpns(vec![ALPN.into()])
+                .relay_mode(RelayMode::Disabled)
+                .bind()
+        };
+        let (server, client) = (bind().await.unwrap(), bind().await.unwrap());
+        let addr = server.node_addr().await.unwrap();
+        let accept = tokio::spawn(async move { server.accept().await.unwrap().await.unwrap() });
+        let conn = client.connect(addr, ALPN).await.unwrap();
+        let accepted = accept.await.unwrap();
+        (DatagramRouter::new(conn), DatagramRouter::new(accepted))
+    }
+
+    #[tokio::test]
+    async fn test_flow_datagrams_and_frames() {
+        let (client, server) = pair().await;
+        let (send, recv) = client.conn.open_bi().await.unwrap();
+        let mut client_flow = client.open(send, recv);
+        // Framed on the stream until the server accepted the flow, which also
+        // makes the stream known to the server.
+        client_flow.send(b"first").await.unwrap();
+        let (send, recv) = server.conn.accept_bi().await.unwrap();
+        let mut server_flow = server.accept(send, recv, BytesMut::new()).await.unwrap();
+        assert_eq!(&server_flow.recv().await.unwrap().unwrap()[..], b"first");
+        assert!(!client_flow.datagrams_ok);
+
+        let max = client.conn.max_datagram_size().unwrap();
+        for len in [1, max - FLOW_ID_LEN, max, MAX_PAYLOAD] {
+            client_flow.send(&vec![7; len]).await.unwrap();
+            let payload = server_flow.recv().await.unwrap().unwrap();
+            assert_eq!(payload.len(), len);
+            server_flow.send(&payload).await.unwrap();
+            assert_eq!(client_flow.recv().await.unwrap().unwrap().len(), len);
+            assert!(client_flow.datagrams_ok);
+        }
+        assert!(client_flow.send(&vec![0; MAX_PAYLOAD + 1]).await.is_err());
+
+        drop(client_flow);
+        assert!(server_flow.recv().await.unwrap().is_none());
+        assert!(server.flows.lock().unwrap().len() == 1);
+        drop(server_flow);
+        assert!(server.flows.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,141 @@
+//! Measures the relay engine against the relay loop it replaced. The counting
+//! allocator replaces the global allocator, so this lives in its own binary.
+
+use bytes::BytesMut;
+use futures_lite::FutureExt;
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+    future::Future,
+    net::SocketAddr,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use wave::relay::{relay, BufferPool, BUFFER_SIZE, DEFAULT_WINDOW};
+use wave_proxy::{
+    protocol::socks5::{
+        types::{ConnectRequest, ConnectedStatus, HandshakeRequest},
+        NoAuthHandshake, Relay,
+    },
+    Address,
+};
+
+#[path = "../src/relay/transfer.rs"]
+mod transfer;
+
+struct CountingAlloc;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.with(|n| n.set(n.get() + 1));
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+/// Large enough that the per-read allocations of the legacy loop dominate.
+const TRANSFER: usize = 4 * 1024 * 1024;
+
+/// Allocations made on this thread while `TRANSFER` bytes go through
+/// `relay_fn`.
+async fn allocations<F, Fut>(relay_fn: F) -> usize
+where
+    F: FnOnce(DuplexStream, DuplexStream) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let before = ALLOCATIONS.with(Cell::get);
+    transfer::transfer(TRANSFER, relay_fn).await;
+    ALLOCATIONS.with(Cell::get) - before
+}
+
+/// The relay loop `client::Handler` used before this engine: every read is
+/// frozen into a `Bytes` and routed through `socks5::Relay`.
+async fn legacy_relay(mut upstream: DuplexStream, mut downstream: DuplexStream) {
+    let upstream_address: SocketAddr = "127.0.0.1:1".parse().unwrap();
+    let target: Address = "te.st:80".parse().unwrap();
+    let mut relay = socks5_relay(upstream_address);
+
+    let mut buf = BytesMut::with_capacity(8 * 1024);
+    let mut buf2 = BytesMut::with_capacity(8 * 1024);
+    let (mut upstream_eof, mut downstream_eof) = (false, false);
+    while !(upstream_eof && downstream_eof) {
+        let (from, data) = async {
+            if downstream_eof {
+                futures_lite::future::pending::<()>().await;
+            }
+            downstream.read_buf(&mut buf).await.unwrap();
+            (target.clone(), buf.split().freeze())
+        }
+        .race(async {
+            if upstream_eof {
+                futures_lite::future::pending::<()>().await;
+            }
+            upstream.read_buf(&mut buf2).await.unwrap();
+            (Address::Ip(upstream_address), buf2.split().freeze())
+        })
+        .await;
+        let from_downstream = from == target;
+        let mut transmit = relay.relay(from, data);
+        let stream = if transmit.to == target {
+            &mut downstream
+        } else {
+            &mut upstream
+        };
+        if transmit.data.is_empty() {
+            stream.shutdown().await.unwrap();
+            if from_downstream {
+                downstream_eof = true;
+            } else {
+                upstream_eof = true;
+            }
+        } else {
+            stream.write_all_buf(&mut transmit.data).await.unwrap();
+        }
+    }
+}
+
+fn socks5_relay(client: SocketAddr) -> Relay {
+    let mut handshake = BytesMut::from(&[0x05, 0x01, 0x00][..]);
+    let mut connect = BytesMut::from(
+        &[
+            0x05, 0x01, 0x00, 0x03, 0x05, b't', b'e', b'.', b's', b't', 0x00, 0x50,
+        ][..],
+    );
+    let (_, connecting) = NoAuthHandshake::new("127.0.0.1:2".parse().unwrap(), client)
+        .handshake(HandshakeRequest::decode(&mut handshake).unwrap().unwrap());
+    let request = ConnectRequest::decode(&mut connect).unwrap().unwrap();
+    let (_, relay) = connecting
+        .unwrap()
+        .connect(request, ConnectedStatus::Succeeded);
+    relay.unwrap()
+}
+
+#[tokio::test]
+async fn test_relay_against_legacy_loop() {
+    let pool = BufferPool::default();
+
+    let legacy = allocations(legacy_relay).await;
+    let engine = || {
+        allocations(|a, b| async {
+            let (sent, received) = relay(a, b, &pool.account("bulk")).await.unwrap();
+            assert_eq!((sent, received), (TRANSFER as u64, 0));
+        })
+    };
+    // The first run fills the pool, the second only reuses its buffers.
+    engine().await;
+    let warm = engine().await;
+
+    assert!(warm < legacy, "{warm} allocations, legacy loop {legacy}");
+    let stats = pool.stats();
+    assert!(stats.allocated <= 2 * (DEFAULT_WINDOW / BUFFER_SIZE + 1));
+    assert_eq!(stats.free, stats.allocated);
+}